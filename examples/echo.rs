@@ -0,0 +1,134 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A TCP echo server.
+//!
+//! ```text
+//! cargo run --example echo -- 127.0.0.1:7000
+//! ```
+//!
+//! The listener is level-triggered, connections are edge-triggered. Whatever the
+//! peer doesn't take right away is kept until it becomes writable again, and no
+//! more is read from it in the meantime.
+
+extern crate epoll;
+
+use epoll::{EPoll, Event, Interest, Timeout};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+
+const LISTENER: u64 = 0;
+
+struct Connection {
+    stream: TcpStream,
+    outbox: Vec<u8>,
+    read_closed: bool,
+}
+
+impl Connection {
+    /// Writes as much of the outbox as the peer takes; returns whether it was emptied.
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.outbox.is_empty() {
+            match self.stream.write(&self.outbox) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => { self.outbox.drain(..written); },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Echoes everything available; returns whether the connection should stay open.
+    fn serve(&mut self) -> io::Result<bool> {
+        let mut buffer = [0u8; 4096];
+
+        loop {
+            if !self.flush()? {
+                return Ok(true);
+            }
+
+            if self.read_closed {
+                return Ok(false);
+            }
+
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.read_closed = true,
+                Ok(read) => self.outbox.extend_from_slice(&buffer[..read]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn accept(listener: &TcpListener, epoll: &mut EPoll, connections: &mut HashMap<u64, Connection>) -> io::Result<()> {
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        stream.set_nonblocking(true)?;
+        let id = stream.as_raw_fd() as u64;
+        epoll.add(&stream, Interest::CLIENT_STREAM, id)?;
+        connections.insert(id, Connection { stream, outbox: Vec::new(), read_closed: false });
+    }
+}
+
+fn main() -> io::Result<()> {
+    let address = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:7000".to_string());
+
+    let listener = TcpListener::bind(&address)?;
+    listener.set_nonblocking(true)?;
+    println!("echoing on {}", listener.local_addr()?);
+
+    let mut epoll = EPoll::new()?;
+    epoll.add(&listener, Interest::ACCEPT, LISTENER)?;
+
+    let mut connections = HashMap::new();
+    let mut events = [Event::default(); 64];
+
+    loop {
+        let count = epoll.wait(&mut events, Timeout::Indefinite)?;
+
+        for event in &events[..count] {
+            let id = event.data;
+
+            if id == LISTENER {
+                if let Err(e) = accept(&listener, &mut epoll, &mut connections) {
+                    eprintln!("accept failed: {}", e);
+                }
+                continue;
+            }
+
+            let open = match connections.get_mut(&id) {
+                Some(connection) => connection.serve().unwrap_or(false),
+                None => continue,
+            };
+
+            if !open {
+                let connection = connections.remove(&id).unwrap();
+                epoll.remove(&connection.stream)?;
+            }
+        }
+    }
+}
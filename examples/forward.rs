@@ -0,0 +1,171 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A TCP port forwarder.
+//!
+//! ```text
+//! cargo run --example forward -- 127.0.0.1:8080 example.com:80
+//! ```
+//!
+//! Each accepted client is paired with a new connection to the target, and both
+//! sides of a pair are registered edge-triggered with the pair's id. On any event
+//! both directions of the pair are pumped, so that a direction stalled by a slow
+//! receiver resumes as soon as the receiver drains. A side that is done sending
+//! has its counterpart shut down for writing once everything was delivered.
+//!
+//! For brevity, the target is connected to synchronously.
+
+extern crate epoll;
+
+use epoll::{EPoll, Event, Interest, Timeout};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process;
+
+const LISTENER: u64 = 0;
+
+struct Side {
+    stream: TcpStream,
+    /// Data read from the other side, waiting to be written to this one.
+    outbox: Vec<u8>,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+impl Side {
+    fn new(stream: TcpStream) -> io::Result<Side> {
+        stream.set_nonblocking(true)?;
+        Ok(Side { stream, outbox: Vec::new(), read_closed: false, write_closed: false })
+    }
+
+    /// Writes as much of the outbox as the peer takes; returns whether it was emptied.
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.outbox.is_empty() {
+            match self.stream.write(&self.outbox) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => { self.outbox.drain(..written); },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Moves data from `from` to `to` until either would block.
+fn pump(from: &mut Side, to: &mut Side) -> io::Result<()> {
+    let mut buffer = [0u8; 16 * 1024];
+
+    while to.flush()? && !from.read_closed {
+        match from.stream.read(&mut buffer) {
+            Ok(0) => from.read_closed = true,
+            Ok(read) => to.outbox.extend_from_slice(&buffer[..read]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    if from.read_closed && to.outbox.is_empty() && !to.write_closed {
+        to.stream.shutdown(Shutdown::Write)?;
+        to.write_closed = true;
+    }
+
+    Ok(())
+}
+
+struct Pair {
+    client: Side,
+    target: Side,
+}
+
+impl Pair {
+    /// Pumps both directions; returns whether the pair still has work to do.
+    fn serve(&mut self) -> io::Result<bool> {
+        pump(&mut self.client, &mut self.target)?;
+        pump(&mut self.target, &mut self.client)?;
+
+        Ok(!(self.client.write_closed && self.target.write_closed))
+    }
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <listen address> <target address>", args[0]);
+        process::exit(2);
+    }
+
+    let listener = TcpListener::bind(&args[1])?;
+    listener.set_nonblocking(true)?;
+    println!("forwarding {} to {}", listener.local_addr()?, args[2]);
+
+    let epoll = EPoll::new()?;
+    epoll.add(&listener, Interest::ACCEPT, LISTENER)?;
+
+    let mut pairs: HashMap<u64, Pair> = HashMap::new();
+    let mut next_id = LISTENER + 1;
+    let mut events = [Event::default(); 64];
+
+    loop {
+        let count = epoll.wait(&mut events, Timeout::Indefinite)?;
+
+        for event in &events[..count] {
+            let id = event.data;
+
+            if id == LISTENER {
+                loop {
+                    let client = match listener.accept() {
+                        Ok((client, _)) => client,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            eprintln!("accept failed: {}", e);
+                            break;
+                        },
+                    };
+
+                    let target = match TcpStream::connect(&args[2]) {
+                        Ok(target) => target,
+                        Err(e) => {
+                            eprintln!("connecting to {} failed: {}", args[2], e);
+                            continue;
+                        },
+                    };
+
+                    let pair = Pair { client: Side::new(client)?, target: Side::new(target)? };
+                    epoll.add(&pair.client.stream, Interest::CLIENT_STREAM, next_id)?;
+                    epoll.add(&pair.target.stream, Interest::CLIENT_STREAM, next_id)?;
+                    pairs.insert(next_id, pair);
+                    next_id += 1;
+                }
+                continue;
+            }
+
+            let open = match pairs.get_mut(&id) {
+                Some(pair) => pair.serve().unwrap_or(false),
+                None => continue,
+            };
+
+            if !open {
+                let pair = pairs.remove(&id).unwrap();
+                epoll.remove(&pair.client.stream)?;
+                epoll.remove(&pair.target.stream)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allocation auditing, for tests asserting that the paths meant to run on
+//! every loop iteration never allocate, e.g. `EPoll::wait_with`, dispatching
+//! with a reused buffer, or scheduling on a timer slab.
+//!
+//! The counts are kept by `CountingAllocator`, which must be installed as the
+//! global allocator; the crate's own tests do so. Counters are per-thread, so
+//! tests running in parallel don't disturb each other. Available with the
+//! `alloc-audit` feature.
+//!
+//! # Example
+//!
+//! ```no-run
+//! #[global_allocator]
+//! static ALLOCATOR: alloc_audit::CountingAllocator = alloc_audit::CountingAllocator;
+//!
+//! #[test]
+//! fn dispatch_does_not_allocate() {
+//!     // ...
+//!     alloc_audit::assert_no_allocations(|| event_loop.dispatch(Timeout::Immediate).unwrap());
+//! }
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// A global allocator that counts the allocations of each thread, and leaves
+/// them to the system allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the amount of allocations (and reallocations) `f` made on this thread.
+///
+/// Always zero unless `CountingAllocator` is the global allocator.
+pub fn allocations_during<R, F: FnOnce() -> R>(f: F) -> (usize, R) {
+    let before = ALLOCATIONS.with(|count| count.get());
+    let result = f();
+    let after = ALLOCATIONS.with(|count| count.get());
+
+    (after - before, result)
+}
+
+/// Runs `f`, and panics if it allocated on this thread.
+#[track_caller]
+pub fn assert_no_allocations<R, F: FnOnce() -> R>(f: F) -> R {
+    assert_allocations(0, f)
+}
+
+/// Runs `f`, and panics unless it allocated exactly `expected` times on this thread.
+#[track_caller]
+pub fn assert_allocations<R, F: FnOnce() -> R>(expected: usize, f: F) -> R {
+    let (count, result) = allocations_during(f);
+    assert_eq!(count, expected, "expected {} allocations, counted {}", expected, count);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::*;
+    use event_loop::{EventLoop, EventLoopBuilder};
+    use std::time::Duration;
+    use testing;
+
+    #[test]
+    fn counts_allocations() {
+        let (count, _) = allocations_during(|| vec![1u8; 16]);
+        assert_eq!(count, 1);
+        assert_allocations(1, || vec![1u8; 16]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 0 allocations, counted 1")]
+    fn fails_on_allocations() {
+        assert_no_allocations(|| vec![1u8; 16]);
+    }
+
+    #[test]
+    fn epoll_wait_does_not_allocate() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+
+        let mut events = [Event::default(); 4];
+        let ready = assert_no_allocations(|| epoll.wait(&mut events, Timeout::Immediate).unwrap());
+
+        assert_eq!(ready, 1);
+    }
+
+    #[test]
+    fn event_loop_wait_does_not_allocate() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let mut event_loop = EventLoop::new().unwrap();
+        event_loop.add(&reader).unwrap();
+
+        let ready = assert_no_allocations(|| {
+            event_loop.wait(Timeout::Immediate).unwrap().count()
+        });
+
+        assert_eq!(ready, 1);
+    }
+
+    #[test]
+    fn epoll_wait_with_does_not_allocate() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 7).unwrap();
+
+        let mut seen = 0;
+        let ready = assert_no_allocations(|| {
+            epoll.wait_with(Timeout::Immediate, |event| seen += event.token()).unwrap()
+        });
+
+        assert_eq!((ready, seen), (1, 7));
+    }
+
+    #[test]
+    fn epoll_wait_iter_does_not_allocate() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let mut epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+
+        // The first wait allocates the internal buffer; later ones reuse it.
+        assert_eq!(epoll.wait_iter(Timeout::Immediate).unwrap().count(), 1);
+        let ready = assert_no_allocations(|| epoll.wait_iter(Timeout::Immediate).unwrap().count());
+
+        assert_eq!(ready, 1);
+    }
+
+    #[test]
+    fn event_loop_dispatch_does_not_allocate() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let calls = Cell::new(0);
+
+        let mut event_loop = EventLoop::<dyn AsRawFd>::new().unwrap();
+        event_loop.add_handler(&reader, |_| calls.set(calls.get() + 1)).unwrap();
+
+        let handled = assert_no_allocations(|| event_loop.dispatch(Timeout::Immediate).unwrap());
+
+        assert_eq!((handled, calls.get()), (1, 1));
+    }
+
+    #[test]
+    fn slab_timers_from_handlers_do_not_allocate() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let fired = Cell::new(0);
+
+        let mut event_loop = EventLoopBuilder::new().timer_slab(1).build::<dyn AsRawFd>().unwrap();
+        let timers = event_loop.timer_slab().unwrap();
+        event_loop.on_slab_timer(|token| fired.set(token));
+        event_loop.add_handler(&reader, move |_| { let _ = timers.after(Duration::ZERO, 7); }).unwrap();
+
+        // The handler arms the timer in the first cycle, and it fires in the second.
+        let handled = assert_no_allocations(|| {
+            event_loop.dispatch(Timeout::Immediate).unwrap() + event_loop.dispatch(Timeout::Immediate).unwrap()
+        });
+
+        assert_eq!((handled, fired.get()), (3, 7));
+    }
+}
@@ -0,0 +1,453 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A subset of mio's API, backed by this crate.
+//!
+//! `Poll`, `Registry`, `Events`, `Token`, `Interest` and `Waker` follow the signatures of
+//! their mio counterparts, so small projects can switch between the two by changing an import.
+//! Like mio, all registrations are edge-triggered.
+//!
+//! Unlike mio, there are no networking types: any `AsRawFd` (e.g. std's non-blocking
+//! sockets) is a `Source`.
+//!
+//! # Example
+//!
+//! ```no-run
+//! use epoll::compat::mio::{Events, Interest, Poll, Token};
+//!
+//! let mut poll = Poll::new()?;
+//! let mut events = Events::with_capacity(128);
+//!
+//! poll.registry().register(&mut listener, Token(0), Interest::READABLE)?;
+//!
+//! loop {
+//!     poll.poll(&mut events, None)?;
+//!     for event in events.iter() {
+//!         match event.token() {
+//!             Token(0) => { /* accept */ },
+//!             _ => unreachable!(),
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::io::{self, Error};
+use std::ops::BitOr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+use std::time::Duration;
+
+use libc;
+use {EPoll, EventType, Timeout};
+use ffi;
+
+/// Identifies a registration in the events it produces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Token(pub usize);
+
+impl From<Token> for usize {
+    fn from(token: Token) -> usize {
+        token.0
+    }
+}
+
+/// The readiness a registration is interested in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Interest(u8);
+
+const READABLE: u8 = 0b001;
+const WRITABLE: u8 = 0b010;
+const PRIORITY: u8 = 0b100;
+
+impl Interest {
+    /// Readable interest.
+    pub const READABLE: Interest = Interest(READABLE);
+
+    /// Writable interest.
+    pub const WRITABLE: Interest = Interest(WRITABLE);
+
+    /// Priority (out-of-band) data interest.
+    pub const PRIORITY: Interest = Interest(PRIORITY);
+
+    /// Adds together two interests.
+    pub const fn add(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+
+    /// Removes `other` from `self`, or returns `None` if nothing would be left.
+    pub fn remove(self, other: Interest) -> Option<Interest> {
+        match self.0 & !other.0 {
+            0 => None,
+            bits => Some(Interest(bits)),
+        }
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.0 & READABLE != 0
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.0 & WRITABLE != 0
+    }
+
+    pub const fn is_priority(self) -> bool {
+        self.0 & PRIORITY != 0
+    }
+
+    fn events(self) -> EventType {
+        let mut events = ::EPOLLET;
+
+        if self.is_readable() {
+            events |= ::EPOLLIN | ::EPOLLRDHUP;
+        }
+
+        if self.is_writable() {
+            events |= ::EPOLLOUT;
+        }
+
+        if self.is_priority() {
+            events |= ::EPOLLPRI;
+        }
+
+        events
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        self.add(other)
+    }
+}
+
+/// Something that can be registered with a `Registry`.
+///
+/// Implemented for everything that has a file descriptor.
+pub trait Source {
+    fn source_fd(&self) -> RawFd;
+}
+
+impl<T: AsRawFd + ?Sized> Source for T {
+    fn source_fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+}
+
+/// Adapts a bare file descriptor into a `Source`.
+#[derive(Debug)]
+pub struct SourceFd<'a>(pub &'a RawFd);
+
+impl<'a> AsRawFd for SourceFd<'a> {
+    fn as_raw_fd(&self) -> RawFd {
+        *self.0
+    }
+}
+
+/// Registers sources with a `Poll`.
+pub struct Registry {
+    epoll: EPoll,
+}
+
+impl Registry {
+    /// Registers `source` with the given token and interests.
+    pub fn register<S: Source + ?Sized>(&self, source: &mut S, token: Token, interests: Interest) -> io::Result<()> {
+        let event = ffi::Event { events: interests.events(), data: token.0 as u64 };
+        self.epoll.ctl(libc::EPOLL_CTL_ADD, source.source_fd(), event).map_err(io::Error::from)
+    }
+
+    /// Changes the token and interests of a registered source.
+    pub fn reregister<S: Source + ?Sized>(&self, source: &mut S, token: Token, interests: Interest) -> io::Result<()> {
+        let event = ffi::Event { events: interests.events(), data: token.0 as u64 };
+        self.epoll.ctl(libc::EPOLL_CTL_MOD, source.source_fd(), event).map_err(io::Error::from)
+    }
+
+    /// Deregisters a source.
+    pub fn deregister<S: Source + ?Sized>(&self, source: &mut S) -> io::Result<()> {
+        self.epoll.ctl(libc::EPOLL_CTL_DEL, source.source_fd(), ffi::Event::default()).map_err(io::Error::from)
+    }
+}
+
+/// Polls for readiness events on all registered sources.
+pub struct Poll {
+    registry: Registry,
+}
+
+impl Poll {
+    /// Creates a new `Poll`.
+    pub fn new() -> io::Result<Poll> {
+        Ok(Poll { registry: Registry { epoll: EPoll::with_flags(::EPOLL_CLOEXEC)? } })
+    }
+
+    /// Returns the registry used to register sources.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Waits for readiness events, replacing the previous contents of `events`.
+    ///
+    /// A timeout of `None` waits indefinitely; others are rounded up to whole milliseconds.
+    pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = match timeout {
+            None => Timeout::Indefinite,
+            Some(duration) => Timeout::from(duration),
+        };
+
+        events.len = 0;
+        // `Event` is a transparent wrapper, so the buffer can be handed to the kernel as-is.
+        let buffer = unsafe {
+            slice::from_raw_parts_mut(events.buffer.as_mut_ptr() as *mut ffi::Event, events.buffer.len())
+        };
+        events.len = self.registry.epoll.wait(buffer, timeout)?;
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for Poll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.registry.epoll.as_raw_fd()
+    }
+}
+
+/// A readiness event.
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct Event {
+    inner: ffi::Event,
+}
+
+impl Event {
+    /// The token the source was registered with.
+    pub fn token(&self) -> Token {
+        Token(self.inner.data as usize)
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.events().intersects(::EPOLLIN | ::EPOLLPRI)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.events().contains(::EPOLLOUT)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.events().contains(::EPOLLERR)
+    }
+
+    pub fn is_read_closed(&self) -> bool {
+        self.events().contains(::EPOLLHUP) ||
+            (self.events().contains(::EPOLLIN) && self.events().contains(::EPOLLRDHUP))
+    }
+
+    pub fn is_write_closed(&self) -> bool {
+        self.events().contains(::EPOLLHUP) ||
+            (self.events().contains(::EPOLLOUT) && self.events().contains(::EPOLLERR))
+    }
+
+    pub fn is_priority(&self) -> bool {
+        self.events().contains(::EPOLLPRI)
+    }
+
+    /// Always false; there is no AIO on Linux.
+    pub fn is_aio(&self) -> bool {
+        false
+    }
+
+    /// Always false; there is no LIO on Linux.
+    pub fn is_lio(&self) -> bool {
+        false
+    }
+
+    fn events(&self) -> EventType {
+        self.inner.events
+    }
+}
+
+impl ::std::fmt::Debug for Event {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Event")
+            .field("token", &self.token())
+            .field("readable", &self.is_readable())
+            .field("writable", &self.is_writable())
+            .field("error", &self.is_error())
+            .field("read_closed", &self.is_read_closed())
+            .field("write_closed", &self.is_write_closed())
+            .finish()
+    }
+}
+
+/// A collection of readiness events, filled by `Poll::poll`.
+pub struct Events {
+    buffer: Vec<Event>,
+    len: usize,
+}
+
+impl Events {
+    /// Creates a collection that can hold up to `capacity` events per poll.
+    pub fn with_capacity(capacity: usize) -> Events {
+        Events {
+            buffer: vec![Event { inner: ffi::Event::default() }; capacity],
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { inner: self.buffer[..self.len].iter() }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<'a> IntoIterator for &'a Events {
+    type Item = &'a Event;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the events of an `Events`.
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, Event>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Event;
+
+    fn next(&mut self) -> Option<&'a Event> {
+        self.inner.next()
+    }
+}
+
+/// Wakes a `Poll` from another thread, producing a readable event with its token.
+pub struct Waker {
+    fd: RawFd,
+}
+
+impl Waker {
+    /// Creates a waker registered with `registry` under `token`.
+    pub fn new(registry: &Registry, token: Token) -> io::Result<Waker> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let waker = Waker { fd };
+        registry.register(&mut SourceFd(&waker.fd), token, Interest::READABLE)?;
+
+        Ok(waker)
+    }
+
+    /// Wakes up the `Poll` this waker is registered with.
+    pub fn wake(&self) -> io::Result<()> {
+        let one: u64 = 1;
+
+        let rc = unsafe { libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8) };
+
+        if rc >= 0 {
+            return Ok(());
+        }
+
+        match Error::last_os_error() {
+            // The counter is about to overflow; reset it and try again.
+            ref e if e.kind() == io::ErrorKind::WouldBlock => {
+                let mut value: u64 = 0;
+                unsafe { libc::read(self.fd, &mut value as *mut u64 as *mut libc::c_void, 8) };
+                self.wake()
+            },
+            e => Err(e),
+        }
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::thread;
+    use unix;
+
+    #[test]
+    fn readiness_and_tokens() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+
+        let (mut left, mut right) = unix::duplex().unwrap();
+        poll.registry().register(&mut right, Token(7), Interest::READABLE).unwrap();
+
+        poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+        assert!(events.is_empty());
+
+        left.write_all(b"x").unwrap();
+        poll.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+
+        let ready: Vec<_> = events.iter().map(|e| (e.token(), e.is_readable(), e.is_writable())).collect();
+        assert_eq!(ready, vec![(Token(7), true, false)]);
+
+        // Edge-triggered: no new data, no new event.
+        poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+        assert!(events.is_empty());
+
+        poll.registry().reregister(&mut right, Token(8), Interest::READABLE | Interest::WRITABLE).unwrap();
+        poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), Token(8));
+
+        poll.registry().deregister(&mut right).unwrap();
+        drop(left);
+        poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn waker() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        let waker = Arc::new(Waker::new(poll.registry(), Token(1)).unwrap());
+
+        let remote = waker.clone();
+        let handle = thread::spawn(move || remote.wake().unwrap());
+
+        poll.poll(&mut events, None).unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), Token(1));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn interest_arithmetic() {
+        let both = Interest::READABLE | Interest::WRITABLE;
+        assert!(both.is_readable() && both.is_writable() && !both.is_priority());
+        assert_eq!(both.remove(Interest::WRITABLE), Some(Interest::READABLE));
+        assert_eq!(Interest::READABLE.remove(Interest::READABLE), None);
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watchable counters, backed by an eventfd.
+//!
+//! Any thread may `increment` a counter; the counter is readable (`EPOLLIN`)
+//! whenever its value is non-zero, so the thread waiting on the epoll is woken
+//! at most once per batch of increments.
+//!
+//! `Counter::new` doesn't use the eventfd's semaphore mode on purpose: the
+//! loop wants the count accumulated since it last looked, which a plain eventfd
+//! hands over in a single read, while a semaphore would take one read per
+//! unit. `Counter::semaphore` is there for consumers that take a unit each.
+//! `EventLoop::on_counter` drains either kind, and passes on the count.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let queued = Arc::new(Counter::new()?);
+//! epoll.add(&*queued, EPOLLIN, QUEUE)?;
+//!
+//! // On a producer thread:
+//! queue.push(job);
+//! queued.increment(1)?;
+//!
+//! // On the loop, once `QUEUE` is reported:
+//! for _ in 0..queued.take()? {
+//!     handle(queue.pop());
+//! }
+//! ```
+
+use super::*;
+use eventfd::EventFd;
+
+/// A counter that can be registered on an `EPoll`.
+#[derive(Debug)]
+pub struct Counter {
+    fd: EventFd,
+    semaphore: bool,
+}
+
+impl Counter {
+    /// Creates a counter whose `take` returns (and resets) the whole accumulated count.
+    pub fn new() -> io::Result<Counter> {
+        Ok(Counter { fd: EventFd::new(0)?, semaphore: false })
+    }
+
+    /// Creates a counter in semaphore mode, whose `take` decrements the count by one.
+    ///
+    /// Suited to sharing the counter between several consumers, each taking one unit of work.
+    pub fn semaphore() -> io::Result<Counter> {
+        Ok(Counter { fd: EventFd::semaphore(0)?, semaphore: true })
+    }
+
+    /// Adds `amount` to the counter.
+    ///
+    /// Fails with `WouldBlock` if the count would exceed `u64::MAX - 1`.
+    pub fn increment(&self, amount: u64) -> io::Result<()> {
+        self.fd.write(amount)
+    }
+
+    /// Takes from the counter: everything it holds, or one unit in semaphore mode.
+    ///
+    /// Returns 0 if the counter is at zero.
+    pub fn take(&self) -> io::Result<u64> {
+        match self.fd.read() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            result => result,
+        }
+    }
+
+    /// Takes everything the counter holds, in semaphore mode as well.
+    ///
+    /// Returns 0 if the counter is at zero.
+    pub fn take_all(&self) -> io::Result<u64> {
+        if !self.semaphore {
+            return self.take();
+        }
+
+        let mut count = 0;
+        loop {
+            match self.take()? {
+                0 => return Ok(count),
+                unit => count += unit,
+            }
+        }
+    }
+
+    /// Whether the counter is in semaphore mode.
+    pub fn is_semaphore(&self) -> bool {
+        self.semaphore
+    }
+}
+
+impl AsRawFd for Counter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Counter {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn accumulates_across_threads() {
+        let counter = Arc::new(Counter::new().unwrap());
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&*counter, EPOLLIN, 0).unwrap();
+
+        let producers: Vec<_> = (0..4).map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || counter.increment(5).unwrap())
+        }).collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!(counter.take().unwrap(), 20);
+
+        assert_eq!(counter.take().unwrap(), 0);
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 0);
+    }
+
+    #[test]
+    fn semaphore_mode() {
+        let counter = Counter::semaphore().unwrap();
+        counter.increment(2).unwrap();
+
+        assert_eq!(counter.take().unwrap(), 1);
+        assert_eq!(counter.take().unwrap(), 1);
+        assert_eq!(counter.take().unwrap(), 0);
+
+        counter.increment(3).unwrap();
+        assert_eq!(counter.take_all().unwrap(), 3);
+        assert_eq!(counter.take_all().unwrap(), 0);
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Errors of changes to an epoll's registrations.
+//!
+//! # Example
+//!
+//! ```no-run
+//! match epoll.add(&file, EPOLLIN, 0) {
+//!     Err(CtlError::AlreadyRegistered) => epoll.modify(&file, EPOLLIN, 0)?,
+//!     result => result?,
+//! }
+//! ```
+
+use super::*;
+use std::error;
+use std::fmt;
+
+/// Why adding, modifying or removing a registration failed.
+///
+/// Converts into an `io::Error` carrying the original errno, so `?` works in
+/// functions returning `io::Result`.
+#[derive(Debug)]
+pub enum CtlError {
+    /// The file is already registered (`EEXIST`).
+    AlreadyRegistered,
+    /// The file is not registered (`ENOENT`).
+    NotRegistered,
+    /// Registering an epoll would create a cycle, or nest epolls too deeply (`ELOOP`).
+    WouldLoop,
+    /// The epoll was asked to watch itself (`EINVAL`).
+    SelfRegistration,
+    /// The file does not support polling, e.g. a regular file (`EPERM`).
+    NotPollable,
+    /// The user's limit on watched descriptors was reached (`ENOSPC`).
+    WatchLimit,
+    /// Any other failure.
+    Other(io::Error),
+}
+
+impl CtlError {
+    /// The errno behind the error, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match *self {
+            CtlError::AlreadyRegistered => Some(libc::EEXIST),
+            CtlError::NotRegistered => Some(libc::ENOENT),
+            CtlError::WouldLoop => Some(libc::ELOOP),
+            CtlError::SelfRegistration => Some(libc::EINVAL),
+            CtlError::NotPollable => Some(libc::EPERM),
+            CtlError::WatchLimit => Some(libc::ENOSPC),
+            CtlError::Other(ref error) => error.raw_os_error(),
+        }
+    }
+}
+
+impl From<io::Error> for CtlError {
+    fn from(error: io::Error) -> CtlError {
+        match error.raw_os_error() {
+            Some(libc::EEXIST) => CtlError::AlreadyRegistered,
+            Some(libc::ENOENT) => CtlError::NotRegistered,
+            Some(libc::ELOOP) => CtlError::WouldLoop,
+            Some(libc::EPERM) => CtlError::NotPollable,
+            Some(libc::ENOSPC) => CtlError::WatchLimit,
+            _ => CtlError::Other(error),
+        }
+    }
+}
+
+impl From<CtlError> for io::Error {
+    fn from(error: CtlError) -> io::Error {
+        match error {
+            CtlError::Other(error) => error,
+            error => Error::from_raw_os_error(error.raw_os_error().expect("named errors have an errno")),
+        }
+    }
+}
+
+impl fmt::Display for CtlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CtlError::SelfRegistration => f.write_str("an epoll cannot watch itself"),
+            CtlError::Other(ref error) => fmt::Display::fmt(error, f),
+            _ => fmt::Display::fmt(&Error::from_raw_os_error(self.raw_os_error().expect("named errors have an errno")), f),
+        }
+    }
+}
+
+impl error::Error for CtlError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            CtlError::Other(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn errno_mapping() {
+        let (reader, _writer) = testing::readable_pipe(b"").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        match epoll.remove(&reader) {
+            Err(CtlError::NotRegistered) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+        let error = epoll.add(&reader, EPOLLIN, 0).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(io::Error::from(error).raw_os_error(), Some(libc::EEXIST));
+
+        match epoll.add(&epoll, EPOLLIN, 1) {
+            Err(CtlError::SelfRegistration) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let file = ::std::fs::File::open("/proc/self/exe").unwrap();
+        match epoll.add(&file, EPOLLIN, 1) {
+            Err(CtlError::NotPollable) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -17,124 +17,1700 @@
 //! Usage and initialization is very similar to EPoll, but flexability is
 //! decreased in favour of the general use-case.
 //!
-//! All registerd files are registerd as EPOLLIN.
+//! Files are registerd as EPOLLIN, unless added with `add_with_interest`.
+//! The defaults can be changed when creating the loop:
+//!
+//! ```no-run
+//! let mut event_loop = EventLoopBuilder::new()
+//!     .interest(Interest::READABLE | Interest::RDHUP)
+//!     .trigger(Trigger::Edge)
+//!     .capacity(256)
+//!     .cloexec(true)
+//!     .build()?;
+//! ```
+//!
+//! Ready files are either iterated over with `wait`, or handed to a closure
+//! registered with `add_handler` by `dispatch`:
+//!
+//! ```no-run
+//! let mut event_loop = EventLoop::<dyn AsRawFd>::new()?;
+//! event_loop.add_handler(&listener, |_| accept_all(&listener))?;
+//!
+//! loop {
+//!     event_loop.dispatch(Timeout::Indefinite)?;
+//! }
+//! ```
+//!
+//! Files can't be removed while the loop is borrowed by `wait`'s iterator,
+//! but they can be marked for removal by the iterator itself:
+//!
+//! ```no-run
+//! let mut ready = event_loop.wait(Timeout::Indefinite)?;
+//! while let Some((connection, event)) = ready.next() {
+//!     if event.is_hangup() {
+//!         ready.mark_remove(connection);
+//!     }
+//! }
+//! ```
+//!
+//! Files can carry a state of their own, handed to `dispatch_with`:
+//!
+//! ```no-run
+//! let mut event_loop = EventLoopBuilder::new().build_with_state::<TcpStream, Connection>()?;
+//! event_loop.add_with_state(&stream, Connection::new())?;
+//! event_loop.dispatch_with(Timeout::Indefinite, |stream, _, connection| connection.read_from(stream))?;
+//! ```
+//!
+//! Errors and hangups can be handled apart from the files' handlers, and
+//! the files dropped from the loop right away:
+//!
+//! ```no-run
+//! event_loop.on_hangup(|connection, _| { closed(connection); true });
+//! ```
+//!
+//! Loops are pollable themselves, so one can be nested in another:
+//!
+//! ```no-run
+//! let mut background = EventLoop::<dyn AsRawFd>::new()?;
+//! background.add_handler(&log_pipe, |_| drain(&log_pipe))?;
+//! event_loop.add_nested(&mut background)?;
+//! ```
+//!
+//! Or, by `run`, until stopped through a `LoopHandle`:
+//!
+//! ```no-run
+//! let handle = event_loop.handle()?;
+//! event_loop.add_handler(&quit_pipe, |_| handle.stop())?;
+//! event_loop.run()?;
+//! ```
+//!
+//! Timers are called by `dispatch` and `run` too, with waits cut short to meet
+//! their deadlines:
+//!
+//! ```no-run
+//! event_loop.every(Duration::from_secs(30), || flush(&log))?;
+//! let deadline = event_loop.after(Duration::from_secs(5), || give_up());
+//! // ...
+//! event_loop.cancel(deadline);
+//! ```
+//!
+//! Handlers and timers that need the time read it from the loop's context, which
+//! `dispatch` updates once per cycle, along with a tick counter:
+//!
+//! ```no-run
+//! let ctx = event_loop.ctx();
+//! event_loop.add_handler(&socket, move |_| touch(&socket, ctx.now(), ctx.tick()))?;
+//! ```
+//!
+//! With many timers, e.g. a timeout per connection, rounding their deadlines up
+//! lets those due close together fire in a single wakeup:
+//!
+//! ```no-run
+//! let mut event_loop = EventLoopBuilder::new().timer_granularity(Duration::from_millis(10)).build()?;
+//! // ...
+//! let stats = event_loop.timer_stats();
+//! println!("{} timers per wakeup", stats.fired as f64 / stats.wakeups as f64);
+//! ```
+//!
+//! Handlers that schedule timers at a steady rate, e.g. a timeout per
+//! request, can use a timer slab: its slots are allocated once, with the loop,
+//! so scheduling a timer never allocates, and fails instead once all are taken.
+//!
+//! ```no-run
+//! let mut event_loop = EventLoopBuilder::new().timer_slab(1024).build()?;
+//! let timers = event_loop.timer_slab().unwrap();
+//! event_loop.on_slab_timer(|request| println!("request {} timed out", request));
+//! event_loop.add_handler(&socket, move |_| { timers.after(Duration::from_secs(5), 42).unwrap(); })?;
+//! ```
+//!
+//! Work can also wait for the next `dispatch`, or for a moment the loop has
+//! nothing else to do:
+//!
+//! ```no-run
+//! event_loop.defer(move || drop(connection));
+//! event_loop.on_idle(|| cache.evict_expired());
+//! ```
+//!
+//! Signals are handled on the loop as well, once `on_signal` blocked them:
+//!
+//! ```no-run
+//! event_loop.on_signal(&[libc::SIGINT, libc::SIGTERM], |_| handle.stop())?;
+//! ```
+//!
+//! And so are children exiting:
+//!
+//! ```no-run
+//! let worker = Command::new("worker").spawn()?;
+//! event_loop.on_child_exit(worker.id(), |status| restart(status))?;
+//! ```
+//!
+//! Other threads pass the loop messages through its channels:
+//!
+//! ```no-run
+//! let jobs = event_loop.channel(1024, |job| run(job))?;
+//! thread::spawn(move || jobs.send(job));
+//! ```
+//!
+//! Dropping a loop tears it down in a fixed order: its files are deregistered,
+//! the `on_drop` callbacks are called, the handlers and the descriptors they own
+//! (e.g. those of `on_signal`, `on_child_exit` and `channel`) are dropped, and
+//! the epoll is closed last. Failures along the way go to `on_drop_error`:
+//!
+//! ```no-run
+//! event_loop.on_drop(|| log("event loop stopped"));
+//! event_loop.on_drop_error(|fd, error| warn!("fd {}: {}", fd, error));
+//! ```
+//!
+//! Handles are `Send`, so other threads can also hand the loop descriptors to
+//! watch, with handlers that run on the loop's thread:
+//!
+//! ```no-run
+//! let handle = event_loop.handle()?;
+//! thread::spawn(move || {
+//!     let stream = TcpStream::connect(address).unwrap();
+//!     handle.add_raw(stream.as_raw_fd(), EPOLLIN, move |_| serve(&stream));
+//! });
+//! event_loop.run()?;
+//! ```
 //!
 //! # Example
 //!
-//! ```no-run rust
+//! ```no-run
 //! // If we want to use different types of files, we must store them
 //! // as trait-objects. If that's the situation, we must specify the trait (here, AsRawFd).
 //! // The trait must inherit AsRawFd
-//! let mut epoll = EventLoop::<AsRawFd>::new().unwrap();
+//! let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
 //! 
 //! // Register a file-like object onto the epoll.
 //! // The last parameter is a user-defined identifier
 //! epoll.add(&some_pipe)?;
 //! epoll.add(&timer)?;
 //! 
-//! for e in epoll.wait(Timeout::Milliseconds(500)).unwrap() {
-//!     match e.data {
-//!         0 => { /* Do something with the pipe  */ },
-//!         1 => { /* Do something with the timer */ },
-//!         _ => unreachable!()
-//!     };
+//! for (file, event) in epoll.wait(Timeout::Milliseconds(500)).unwrap() {
+//!     if event.is_hangup() {
+//!         /* The file is gone */
+//!     }
+//!     else if file.as_raw_fd() == some_pipe.as_raw_fd() {
+//!         /* Do something with the pipe  */
+//!     }
+//!     else {
+//!         /* Do something with the timer */
+//!     }
 //! }
 //! ```
 
 use super::*;
+use sigset::SigSet;
+use signalfd::{SignalFd, SignalInfo};
+use pidfd::PidFd;
+use counter::Counter;
+use health::{Finding, HealthChecker};
+use std::cell::{Cell, RefCell};
+use std::mem::ManuallyDrop;
+use std::rc::Rc;
+use std::process::ExitStatus;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+pub struct EventLoop<'a, T: AsRawFd + ?Sized + 'a, S = ()> {
+    /// Closed explicitly by `drop`, once everything else is torn down.
+    epoll: ManuallyDrop<EPoll>,
+
+    /// Indexed by the data each file is registered with.
+    files: Slab<Entry<'a, T, S>>,
+    events: Vec<Event>,
+    control: Arc<Control>,
+    timers: Timers<'a>,
+    deferred: Vec<Box<dyn FnOnce() + 'a>>,
+    idle: Vec<Box<dyn FnMut() + 'a>>,
+    error_hook: Option<Hook<'a, T>>,
+    hangup_hook: Option<Hook<'a, T>>,
+    health: Option<Health<'a>>,
+    ctx: Ctx,
+    drop_hooks: Vec<Box<dyn FnOnce() + 'a>>,
+    drop_error_hook: Option<DropErrorHook<'a>>,
+
+    /// What `add` and `add_handler` register files for.
+    interest: EventType,
+    trigger: Trigger,
+    /// `None` if the event buffer grows with the amount of files.
+    capacity: Option<usize>,
+}
+
+/// Creates an `EventLoop` with other defaults than `EventLoop::new`.
+#[derive(Clone, Copy, Debug)]
+pub struct EventLoopBuilder {
+    capacity: Option<usize>,
+    interest: Interest,
+    trigger: Trigger,
+    cloexec: bool,
+    timer_granularity: Duration,
+    timer_slab: Option<usize>,
+}
+
+impl EventLoopBuilder {
+    /// Starts from the defaults of `EventLoop::new`.
+    pub fn new() -> EventLoopBuilder {
+        EventLoopBuilder {
+            capacity: None,
+            interest: Interest::READABLE,
+            trigger: Trigger::Level,
+            cloexec: false,
+            timer_granularity: Duration::ZERO,
+            timer_slab: None,
+        }
+    }
+
+    /// How many events a wait reports at most; by default, as many as there are files.
+    pub fn capacity(mut self, capacity: usize) -> EventLoopBuilder {
+        self.capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// What `add` and `add_handler` register files for; `Interest::READABLE` by default.
+    pub fn interest(mut self, interest: Interest) -> EventLoopBuilder {
+        self.interest = interest;
+        self
+    }
+
+    /// How files are registered, including by `add_with_interest`; `Trigger::Level` by default.
+    pub fn trigger(mut self, trigger: Trigger) -> EventLoopBuilder {
+        self.trigger = trigger;
+        self
+    }
+
+    /// Whether the loop's epoll is closed on exec; it isn't by default, as with `EPoll::new`.
+    pub fn cloexec(mut self, cloexec: bool) -> EventLoopBuilder {
+        self.cloexec = cloexec;
+        self
+    }
+
+    /// Rounds the deadlines of timers up to a multiple of `granularity` since
+    /// the loop was created, so that timers due close together fire in a single
+    /// wakeup; see `EventLoop::timer_stats`. Timers may then fire up to
+    /// `granularity` late, but never early.
+    ///
+    /// Deadlines aren't rounded by default, i.e. with a zero granularity.
+    pub fn timer_granularity(mut self, granularity: Duration) -> EventLoopBuilder {
+        self.timer_granularity = granularity;
+        self
+    }
+
+    /// Gives the loop a `TimerSlab` with room for `capacity` timers, allocated
+    /// up front; see `EventLoop::timer_slab`.
+    pub fn timer_slab(mut self, capacity: usize) -> EventLoopBuilder {
+        self.timer_slab = Some(capacity);
+        self
+    }
+
+    /// Creates the event loop, e.g. `build::<dyn AsRawFd>()`.
+    pub fn build<'a, T: AsRawFd + ?Sized + 'a>(self) -> io::Result<EventLoop<'a, T>> {
+        self.build_with_state()
+    }
+
+    /// Creates an event loop that keeps a state of type `S` with its files,
+    /// e.g. `build_with_state::<TcpStream, Connection>()`.
+    pub fn build_with_state<'a, T: AsRawFd + ?Sized + 'a, S>(self) -> io::Result<EventLoop<'a, T, S>> {
+        let flags = if self.cloexec { EPOLL_CLOEXEC } else { CreateFlags::empty() };
+
+        Ok(EventLoop {
+               epoll: ManuallyDrop::new(EPoll::with_flags(flags)?),
+               files: Slab::new(),
+               // Room for a wake even before any file is added.
+               events: vec![Event::default(); self.capacity.unwrap_or(1)],
+               control: Arc::new(Control::default()),
+               timers: Timers::new(self.timer_granularity, self.timer_slab),
+               deferred: Vec::new(),
+               idle: Vec::new(),
+               error_hook: None,
+               hangup_hook: None,
+               health: None,
+               ctx: Ctx { cycle: Rc::new(Cell::new((Instant::now(), 0))) },
+               drop_hooks: Vec::new(),
+               drop_error_hook: None,
+               interest: self.interest.events(),
+               trigger: self.trigger,
+               capacity: self.capacity,
+           })
+    }
+}
+
+impl Default for EventLoopBuilder {
+    fn default() -> EventLoopBuilder {
+        EventLoopBuilder::new()
+    }
+}
+
+/// The state shared between a loop and its handles.
+#[derive(Default)]
+struct Control {
+    stopped: AtomicBool,
+    commands: Mutex<Vec<Command>>,
+}
+
+impl Control {
+    fn push(&self, command: Command) {
+        self.commands.lock().unwrap_or_else(|e| e.into_inner()).push(command);
+    }
+}
+
+/// A change requested through a handle, applied on the loop's thread.
+enum Command {
+    Add(RawFd, EventType, Box<dyn FnMut(Event) + Send>),
+    Remove(RawFd),
+}
+
+/// Controls an `EventLoop` from its handlers, or from other threads.
+#[derive(Clone)]
+pub struct LoopHandle {
+    control: Arc<Control>,
+    waker: Waker,
+}
+
+impl LoopHandle {
+    /// Makes `run` return once the handlers of the current iteration are done,
+    /// waking the loop if it is blocked.
+    pub fn stop(&self) {
+        self.control.stopped.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Makes a blocked `wait`, `dispatch` or `run` iteration return early.
+    pub fn wake(&self) {
+        // The counter only overflows after 2^64 - 1 wakes without a wait.
+        let _ = self.waker.wake();
+    }
+
+    /// Asks the loop to watch a descriptor, calling `handler` with its events.
+    ///
+    /// The registration happens on the loop's thread, before its next wait,
+    /// and a failure to add it is returned from there. The descriptor must stay
+    /// open until it is removed; moving its owner into `handler` does that.
+    pub fn add_raw<E, F>(&self, fd: RawFd, events: E, handler: F)
+        where E: Into<EventType>,
+              F: FnMut(Event) + Send + 'static
+    {
+        self.push(Command::Add(fd, events.into(), Box::new(handler)));
+    }
+
+    /// Asks the loop to stop watching a descriptor, before its next wait.
+    ///
+    /// Descriptors the loop doesn't watch by then are ignored.
+    pub fn remove_raw(&self, fd: RawFd) {
+        self.push(Command::Remove(fd));
+    }
+
+    fn push(&self, command: Command) {
+        self.control.push(command);
+        self.wake();
+    }
+}
+
+struct Entry<'a, T: ?Sized + 'a, S> {
+    fd: RawFd,
+    /// `None` for descriptors added by their number, which only have a handler.
+    file: Option<&'a T>,
+    handler: Option<Handler<'a>>,
+    state: Option<S>,
+    /// What the file is registered for, unless paused.
+    events: EventType,
+    paused: bool,
+}
+
+type Handler<'a> = Box<dyn FnMut(Event) + 'a>;
+
+/// The periodic health checks of a loop's files; see `EventLoop::check_health`.
+struct Health<'a> {
+    checker: HealthChecker,
+    /// Set by the timer of the checks, and cleared once `dispatch` ran one.
+    due: Rc<Cell<bool>>,
+    timer: TimerId,
+    callback: Box<dyn FnMut(Finding) + 'a>,
+}
+
+/// Called for a file's errors or hangups; returns whether to remove the file.
+type Hook<'a, T> = Box<dyn FnMut(&'a T, Event) -> bool + 'a>;
+
+/// Called with the descriptors a loop failed to deregister or close while being dropped.
+type DropErrorHook<'a> = Box<dyn FnMut(RawFd, io::Error) + 'a>;
+
+/// What `dispatch_with` calls for files with a state.
+type StateHandler<'h, 'a, T, S> = &'h mut dyn FnMut(&'a T, Event, &mut S);
+
+/// The time and tick of a loop's current dispatch cycle; see `EventLoop::ctx`.
+///
+/// Reading it costs no system call, unlike `Instant::now`.
+#[derive(Clone, Debug)]
+pub struct Ctx {
+    cycle: Rc<Cell<(Instant, u64)>>,
+}
+
+impl Ctx {
+    /// When the current cycle's wait returned; when the loop was created, before its first cycle.
+    pub fn now(&self) -> Instant {
+        self.cycle.get().0
+    }
+
+    /// The number of the current cycle: 1 for the first, and growing by one for each.
+    pub fn tick(&self) -> u64 {
+        self.cycle.get().1
+    }
+}
+
+/// Why `EventLoop::run_once` returned. When several things happened in the
+/// same wait, the first of these that applies is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeReason {
+    /// The loop's files raised this many events.
+    Events(usize),
+    /// No file raised an event, but this many timers fired.
+    TimerFired(usize),
+    /// Only a `LoopHandle` woke the loop, e.g. to apply changes or to stop it.
+    Waker,
+    /// The timeout passed without anything happening.
+    TimedOut,
+    /// A signal interrupted the wait; nothing was handled.
+    Interrupted,
+}
+
+/// What a single `dispatch` did.
+struct Cycle {
+    /// The amount of callbacks called.
+    handled: usize,
+    /// The amount of events raised by the loop's files.
+    events: usize,
+    timers: usize,
+    /// Whether a `LoopHandle` woke the wait.
+    woken: bool,
+}
+
+/// Identifies a timer of an `EventLoop`, to cancel it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+/// How well a loop's timers coalesce; see `EventLoopBuilder::timer_granularity`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimerStats {
+    /// The amount of times timers fired.
+    pub fired: u64,
+    /// The amount of dispatch cycles in which timers fired.
+    pub wakeups: u64,
+}
+
+/// Identifies a timer of a `TimerSlab`, to cancel it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SlabTimerId {
+    slot: u32,
+    generation: u32,
+}
+
+/// Oneshot timers in a fixed amount of slots, allocated with the loop, which
+/// can be scheduled from handlers without allocating; see
+/// `EventLoopBuilder::timer_slab`. The loop calls its `on_slab_timer` callback
+/// with the token of each timer that fires.
+///
+/// Clones share the same slots. The slots are scanned on every `dispatch`, so
+/// the slab suits up to a few thousand timers.
+#[derive(Clone)]
+pub struct TimerSlab {
+    slots: Rc<RefCell<SlabSlots>>,
+}
+
+struct SlabSlots {
+    slots: Vec<SlabSlot>,
+    /// Indexes of the unarmed slots; never outgrows its initial capacity.
+    free: Vec<u32>,
+    /// Numbers the timers in the order they were armed.
+    next_sequence: u64,
+    epoch: Instant,
+    granularity: Duration,
+}
+
+#[derive(Clone, Copy)]
+struct SlabSlot {
+    armed: bool,
+    /// `None` for timers too far in the future to ever fire.
+    deadline: Option<Instant>,
+    token: u64,
+    generation: u32,
+    sequence: u64,
+}
+
+impl TimerSlab {
+    fn new(capacity: usize, epoch: Instant, granularity: Duration) -> TimerSlab {
+        let slot = SlabSlot { armed: false, deadline: None, token: 0, generation: 0, sequence: 0 };
+        TimerSlab {
+            slots: Rc::new(RefCell::new(SlabSlots {
+                slots: vec![slot; capacity],
+                free: (0..capacity as u32).rev().collect(),
+                next_sequence: 0,
+                epoch,
+                granularity,
+            })),
+        }
+    }
+
+    /// Fires a timer with `token` once `delay` has passed, without allocating.
+    ///
+    /// Fails with `ENOSPC` if all the slots are taken. A delay too long to
+    /// represent never passes, but takes a slot until cancelled.
+    pub fn after(&self, delay: Duration, token: u64) -> io::Result<SlabTimerId> {
+        let mut slots = self.slots.borrow_mut();
+        let index = slots.free.pop().ok_or_else(|| Error::from_raw_os_error(libc::ENOSPC))?;
+        let deadline = Instant::now().checked_add(delay).and_then(|due| round_up(slots.epoch, slots.granularity, due));
+        let sequence = slots.next_sequence;
+        slots.next_sequence += 1;
+
+        let slot = &mut slots.slots[index as usize];
+        *slot = SlabSlot { armed: true, deadline, token, generation: slot.generation, sequence };
+
+        Ok(SlabTimerId { slot: index, generation: slot.generation })
+    }
+
+    /// Cancels a timer; returns false if it already fired, or was cancelled before.
+    pub fn cancel(&self, timer: SlabTimerId) -> bool {
+        let mut slots = self.slots.borrow_mut();
+        match slots.slots.get(timer.slot as usize) {
+            Some(slot) if slot.armed && slot.generation == timer.generation => {},
+            _ => return false,
+        }
+
+        slots.release(timer.slot);
+        true
+    }
+
+    /// The amount of timers that can be armed at once.
+    pub fn capacity(&self) -> usize {
+        self.slots.borrow().slots.len()
+    }
+
+    /// The amount of timers armed.
+    pub fn len(&self) -> usize {
+        let slots = self.slots.borrow();
+        slots.slots.len() - slots.free.len()
+    }
+
+    /// Whether no timer is armed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.slots.borrow().slots.iter().filter(|slot| slot.armed).filter_map(|slot| slot.deadline).min()
+    }
+
+    /// Calls `callback` with the token of each timer due by `now`, and returns
+    /// how many fired. Timers armed by `callback` wait for the next call.
+    fn fire(&self, now: Instant, callback: &mut Option<Box<dyn FnMut(u64) + '_>>) -> usize {
+        let (capacity, armed_before) = {
+            let slots = self.slots.borrow();
+            (slots.slots.len(), slots.next_sequence)
+        };
+
+        let mut fired = 0;
+        for index in 0..capacity {
+            let token = {
+                let mut slots = self.slots.borrow_mut();
+                let slot = slots.slots[index];
+                if !slot.armed || slot.sequence >= armed_before || slot.deadline.is_none_or(|deadline| deadline > now) {
+                    continue;
+                }
+                slots.release(index as u32);
+                slot.token
+            };
+
+            fired += 1;
+            if let Some(callback) = callback.as_mut() {
+                callback(token);
+            }
+        }
+
+        fired
+    }
+}
+
+impl SlabSlots {
+    fn release(&mut self, index: u32) {
+        let slot = &mut self.slots[index as usize];
+        slot.armed = false;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index);
+    }
+}
+
+impl ::std::fmt::Debug for TimerSlab {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        f.debug_struct("TimerSlab").field("capacity", &self.capacity()).field("len", &self.len()).finish()
+    }
+}
+
+/// Rounds `due` up to a multiple of `granularity` since `epoch`, or returns
+/// `None` if that can't be represented.
+fn round_up(epoch: Instant, granularity: Duration, due: Instant) -> Option<Instant> {
+    if granularity.is_zero() {
+        return Some(due);
+    }
+
+    let granularity = granularity.as_nanos();
+    let since = due.saturating_duration_since(epoch).as_nanos().div_ceil(granularity) * granularity;
+    let secs = ::std::convert::TryFrom::try_from(since / 1_000_000_000).ok()?;
+    epoch.checked_add(Duration::new(secs, (since % 1_000_000_000) as u32))
+}
+
+struct Timer<'a> {
+    /// When the timer is due; `None` for timers too far in the future to ever fire.
+    due: Option<Instant>,
+    /// When it fires: `due`, rounded up to the loop's granularity.
+    deadline: Option<Instant>,
+    /// `None` for timers that fire once.
+    interval: Option<Duration>,
+    callback: Box<dyn FnMut() + 'a>,
+}
+
+/// The timers of a loop, ordered by their deadlines.
+struct Timers<'a> {
+    /// Entries of cancelled or rescheduled timers are dropped once they reach the top.
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    timers: HashMap<u64, Timer<'a>>,
+    next_id: u64,
+
+    /// Deadlines are rounded up to multiples of the granularity since the epoch.
+    epoch: Instant,
+    granularity: Duration,
+    stats: TimerStats,
+
+    slab: Option<TimerSlab>,
+    slab_callback: Option<Box<dyn FnMut(u64) + 'a>>,
+}
+
+impl<'a> Timers<'a> {
+    fn new(granularity: Duration, slab: Option<usize>) -> Timers<'a> {
+        let epoch = Instant::now();
+        Timers {
+            deadlines: BinaryHeap::new(),
+            timers: HashMap::new(),
+            next_id: 0,
+            epoch,
+            granularity,
+            stats: TimerStats::default(),
+            slab: slab.map(|capacity| TimerSlab::new(capacity, epoch, granularity)),
+            slab_callback: None,
+        }
+    }
+
+    fn insert(&mut self, timer: Timer<'a>) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.schedule(id, timer);
+
+        TimerId(id)
+    }
+
+    fn schedule(&mut self, id: u64, mut timer: Timer<'a>) {
+        timer.deadline = timer.due.and_then(|due| round_up(self.epoch, self.granularity, due));
+        if let Some(deadline) = timer.deadline {
+            self.deadlines.push(Reverse((deadline, id)));
+        }
+        self.timers.insert(id, timer);
+    }
+
+    /// The earliest deadline of a live timer.
+    fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.timers.get(&id).is_some_and(|timer| timer.deadline == Some(deadline)) {
+                return Some(deadline);
+            }
+            self.deadlines.pop();
+        }
+
+        None
+    }
+
+    /// The earliest deadline of any timer, including the slab's.
+    fn earliest(&mut self) -> Option<Instant> {
+        let slab = self.slab.as_ref().and_then(TimerSlab::next_deadline);
+        self.next_deadline().into_iter().chain(slab).min()
+    }
+
+    /// Shortens `timeout` to end at the next deadline.
+    fn limit(&mut self, timeout: Timeout) -> Timeout {
+        let remaining = match self.earliest() {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => return timeout,
+        };
+
+        match timeout.as_duration() {
+            Some(duration) if duration <= remaining => timeout,
+            _ => remaining.into(),
+        }
+    }
+
+    /// Calls the timers due by `now`, and returns how many were called.
+    ///
+    /// Repeating timers are rescheduled by their interval, skipping ticks that were missed.
+    fn fire(&mut self, now: Instant) -> usize {
+        let mut due = Vec::new();
+        while let Some(deadline) = self.next_deadline() {
+            if deadline > now {
+                break;
+            }
+            let Reverse((_, id)) = self.deadlines.pop().expect("the deadline was just seen");
+            due.push((id, self.timers.remove(&id).expect("the deadline is live")));
+        }
+
+        let mut fired = due.len();
+        if let Some(ref slab) = self.slab {
+            fired += slab.fire(now, &mut self.slab_callback);
+        }
+        if fired > 0 {
+            self.stats.fired += fired as u64;
+            self.stats.wakeups += 1;
+        }
+
+        for (id, mut timer) in due {
+            (timer.callback)();
+
+            if let Some(interval) = timer.interval {
+                timer.due = match timer.due.and_then(|due| due.checked_add(interval)) {
+                    Some(due) if due > now => Some(due),
+                    _ => now.checked_add(interval),
+                };
+                self.schedule(id, timer);
+            }
+        }
+
+        fired
+    }
+}
+
+impl<'a, T: AsRawFd + ?Sized + 'a> EventLoop<'a, T> {
+    /// Creates a new event loop
+    pub fn new() -> std::io::Result<EventLoop<'a, T>> {
+        EventLoopBuilder::new().build()
+    }
+}
+
+impl<'a, T: AsRawFd + ?Sized + 'a, S> EventLoop<'a, T, S> {
+
+    /// Registers a file onto the event loop, for readability unless configured otherwise.
+    pub fn add(&mut self, file: &'a T) -> io::Result<()> {
+        let interest = self.interest;
+        self.add_with_interest(file, interest)
+    }
+
+    /// Registers a file onto the event loop with its own event mask,
+    /// e.g. `EPOLLOUT` to learn when a connect completes.
+    pub fn add_with_interest<E: Into<EventType>>(&mut self, file: &'a T, events: E) -> io::Result<()> {
+        self.insert(file, events.into(), None, None)
+    }
+
+    /// Registers a file onto the event loop like `add`, with a closure that
+    /// `dispatch` calls with the file's events.
+    pub fn add_handler<F: FnMut(Event) + 'a>(&mut self, file: &'a T, handler: F) -> io::Result<()> {
+        let interest = self.interest;
+        self.insert(file, interest, Some(Box::new(handler)), None)
+    }
+
+    /// Registers a file onto the event loop like `add`, together with a state
+    /// that `dispatch_with` hands out with the file's events.
+    pub fn add_with_state(&mut self, file: &'a T, state: S) -> io::Result<()> {
+        let interest = self.interest;
+        self.insert(file, interest, None, Some(state))
+    }
+
+    /// Returns the state a file was added with.
+    pub fn state(&self, file: &T) -> Option<&S> {
+        let index = self.find_file_index(file.as_raw_fd())?;
+        self.files.get(index).and_then(|entry| entry.state.as_ref())
+    }
+
+    /// Returns the state a file was added with, mutably.
+    pub fn state_mut(&mut self, file: &T) -> Option<&mut S> {
+        let index = self.find_file_index(file.as_raw_fd())?;
+        self.files.get_mut(index).and_then(|entry| entry.state.as_mut())
+    }
+
+    /// Registers a descriptor onto the event loop with a closure that `dispatch`
+    /// calls with its events; `wait` doesn't report it.
+    ///
+    /// The descriptor must stay open until it is removed with `remove_raw`.
+    pub fn add_raw<E, F>(&mut self, fd: RawFd, events: E, handler: F) -> io::Result<()>
+        where E: Into<EventType>,
+              F: FnMut(Event) + 'a
+    {
+        self.insert_entry(Entry { fd, file: None, handler: Some(Box::new(handler)), state: None, events: events.into(), paused: false })
+    }
+
+    /// Changes the events a file is registered for, e.g. to `EPOLLOUT` once
+    /// its outgoing buffer fills. Its handler and state are kept.
+    ///
+    /// Paused files stay paused, and are registered for `events` once resumed.
+    ///
+    /// Fails with `ENOENT` for files that aren't in the loop.
+    pub fn modify<E: Into<EventType>>(&mut self, file: &T, events: E) -> io::Result<()> {
+        let index = self.file_index(file)?;
+        let events = self.triggered(events.into());
+
+        if !self.files.get(index).expect("the file was just found").paused {
+            self.epoll.modify_raw(file.as_raw_fd(), events, index as u64)?;
+        }
+        self.files.get_mut(index).expect("the file was just found").events = events;
+
+        Ok(())
+    }
+
+    /// Stops reporting a file's events until it is resumed, e.g. a socket whose
+    /// peer doesn't keep up. Its handler and state are kept.
+    ///
+    /// Errors and hangups are still reported, as epoll always watches for them.
+    /// Fails with `ENOENT` for files that aren't in the loop.
+    pub fn pause(&mut self, file: &T) -> io::Result<()> {
+        self.set_paused(file, true)
+    }
+
+    /// Reports a paused file's events again.
+    ///
+    /// Fails with `ENOENT` for files that aren't in the loop.
+    pub fn resume(&mut self, file: &T) -> io::Result<()> {
+        self.set_paused(file, false)
+    }
+
+    fn set_paused(&mut self, file: &T, paused: bool) -> io::Result<()> {
+        let index = self.file_index(file)?;
+        let entry = self.files.get_mut(index).expect("the file was just found");
+        let events = if paused { EventType::empty() } else { entry.events };
+
+        self.epoll.modify_raw(entry.fd, events, index as u64)?;
+        entry.paused = paused;
+        Ok(())
+    }
+
+    /// Removes a file from the event loop.
+    pub fn remove(&mut self, file: &'a T) -> io::Result<()> {
+        self.remove_raw(file.as_raw_fd())
+    }
+
+    /// Removes a file, or a descriptor added by `add_raw`, by its descriptor.
+    pub fn remove_raw(&mut self, fd: RawFd) -> io::Result<()> {
+        let index = self.find_file_index(fd);
+        self.epoll.remove_raw(fd)?;
+
+        if let Some(index) = index {
+            self.files.remove(index);
+        }
+
+        Ok(())
+    }
+
+    /// Removes every file for which `keep` returns false.
+    ///
+    /// The predicate sees all files before any of them is removed; descriptors
+    /// added by `add_raw` are kept.
+    /// A file is dropped from the loop even if deregistering it fails
+    /// (e.g. because it was already closed); the first such error is returned
+    /// after all rejected files were handled.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) -> io::Result<()> {
+        let rejected: Vec<usize> = self.files.iter().filter(|&(_, entry)| entry.file.is_some_and(|file| !keep(file))).map(|(index, _)| index).collect();
+        let mut result = Ok(());
+
+        for index in rejected {
+            let entry = self.files.remove(index).expect("rejected files are in the loop");
+            if let Err(e) = self.epoll.remove_raw(entry.fd) {
+                if result.is_ok() {
+                    result = Err(e.into());
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Waits for incoming events and returns an iterator over the
+    /// files that raised the events, together with the events.
+    pub fn wait<U: Into<Timeout>>(&mut self, timeout: U) -> io::Result<EventLoopIterator<'_, 'a, T, S>> {
+        self.apply_commands()?;
+        let event_amount = self.epoll.wait(&mut self.events, timeout)?;
+
+        Ok(EventLoopIterator {
+               event_loop: self,
+               index: 0,
+               amount: event_amount,
+           })
+    }
+
+    /// Waits for incoming events, and calls the handlers of the files that raised them,
+    /// then the timers that are due. The wait ends early at the next timer's deadline.
+    ///
+    /// Deferred callbacks are called before waiting, and idle callbacks too if
+    /// the wait would block.
+    ///
+    /// Changes requested while the loop is borrowed, through a `LoopHandle`, by
+    /// `mark_remove` or by the error and hangup hooks, are applied first, before
+    /// anything else and before the wait. A descriptor a handler adds through a
+    /// handle is therefore waited on by the very next cycle, and one it removes
+    /// is not reported by it anymore.
+    ///
+    /// Returns the amount of callbacks called; events of files without a handler are dropped.
+    pub fn dispatch<U: Into<Timeout>>(&mut self, timeout: U) -> io::Result<usize> {
+        Ok(self.dispatch_inner(timeout.into(), None)?.handled)
+    }
+
+    /// Like `dispatch`, but also calls `handler` with the events of files added
+    /// with a state, and their state.
+    pub fn dispatch_with<U, F>(&mut self, timeout: U, mut handler: F) -> io::Result<usize>
+        where U: Into<Timeout>,
+              F: FnMut(&'a T, Event, &mut S)
+    {
+        Ok(self.dispatch_inner(timeout.into(), Some(&mut handler))?.handled)
+    }
+
+    fn dispatch_inner(&mut self, timeout: Timeout, mut stateful: Option<StateHandler<'_, 'a, T, S>>) -> io::Result<Cycle> {
+        self.apply_commands()?;
+        let mut handled = self.deferred.len();
+        let mut woken = false;
+        for callback in self.deferred.drain(..) {
+            callback();
+        }
+
+        let timeout = self.timers.limit(timeout);
+        let blocking = timeout.as_duration() != Some(Duration::ZERO);
+        let mut event_amount = 0;
+        if blocking && !self.idle.is_empty() {
+            event_amount = self.epoll.wait(&mut self.events, Timeout::Immediate)?;
+        }
+
+        if event_amount == 0 {
+            let due = self.timers.earliest().is_some_and(|deadline| deadline <= Instant::now());
+            if blocking && !due {
+                for callback in &mut self.idle {
+                    callback();
+                }
+                handled += self.idle.len();
+            }
+
+            event_amount = self.epoll.wait(&mut self.events, timeout)?;
+        }
+
+        let now = Instant::now();
+        self.ctx.cycle.set((now, self.ctx.tick() + 1));
+
+        let mut file_events = 0;
+        for event in &self.events[..event_amount] {
+            let entry = match self.files.get_mut(event.token() as usize) {
+                Some(entry) => entry,
+                None => {
+                    woken |= event.token() == EPoll::WAKE_TOKEN;
+                    continue;
+                },
+            };
+            file_events += 1;
+
+            if let Some(file) = entry.file {
+                let hook = if event.is_error() {
+                    self.error_hook.as_mut()
+                }
+                else if event.is_hangup() {
+                    self.hangup_hook.as_mut()
+                }
+                else {
+                    None
+                };
+
+                if let Some(hook) = hook {
+                    handled += 1;
+                    if hook(file, *event) {
+                        self.control.push(Command::Remove(entry.fd));
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(ref mut handler) = entry.handler {
+                handler(*event);
+                handled += 1;
+            }
+            else if let (Some(file), Some(state), Some(stateful)) = (entry.file, entry.state.as_mut(), stateful.as_mut()) {
+                stateful(file, *event, state);
+                handled += 1;
+            }
+        }
+
+        let fired = self.timers.fire(now);
+        handled += fired;
+        if self.health.as_ref().is_some_and(|health| health.due.replace(false)) {
+            self.check_files();
+        }
+
+        Ok(Cycle { handled, events: file_events, timers: fired, woken })
+    }
+
+    /// Dispatches events until stopped by `stop` or a `LoopHandle`.
+    ///
+    /// Interrupted waits are retried.
+    pub fn run(&mut self) -> io::Result<()> {
+        self.run_until(|| false)
+    }
+
+    /// Dispatches events until `done` returns true, or the loop is stopped.
+    ///
+    /// `done` is checked before every wait, and interrupted waits are retried.
+    pub fn run_until<F: FnMut() -> bool>(&mut self, mut done: F) -> io::Result<()> {
+        while !self.control.stopped.swap(false, Ordering::SeqCst) && !done() {
+            self.run_once(Timeout::Indefinite)?;
+        }
+
+        Ok(())
+    }
+
+    /// Dispatches the events of a single wait, for loops driven from another
+    /// main loop, and tells what ended the wait. Unlike `dispatch`, an
+    /// interrupted wait handles nothing instead of failing.
+    ///
+    /// As with `dispatch`, pending changes are applied before the wait.
+    pub fn run_once<U: Into<Timeout>>(&mut self, timeout: U) -> io::Result<WakeReason> {
+        let cycle = match self.dispatch_inner(timeout.into(), None) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return Ok(WakeReason::Interrupted),
+            result => result?,
+        };
+
+        Ok(if cycle.events > 0 {
+            WakeReason::Events(cycle.events)
+        }
+        else if cycle.timers > 0 {
+            WakeReason::TimerFired(cycle.timers)
+        }
+        else if cycle.woken {
+            WakeReason::Waker
+        }
+        else {
+            WakeReason::TimedOut
+        })
+    }
+
+    /// Dispatches whatever is ready without blocking, for loops nested in another
+    /// loop or epoll that reported them readable.
+    ///
+    /// A nested loop's timers only fire when it is polled, so its parent must
+    /// wake up in time for them.
+    pub fn poll_nested(&mut self) -> io::Result<usize> {
+        match self.dispatch(Timeout::Immediate) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(0),
+            result => result,
+        }
+    }
+
+    /// Nests another loop in this one: whenever the child has events ready,
+    /// `dispatch` polls it with `poll_nested`, and a failure to do so is dropped.
+    ///
+    /// Gives the child's files a tier of their own, e.g. handled only after the
+    /// parent's, or a subsystem its own loop.
+    pub fn add_nested<'c: 'a, U: AsRawFd + ?Sized + 'c, C: 'a>(&mut self, child: &'a mut EventLoop<'c, U, C>) -> io::Result<()> {
+        let fd = child.as_raw_fd();
+        self.add_raw(fd, EPOLLIN, move |_| { let _ = child.poll_nested(); })
+    }
+
+    /// Calls `callback` from `dispatch` once `delay` has passed.
+    ///
+    /// A delay too long to represent, e.g. `Duration::MAX`, never passes.
+    pub fn after<F: FnOnce() + 'a>(&mut self, delay: Duration, callback: F) -> TimerId {
+        let mut callback = Some(callback);
+        self.timers.insert(Timer {
+            due: Instant::now().checked_add(delay),
+            deadline: None,
+            interval: None,
+            callback: Box::new(move || if let Some(callback) = callback.take() { callback() }),
+        })
+    }
+
+    /// Calls `callback` from `dispatch` every `interval`, until cancelled.
+    ///
+    /// Fails with `EINVAL` if `interval` is zero. An interval too long to
+    /// represent never passes.
+    pub fn every<F: FnMut() + 'a>(&mut self, interval: Duration, callback: F) -> io::Result<TimerId> {
+        if interval.is_zero() {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(self.timers.insert(Timer {
+            due: Instant::now().checked_add(interval),
+            deadline: None,
+            interval: Some(interval),
+            callback: Box::new(callback),
+        }))
+    }
+
+    /// The loop's timer slab, if the builder gave it one. Clones of it can be
+    /// moved into handlers to schedule timers from them without allocating.
+    pub fn timer_slab(&self) -> Option<TimerSlab> {
+        self.timers.slab.clone()
+    }
+
+    /// Calls `callback` from `dispatch` with the token of each timer of the
+    /// timer slab that fires, replacing any previous callback.
+    pub fn on_slab_timer<F: FnMut(u64) + 'a>(&mut self, callback: F) {
+        self.timers.slab_callback = Some(Box::new(callback));
+    }
+
+    /// How many times timers fired, and in how many wakeups.
+    pub fn timer_stats(&self) -> TimerStats {
+        self.timers.stats
+    }
+
+    /// Cancels a timer; returns false if it already fired for the last time,
+    /// or was cancelled before.
+    pub fn cancel(&mut self, timer: TimerId) -> bool {
+        self.timers.timers.remove(&timer.0).is_some()
+    }
+
+    /// Calls `callback` from `dispatch` with each of `signals` that is delivered.
+    ///
+    /// The signals are blocked in the calling thread so that they are queued
+    /// for the loop instead; threads spawned afterwards inherit the mask, but
+    /// threads that already run must block them on their own.
+    pub fn on_signal<F: FnMut(SignalInfo) + 'a>(&mut self, signals: &[libc::c_int], mut callback: F) -> io::Result<()> {
+        let set = SigSet::of(signals)?;
+        let signalfd = SignalFd::new(&set)?;
+        let previous = set.block()?;
+        let fd = signalfd.as_raw_fd();
+        let added = self.add_raw(fd, EPOLLIN, move |_| {
+            // Reading only fails once the descriptor is drained.
+            while let Ok(Some(info)) = signalfd.read() {
+                callback(info);
+            }
+        });
+
+        if added.is_err() {
+            previous.set_thread_mask()?;
+        }
+
+        added
+    }
+
+    /// Calls `callback` from `dispatch` with the exit status of the child `pid`
+    /// (e.g. `child.id()`), once it exits.
+    ///
+    /// The loop reaps the child, so it must not be waited for otherwise.
+    pub fn on_child_exit<F: FnOnce(ExitStatus) + 'a>(&mut self, pid: u32, callback: F) -> io::Result<()> {
+        let pidfd = PidFd::open(pid)?;
+        let fd = pidfd.as_raw_fd();
+        let control = self.control.clone();
+        let mut callback = Some(callback);
+
+        self.add_raw(fd, EPOLLIN, move |_| {
+            match pidfd.try_wait() {
+                Ok(None) => return,
+                Ok(Some(status)) => if let Some(callback) = callback.take() { callback(status) },
+                // Someone else reaped the child; its status is gone.
+                Err(_) => {},
+            }
+
+            control.push(Command::Remove(fd));
+        })
+    }
+
+    /// Calls `callback` from `dispatch` with the count `counter` accumulated
+    /// since it was last drained, whenever it is non-zero.
+    ///
+    /// A counter in semaphore mode is drained one unit per read, so its
+    /// callback gets the sum.
+    pub fn on_counter<F: FnMut(u64) + 'a>(&mut self, counter: &'a Counter, mut callback: F) -> io::Result<()> {
+        self.add_raw(counter.as_raw_fd(), EPOLLIN, move |_| {
+            // The counter is readable, so taking from it doesn't fail.
+            if let Ok(count) = counter.take_all() {
+                if count > 0 {
+                    callback(count);
+                }
+            }
+        })
+    }
+
+    /// Creates a channel whose messages are handed to `callback` by `dispatch`,
+    /// on the loop's thread. See `ring::channel` for how the channel behaves.
+    ///
+    /// The channel holds `capacity` messages at most; senders choose between
+    /// failing and waiting once it is full, and `Sender::stats` tells the
+    /// depth of its queue. The loop's own thread must not wait on it.
+    pub fn channel<M, F>(&mut self, capacity: usize, mut callback: F) -> io::Result<ring::Sender<M>>
+        where M: Copy + Send + 'a,
+              F: FnMut(M) + 'a
+    {
+        let (sender, mut receiver) = ring::channel(capacity)?;
+        let fd = receiver.as_raw_fd();
+        let mut batch = Vec::new();
+
+        self.add_raw(fd, EPOLLIN, move |_| {
+            // The receiver's counter is readable, so taking it doesn't fail.
+            let _ = receiver.recv_batch(&mut batch);
+            for message in batch.drain(..) {
+                callback(message);
+            }
+        })?;
+
+        Ok(sender)
+    }
+
+    /// Has `dispatch` call `hook` with the files reporting `EPOLLERR`, before
+    /// their handlers. Files for which it returns true are removed before
+    /// the next wait, and their handlers aren't called.
+    pub fn on_error<F: FnMut(&'a T, Event) -> bool + 'a>(&mut self, hook: F) {
+        self.error_hook = Some(Box::new(hook));
+    }
+
+    /// Like `on_error`, for files reporting `EPOLLHUP` without an error.
+    pub fn on_hangup<F: FnMut(&'a T, Event) -> bool + 'a>(&mut self, hook: F) {
+        self.hangup_hook = Some(Box::new(hook));
+    }
+
+    /// Checks the loop's files every `interval` for descriptors that were closed
+    /// or replaced behind its back, drops those files from the loop, and calls
+    /// `callback` with each finding; see the `health` module.
+    ///
+    /// The files are first checked right away, and are remembered as they are
+    /// now. Replaces the checks set up before; fails with `EINVAL` if `interval` is zero.
+    pub fn check_health<F: FnMut(Finding) + 'a>(&mut self, interval: Duration, callback: F) -> io::Result<()> {
+        let due = Rc::new(Cell::new(false));
+        let timer = {
+            let due = due.clone();
+            self.every(interval, move || due.set(true))?
+        };
+
+        if let Some(previous) = self.health.take() {
+            self.cancel(previous.timer);
+        }
+
+        self.health = Some(Health { checker: HealthChecker::new(), due, timer, callback: Box::new(callback) });
+        self.check_files();
+        Ok(())
+    }
+
+    /// Calls `callback` when the loop is dropped, after its files were
+    /// deregistered but before its handlers (and the descriptors they own) are
+    /// dropped. Callbacks are called in the order they were added.
+    pub fn on_drop<F: FnOnce() + 'a>(&mut self, callback: F) {
+        self.drop_hooks.push(Box::new(callback));
+    }
+
+    /// Has the loop's teardown call `hook` with each descriptor it fails to
+    /// deregister, and with the epoll if closing it fails; such failures are
+    /// ignored otherwise.
+    pub fn on_drop_error<F: FnMut(RawFd, io::Error) + 'a>(&mut self, hook: F) {
+        self.drop_error_hook = Some(Box::new(hook));
+    }
+
+    /// Returns the loop's context, whose time and tick `dispatch` updates once
+    /// per cycle, right after its wait. Handlers and timers called by the same
+    /// cycle see the same values; deferred and idle callbacks, which are called
+    /// before the wait, see the previous cycle's.
+    pub fn ctx(&self) -> Ctx {
+        self.ctx.clone()
+    }
+
+    /// Calls `callback` once, at the start of the next `dispatch`.
+    pub fn defer<F: FnOnce() + 'a>(&mut self, callback: F) {
+        self.deferred.push(Box::new(callback));
+    }
+
+    /// Calls `callback` whenever `dispatch` finds nothing to do, just before it blocks.
+    pub fn on_idle<F: FnMut() + 'a>(&mut self, callback: F) {
+        self.idle.push(Box::new(callback));
+    }
+
+    /// Makes `run` return once the handlers of the current iteration are done.
+    pub fn stop(&self) {
+        self.control.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns a handle that can stop or change the loop from handlers or other
+    /// threads, even while it is blocked.
+    pub fn handle(&self) -> io::Result<LoopHandle> {
+        Ok(LoopHandle { control: self.control.clone(), waker: self.epoll.waker()? })
+    }
+
+    fn insert(&mut self, file: &'a T, events: EventType, handler: Option<Handler<'a>>, state: Option<S>) -> io::Result<()> {
+        let events = self.triggered(events);
+        self.insert_entry(Entry { fd: file.as_raw_fd(), file: Some(file), handler, state, events, paused: false })
+    }
+
+    fn insert_entry(&mut self, entry: Entry<'a, T, S>) -> io::Result<()> {
+        let (fd, events) = (entry.fd, entry.events);
+        let index = self.files.insert(entry);
+
+        if let Err(e) = self.epoll.add_raw(fd, events, index as u64) {
+            self.files.remove(index);
+            return Err(e.into());
+        }
+
+        // One more than there are files, so that a wake doesn't crowd out a file's event.
+        if self.capacity.is_none() && self.events.len() <= self.files.len() {
+            self.events.push(Default::default());
+        }
+
+        Ok(())
+    }
+
+    /// Adds the loop's trigger to the events of a file.
+    fn triggered(&self, events: EventType) -> EventType {
+        match self.trigger {
+            Trigger::Level => events,
+            Trigger::Edge => events | EPOLLET,
+        }
+    }
+
+    /// Applies the changes queued by handles, in order.
+    ///
+    /// Every change is attempted; the first failure is returned.
+    fn apply_commands(&mut self) -> io::Result<()> {
+        let commands = mem::take(&mut *self.control.commands.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut result = Ok(());
+
+        for command in commands {
+            let applied = match command {
+                Command::Add(fd, events, handler) => {
+                    self.insert_entry(Entry { fd, file: None, handler: Some(handler), state: None, events, paused: false })
+                },
+                Command::Remove(fd) if self.find_file_index(fd).is_none() => Ok(()),
+                Command::Remove(fd) => self.remove_raw(fd),
+            };
+
+            if result.is_ok() {
+                result = applied;
+            }
+        }
+
+        result
+    }
+
+    /// Returns the index of a file, or fails with `ENOENT` if it isn't in the loop.
+    fn file_index(&self, file: &T) -> io::Result<usize> {
+        self.find_file_index(file.as_raw_fd()).ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    /// Runs a health check, dropping the stale files.
+    fn check_files(&mut self) {
+        let health = match self.health {
+            Some(ref mut health) => health,
+            None => return,
+        };
+
+        for finding in health.checker.repair(&self.epoll) {
+            // The epoll forgot the descriptor, but the file's index is still its data.
+            self.files.remove(finding.registration.data as usize);
+            (health.callback)(finding);
+        }
+    }
+
+    /// Returns the index of a file using its descriptor.
+    fn find_file_index(&self, fd: RawFd) -> Option<usize> {
+        self.epoll.data_for(fd).map(|data| data as usize)
+    }
+}
+
+/// Tears the loop down in the order described by the module's documentation.
+impl<'a, T: AsRawFd + ?Sized + 'a, S> Drop for EventLoop<'a, T, S> {
+    fn drop(&mut self) {
+        let mut hook = self.drop_error_hook.take();
+        let mut report = |fd, error| if let Some(ref mut hook) = hook { hook(fd, error) };
+
+        for (_, entry) in self.files.iter() {
+            if let Err(e) = self.epoll.remove_raw(entry.fd) {
+                report(entry.fd, e.into());
+            }
+        }
+
+        for callback in self.drop_hooks.drain(..) {
+            callback();
+        }
+
+        drop(mem::replace(&mut self.files, Slab::new()));
+        drop(mem::replace(&mut self.timers, Timers::new(Duration::ZERO, None)));
+        self.deferred.clear();
+        self.idle.clear();
+        self.error_hook = None;
+        self.hangup_hook = None;
+        self.health = None;
+
+        // Nothing uses the epoll past this point.
+        let epoll = unsafe { ManuallyDrop::take(&mut self.epoll) };
+        let fd = epoll.as_raw_fd();
+        if let Err(e) = epoll.close() {
+            report(fd, e);
+        }
+    }
+}
+
+impl<'a, T: AsRawFd + ?Sized + 'a, S> AsRawFd for EventLoop<'a, T, S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl<'a, T: AsRawFd + ?Sized + 'a, S> AsFd for EventLoop<'a, T, S> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.as_fd()
+    }
+}
+
+/// An iterator over an event loop.
+pub struct EventLoopIterator<'a, 'b: 'a, T: AsRawFd + ?Sized + 'b, S: 'a = ()> {
+    event_loop: &'a EventLoop<'b, T, S>,
+    index: usize,
+    amount: usize,
+}
+
+impl<'a, 'b: 'a, T: AsRawFd + ?Sized + 'b, S> EventLoopIterator<'a, 'b, T, S> {
+    /// Removes a file from the loop before its next wait, e.g. once its peer hung up.
+    ///
+    /// Handlers can do the same through `LoopHandle::remove_raw`.
+    pub fn mark_remove(&self, file: &T) {
+        self.event_loop.control.push(Command::Remove(file.as_raw_fd()));
+    }
+}
+
+impl<'a, 'b: 'a, T: AsRawFd + ?Sized + 'b, S> Iterator for EventLoopIterator<'a, 'b, T, S> {
+    type Item = (&'b T, Event);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.amount {
+            let event = self.event_loop.events[self.index];
+            self.index += 1;
+
+            if let Some(file) = self.event_loop.files.get(event.token() as usize).and_then(|entry| entry.file) {
+                return Some((file, event));
+            }
+        }
+
+        None
+    }
+}
+
+/// Identifies a file of an `OwnedEventLoop`.
+///
+/// Tokens of removed files are never confused with later ones, even when an
+/// event of a removed file is still waiting to be handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token {
+    index: u32,
+    generation: u32,
+}
+
+impl Token {
+    /// The token of the file that raised an event of an `OwnedEventLoop`.
+    pub fn of(event: &Event) -> Token {
+        let data = event.token();
+        Token { index: data as u32, generation: (data >> 32) as u32 }
+    }
+
+    fn data(&self) -> u64 {
+        self.index as u64 | (self.generation as u64) << 32
+    }
+}
+
+/// An event loop that owns its files.
+///
+/// Waiting fills a buffer of the caller's, so the loop isn't borrowed while the
+/// events are handled, and files can be added or removed meanwhile. Events of
+/// files removed meanwhile find no file through `get`.
+///
+/// # Example
+///
+/// ```no-run
+/// let mut event_loop = OwnedEventLoop::new()?;
+/// let listener = event_loop.add(TcpListener::bind(address)?)?;
+///
+/// let mut events = [Event::default(); 64];
+/// loop {
+///     let amount = event_loop.wait(&mut events, Timeout::Indefinite)?;
+///     for event in &events[..amount] {
+///         if Token::of(event) == listener {
+///             let (stream, _) = event_loop[listener].accept()?;
+///             event_loop.add(stream)?;
+///         }
+///     }
+/// }
+/// ```
+///
+/// Dropping the loop deregisters its files, then drops them, and closes the
+/// epoll last; failures go to `on_drop_error`.
+pub struct OwnedEventLoop<T: AsRawFd> {
+    /// Closed explicitly by `drop`, once the files are.
+    epoll: ManuallyDrop<EPoll>,
+    files: Slab<T>,
+    drop_error_hook: Option<Box<dyn FnMut(RawFd, io::Error) + Send>>,
+}
+
+impl<T: AsRawFd> OwnedEventLoop<T> {
+    /// Creates a new event loop.
+    pub fn new() -> io::Result<OwnedEventLoop<T>> {
+        Ok(OwnedEventLoop { epoll: ManuallyDrop::new(EPoll::new()?), files: Slab::new(), drop_error_hook: None })
+    }
 
-pub struct EventLoop<'a, T: AsRawFd + ?Sized + 'a> {
-    epoll: EPoll,
-    files: Vec<&'a T>,
-    events: Vec<Event>,
-}
+    /// Has dropping the loop call `hook` with each file it fails to deregister,
+    /// and with the epoll if closing it fails; such failures are ignored otherwise.
+    pub fn on_drop_error<F: FnMut(RawFd, io::Error) + Send + 'static>(&mut self, hook: F) {
+        self.drop_error_hook = Some(Box::new(hook));
+    }
 
-impl<'a, T: AsRawFd + ?Sized + 'a> EventLoop<'a, T> {
-    /// Creates a new event loop
-    pub fn new() -> std::io::Result<EventLoop<'a, T>> {
-        Ok(EventLoop {
-               epoll: EPoll::new()?,
-               files: Vec::new(),
-               events: Vec::new(),
-           })
+    /// Moves a file into the event loop, registering it for readability.
+    pub fn add(&mut self, file: T) -> io::Result<Token> {
+        self.add_with_interest(file, EPOLLIN)
     }
 
-    /// Registers a file onto the event loop.
-    pub fn add(&mut self, file: &'a T) -> io::Result<()> {
-        self.epoll.add(file, EPOLLIN, file.as_raw_fd() as u64)?;
-        self.files.push(file);
+    /// Moves a file into the event loop, registering it with its own event mask.
+    pub fn add_with_interest<E: Into<EventType>>(&mut self, file: T, events: E) -> io::Result<Token> {
+        let fd = file.as_raw_fd();
+        let index = self.files.insert(file);
+        if index > u32::MAX as usize {
+            self.files.remove(index);
+            return Err(Error::from_raw_os_error(libc::ENOSPC));
+        }
 
-        if self.events.len() < self.files.len() {
-            self.events.push(Default::default());
+        let token = Token { index: index as u32, generation: self.files.generation(index) };
+        if let Err(e) = self.epoll.add_raw(fd, events, token.data()) {
+            self.files.remove(index);
+            return Err(e.into());
         }
 
-        Ok(())
+        Ok(token)
     }
 
-    /// Removes a file from the event loop.
-    pub fn remove(&mut self, file: &'a T) -> io::Result<()> {
-        self.epoll.remove(file)?;
+    /// Removes a file from the event loop, and hands it back.
+    ///
+    /// Fails with `ENOENT` for tokens of files that were already removed.
+    pub fn remove(&mut self, token: Token) -> io::Result<T> {
+        let fd = match self.get(token) {
+            Some(file) => file.as_raw_fd(),
+            None => return Err(Error::from_raw_os_error(libc::ENOENT)),
+        };
 
-        if let Some(index) = self.find_file_index(file.as_raw_fd()) {
-            self.files.remove(index);
+        self.epoll.remove_raw(fd)?;
+        Ok(self.files.remove(token.index as usize).expect("the file was just found"))
+    }
+
+    /// Returns a file of the loop, or `None` if it was removed.
+    pub fn get(&self, token: Token) -> Option<&T> {
+        if self.files.generation(token.index as usize) != token.generation {
+            return None;
         }
 
-        Ok(())
+        self.files.get(token.index as usize)
     }
 
-    /// Waits for incoming events and returns an iterator over the
-    /// files that raised the events.
-    pub fn wait(&mut self, timeout: Timeout) -> io::Result<EventLoopIterator<T>> {
-        let event_amount = self.epoll.wait(&mut self.events, timeout)?;
+    /// Returns a file of the loop mutably, or `None` if it was removed.
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        if self.files.generation(token.index as usize) != token.generation {
+            return None;
+        }
 
-        Ok(EventLoopIterator {
-               event_loop: self,
-               index: 0,
-               amount: event_amount,
-           })
+        self.files.get_mut(token.index as usize)
     }
 
-    /// Returns the index of a file using its descriptor.
-    #[inline(always)]
-    fn find_file_index(&self, fd: RawFd) -> Option<usize> {
-        self.files.iter().position(|i| i.as_raw_fd() == fd)
+    /// Waits for events into `events`, and returns their amount; see `Token::of`.
+    pub fn wait<U: Into<Timeout>>(&self, events: &mut [Event], timeout: U) -> io::Result<usize> {
+        self.epoll.wait(events, timeout)
+    }
+}
+
+impl<T: AsRawFd> Drop for OwnedEventLoop<T> {
+    fn drop(&mut self) {
+        let mut hook = self.drop_error_hook.take();
+        let mut report = |fd, error| if let Some(ref mut hook) = hook { hook(fd, error) };
+
+        for (_, file) in self.files.iter() {
+            if let Err(e) = self.epoll.remove_raw(file.as_raw_fd()) {
+                report(file.as_raw_fd(), e.into());
+            }
+        }
+        drop(mem::replace(&mut self.files, Slab::new()));
+
+        // Nothing uses the epoll past this point.
+        let epoll = unsafe { ManuallyDrop::take(&mut self.epoll) };
+        let fd = epoll.as_raw_fd();
+        if let Err(e) = epoll.close() {
+            report(fd, e);
+        }
+    }
+}
+
+impl<T: AsRawFd> ::std::ops::Index<Token> for OwnedEventLoop<T> {
+    type Output = T;
+
+    fn index(&self, token: Token) -> &T {
+        self.get(token).expect("no file for token")
     }
+}
 
-    /// Returns the index of a file using an event.
-    fn find_file_index_by_event(&self, event_index: usize) -> Option<usize> {
-        self.find_file_index(self.events[event_index].data as RawFd)
+impl<T: AsRawFd> ::std::ops::IndexMut<Token> for OwnedEventLoop<T> {
+    fn index_mut(&mut self, token: Token) -> &mut T {
+        self.get_mut(token).expect("no file for token")
     }
 }
 
-/// An iterator over an event loop.
-pub struct EventLoopIterator<'a, 'b: 'a, T: AsRawFd + ?Sized + 'b> {
-    event_loop: &'a EventLoop<'b, T>,
-    index: usize,
-    amount: usize,
+/// Values stored at small indices, which are reused once freed.
+struct Slab<V> {
+    slots: Vec<Option<V>>,
+    /// How many times each index was freed.
+    generations: Vec<u32>,
+    free: Vec<usize>,
 }
 
-impl<'a, 'b: 'a, T: AsRawFd + ?Sized + 'b> Iterator for EventLoopIterator<'a, 'b, T> {
-    type Item = &'a T;
+impl<V> Slab<V> {
+    fn new() -> Slab<V> {
+        Slab { slots: Vec::new(), generations: Vec::new(), free: Vec::new() }
+    }
 
-    fn next(&mut self) -> Option<&'b T> {
-        if self.index >= self.amount {
-            None
-        } else {
-            let idx = self.index;
-            self.index += 1;
+    fn insert(&mut self, value: V) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index] = Some(value);
+                index
+            },
+            None => {
+                self.slots.push(Some(value));
+                self.generations.push(0);
+                self.slots.len() - 1
+            },
+        }
+    }
 
-            self.event_loop
-                .find_file_index_by_event(idx)
-                .map(|i| self.event_loop.files[i])
+    fn remove(&mut self, index: usize) -> Option<V> {
+        let value = self.slots.get_mut(index).and_then(Option::take);
+        if value.is_some() {
+            self.generations[index] = self.generations[index].wrapping_add(1);
+            self.free.push(index);
         }
+
+        value
+    }
+
+    /// Tells the values stored at an index apart; unused indices are at generation 0.
+    fn generation(&self, index: usize) -> u32 {
+        self.generations.get(index).cloned().unwrap_or(0)
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (usize, &V)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| slot.as_ref().map(|value| (index, value)))
+    }
+
+    fn get(&self, index: usize) -> Option<&V> {
+        self.slots.get(index).and_then(Option::as_ref)
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut V> {
+        self.slots.get_mut(index).and_then(Option::as_mut)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use testing;
 
-    struct Fd(RawFd, u32);
+    struct Fd(RawFd);
 
     impl AsRawFd for Fd {
         fn as_raw_fd(&self) -> RawFd {
@@ -169,13 +1745,13 @@ mod tests {
     fn no_event() {
         let timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
         assert!(timerfd >= 0);
-        let timer = Fd(timerfd as RawFd, 0xDEADBEEF);
+        let timer = Fd(timerfd as RawFd);
 
         let mut epoll = EventLoop::new().unwrap();
         epoll.add(&timer).unwrap();
 
         let mut times = 0;
-        for i in epoll.wait(Timeout::Immediate).unwrap() {
+        for (i, _) in epoll.wait(Timeout::Immediate).unwrap() {
             assert_eq!(i.as_raw_fd(), timerfd);
             times += 1;
         }
@@ -198,11 +1774,13 @@ mod tests {
 
         let timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
         assert!(timerfd >= 0);
-        let fd = Fd(timerfd as RawFd, 0xDEADBEEF);
-        let fd2 = Fd2(0);
+        let fd = Fd(timerfd as RawFd);
+        let idle_timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        assert!(idle_timerfd >= 0);
+        let fd2 = Fd2(idle_timerfd as RawFd);
 
         // Here we're creating a an eventloop that contains trait objects.
-        let mut epoll = EventLoop::<AsRawFd>::new().unwrap();
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
         epoll.add(&fd).unwrap();
         epoll.add(&fd2).unwrap();
 
@@ -210,11 +1788,681 @@ mod tests {
         assert!(res >= 0);
 
         let mut times = 0;
-        for i in epoll.wait(Timeout::Milliseconds(1000)).unwrap() {
-            assert_eq!(i.as_raw_fd(), timerfd); // STDIN should probably not pop up.
+        for (i, _) in epoll.wait(Timeout::Milliseconds(1000)).unwrap() {
+            assert_eq!(i.as_raw_fd(), timerfd); // The idle timer should not pop up.
             times += 1;
         }
 
         assert_eq!(times, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn interest_per_file() {
+        let (reader, writer) = pipe::Pipe::new().unwrap().split();
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add(&reader).unwrap();
+        epoll.add_with_interest(&writer, EPOLLOUT).unwrap();
+
+        let ready: Vec<_> = epoll.wait(Timeout::Immediate).unwrap().map(|(f, e)| (f.as_raw_fd(), e.readiness())).collect();
+        assert_eq!(ready, vec![(writer.as_raw_fd(), EPOLLOUT)]);
+    }
+
+    #[test]
+    fn handlers() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let (idle, _idle_writer) = testing::readable_pipe(b"").unwrap();
+        let seen = std::cell::Cell::new(0);
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add_handler(&reader, |event| {
+            assert!(event.is_readable());
+            seen.set(seen.get() + 1);
+        }).unwrap();
+        epoll.add_handler(&idle, |_| panic!("idle pipe reported")).unwrap();
+
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 1);
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 1);
+        assert_eq!(seen.get(), 2);
+
+        epoll.remove(&reader).unwrap();
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 0);
+    }
+
+    #[test]
+    fn owned_files() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let (idle, idle_writer) = testing::readable_pipe(b"").unwrap();
+
+        let mut event_loop = OwnedEventLoop::new().unwrap();
+        let ready = event_loop.add(reader).unwrap();
+        let waiting = event_loop.add(idle).unwrap();
+
+        let mut events = [Event::default(); 4];
+        let amount = event_loop.wait(&mut events, Timeout::Immediate).unwrap();
+        assert_eq!(events[..amount].iter().map(Token::of).collect::<Vec<_>>(), vec![ready]);
+
+        // The loop isn't borrowed by the events, so files can come and go while handling them.
+        let reader = event_loop.remove(ready).unwrap();
+        assert!(event_loop.get(ready).is_none());
+        let again = event_loop.add(reader).unwrap();
+        assert_ne!(again, ready);
+        assert!(event_loop.get(ready).is_none());
+
+        drop(idle_writer);
+        let amount = event_loop.wait(&mut events, Timeout::Immediate).unwrap();
+        assert_eq!(amount, 2);
+        assert_eq!(event_loop[waiting].as_raw_fd(), event_loop.get(waiting).unwrap().as_raw_fd());
+        assert!(event_loop.remove(Token { index: 7, generation: 0 }).is_err());
+    }
+
+    #[test]
+    fn stale_events_of_owned_files() {
+        let (first, _w1) = testing::readable_pipe(b"x").unwrap();
+        let (second, _w2) = testing::readable_pipe(b"x").unwrap();
+        let (third, _w3) = testing::readable_pipe(b"x").unwrap();
+
+        let mut event_loop = OwnedEventLoop::new().unwrap();
+        let first = event_loop.add(first).unwrap();
+        let second = event_loop.add(second).unwrap();
+
+        let mut events = [Event::default(); 4];
+        let amount = event_loop.wait(&mut events, Timeout::Immediate).unwrap();
+        assert_eq!(amount, 2);
+
+        // Handling the first event replaces the second file, whose event is still pending.
+        let mut handled = Vec::new();
+        let mut replacement = Some(third);
+        for event in &events[..amount] {
+            let token = Token::of(event);
+            if event_loop.get(token).is_none() {
+                continue;
+            }
+            handled.push(token);
+
+            if let Some(third) = replacement.take() {
+                let other = if token == first { second } else { first };
+                event_loop.remove(other).unwrap();
+                let third = event_loop.add(third).unwrap();
+                assert_eq!(third.index, other.index);
+                assert_ne!(third, other);
+            }
+        }
+
+        assert_eq!(handled.len(), 1);
+    }
+
+    #[test]
+    fn run_until_stopped() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let calls = std::cell::Cell::new(0);
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        let handle = epoll.handle().unwrap();
+        let counted = &calls;
+        epoll.add_handler(&reader, move |_| {
+            counted.set(counted.get() + 1);
+            if counted.get() == 3 {
+                handle.stop();
+            }
+        }).unwrap();
+        epoll.run().unwrap();
+        assert_eq!(calls.get(), 3);
+
+        // Stopping from another thread wakes the blocked loop.
+        epoll.remove(&reader).unwrap();
+        let handle = epoll.handle().unwrap();
+        let stopping = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.stop();
+        });
+        epoll.run().unwrap();
+        stopping.join().unwrap();
+    }
+
+    #[test]
+    fn retain() {
+        let first = Fd(unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) });
+        let second = Fd(unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) });
+        assert!(first.0 >= 0 && second.0 >= 0);
+
+        let mut epoll = EventLoop::new().unwrap();
+        epoll.add(&first).unwrap();
+        epoll.add(&second).unwrap();
+
+        epoll.retain(|file| file.as_raw_fd() == second.0).unwrap();
+
+        assert_eq!(epoll.files.len(), 1);
+        assert_eq!(epoll.find_file_index(second.0), Some(1));
+        assert_eq!(epoll.epoll.data_for(first.0), None);
+        assert!(epoll.epoll.data_for(second.0).is_some());
+    }
+
+    #[test]
+    fn timers() {
+        let fired = std::cell::Cell::new(0);
+        let ticks = std::cell::Cell::new(0);
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        let cancelled = epoll.after(Duration::from_millis(1), || panic!("cancelled timer fired"));
+        assert!(epoll.cancel(cancelled));
+        assert!(!epoll.cancel(cancelled));
+
+        let start = Instant::now();
+        epoll.after(Duration::from_millis(20), || fired.set(fired.get() + 1));
+        let ticking = epoll.every(Duration::from_millis(5), || ticks.set(ticks.get() + 1)).unwrap();
+        while fired.get() == 0 {
+            epoll.dispatch(Timeout::Indefinite).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(ticks.get() >= 2);
+
+        assert!(epoll.cancel(ticking));
+        assert_eq!(epoll.dispatch(Timeout::Milliseconds(30)).unwrap(), 0);
+        assert_eq!(fired.get(), 1);
+    }
+
+    #[test]
+    fn timers_that_never_fire() {
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        let never = epoll.after(Duration::MAX, || panic!("a timer of Duration::MAX fired"));
+        let ticking = epoll.every(Duration::MAX, || panic!("a timer of Duration::MAX ticked")).unwrap();
+        assert_eq!(epoll.every(Duration::ZERO, || {}).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 0);
+        assert!(epoll.cancel(never));
+        assert!(epoll.cancel(ticking));
+    }
+
+    #[test]
+    fn periodic_health_checks() {
+        use health::Problem;
+
+        // Taken from high up, so other tests don't reuse the descriptor once it is closed.
+        let (reader, _writer) = testing::readable_pipe(b"").unwrap();
+        let doomed = Fd(unsafe { libc::fcntl(reader.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 1100) });
+        let (healthy, _healthy_writer) = testing::readable_pipe(b"").unwrap();
+        let findings = std::cell::RefCell::new(Vec::new());
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add(&doomed).unwrap();
+        epoll.add(&healthy).unwrap();
+        epoll.check_health(Duration::from_millis(1), |finding| findings.borrow_mut().push(finding)).unwrap();
+        assert!(findings.borrow().is_empty());
+
+        assert_eq!(unsafe { libc::close(doomed.0) }, 0);
+        let start = Instant::now();
+        while findings.borrow().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            epoll.dispatch(Timeout::Milliseconds(10)).unwrap();
+        }
+
+        let findings = findings.borrow();
+        assert_eq!(findings.iter().map(|f| (f.fd, f.problem)).collect::<Vec<_>>(), vec![(doomed.0, Problem::Closed)]);
+        assert_eq!(epoll.files.len(), 1);
+        assert!(epoll.find_file_index(healthy.as_raw_fd()).is_some());
+    }
+
+    #[test]
+    fn deferred_and_idle() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let calls = std::cell::RefCell::new(Vec::new());
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add_handler(&reader, |_| calls.borrow_mut().push("ready")).unwrap();
+        epoll.on_idle(|| calls.borrow_mut().push("idle"));
+        epoll.defer(|| calls.borrow_mut().push("deferred"));
+
+        assert_eq!(epoll.dispatch(Timeout::Milliseconds(10)).unwrap(), 2);
+        assert_eq!(*calls.borrow(), ["deferred", "ready"]);
+
+        // Only a wait that would block makes the loop idle.
+        epoll.remove(&reader).unwrap();
+        calls.borrow_mut().clear();
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 0);
+        assert_eq!(epoll.dispatch(Timeout::Milliseconds(1)).unwrap(), 1);
+        assert_eq!(*calls.borrow(), ["idle"]);
+    }
+
+    #[test]
+    fn signals() {
+        let received = std::cell::Cell::new(None);
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.on_signal(&[libc::SIGUSR2], |info| received.set(Some(info.signal))).unwrap();
+        assert!(SigSet::thread_mask().unwrap().contains(libc::SIGUSR2));
+        assert!(epoll.on_signal(&[-1], |_| {}).is_err());
+
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR2) };
+        assert_eq!(epoll.dispatch(Timeout::Milliseconds(1000)).unwrap(), 1);
+        assert_eq!(received.get(), Some(libc::SIGUSR2));
+
+        let mut usr2 = SigSet::empty();
+        usr2.add(libc::SIGUSR2).unwrap();
+        usr2.unblock().unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::zombie_processes)] // Reaped by the loop.
+    fn child_exit() {
+        let child = std::process::Command::new("sh").args(["-c", "exit 7"]).spawn().unwrap();
+        let status = std::cell::Cell::new(None);
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.on_child_exit(child.id(), |exited| status.set(exited.code())).unwrap();
+        assert_eq!(epoll.dispatch(Timeout::Milliseconds(5000)).unwrap(), 1);
+        assert_eq!(status.get(), Some(7));
+
+        // The pidfd is gone once the child was reaped.
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 0);
+        assert_eq!(epoll.files.len(), 0);
+    }
+
+    #[test]
+    fn counters() {
+        let counter = Arc::new(Counter::new().unwrap());
+        let semaphore = Counter::semaphore().unwrap();
+        let counts = std::cell::RefCell::new(Vec::new());
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.on_counter(&counter, |count| counts.borrow_mut().push(count)).unwrap();
+        epoll.on_counter(&semaphore, |count| counts.borrow_mut().push(count * 100)).unwrap();
+
+        let producer = counter.clone();
+        std::thread::spawn(move || { producer.increment(2).unwrap(); producer.increment(3).unwrap(); }).join().unwrap();
+        semaphore.increment(4).unwrap();
+
+        assert_eq!(epoll.dispatch(Timeout::Milliseconds(5000)).unwrap(), 2);
+        counts.borrow_mut().sort();
+        assert_eq!(*counts.borrow(), [5, 400]);
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 0);
+    }
+
+    #[test]
+    fn channel() {
+        let received = std::cell::RefCell::new(Vec::new());
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        let sender = epoll.channel(2, |message| received.borrow_mut().push(message)).unwrap();
+        let stats = sender.clone();
+
+        // The sender waits for the loop whenever the channel fills up.
+        let sending = std::thread::spawn(move || for message in 0..20 { sender.send(message).unwrap() });
+        while received.borrow().len() < 20 {
+            epoll.dispatch(Timeout::Milliseconds(5000)).unwrap();
+        }
+        sending.join().unwrap();
+
+        assert_eq!(*received.borrow(), (0..20).collect::<Vec<_>>());
+        assert_eq!(stats.stats().depth, 0);
+    }
+
+    #[test]
+    fn removal_while_iterating() {
+        let (first, _first_writer) = testing::readable_pipe(b"x").unwrap();
+        let (second, _second_writer) = testing::readable_pipe(b"x").unwrap();
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add(&first).unwrap();
+        epoll.add(&second).unwrap();
+
+        let mut ready = epoll.wait(Timeout::Immediate).unwrap();
+        let mut seen = 0;
+        while let Some((file, _)) = ready.next() {
+            if file.as_raw_fd() == first.as_raw_fd() {
+                ready.mark_remove(file);
+            }
+            seen += 1;
+        }
+        assert_eq!(seen, 2);
+
+        let ready: Vec<_> = epoll.wait(Timeout::Immediate).unwrap().map(|(file, _)| file.as_raw_fd()).collect();
+        assert_eq!(ready, vec![second.as_raw_fd()]);
+        assert_eq!(epoll.files.len(), 1);
+    }
+
+    #[test]
+    fn builder() {
+        let (first, _first_writer) = testing::readable_pipe(b"x").unwrap();
+        let (second, _second_writer) = testing::readable_pipe(b"x").unwrap();
+
+        let mut epoll = EventLoopBuilder::new().capacity(1).trigger(Trigger::Edge).cloexec(true).build::<dyn AsRawFd>().unwrap();
+        epoll.add(&first).unwrap();
+        epoll.add(&second).unwrap();
+        assert_eq!(epoll.events.len(), 1);
+
+        // One file per wait, and each only once since they are edge-triggered.
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 1);
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 1);
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 0);
+
+        let flags = unsafe { libc::fcntl(epoll.epoll.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+
+    #[test]
+    fn per_file_state() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let (idle, _idle_writer) = testing::readable_pipe(b"").unwrap();
+
+        let mut epoll = EventLoopBuilder::new().build_with_state::<pipe::PipeReader, u32>().unwrap();
+        epoll.add_with_state(&reader, 0).unwrap();
+        epoll.add_with_state(&idle, 100).unwrap();
+
+        for _ in 0..2 {
+            let handled = epoll.dispatch_with(Timeout::Immediate, |file, event, count| {
+                assert_eq!(file.as_raw_fd(), reader.as_raw_fd());
+                assert!(event.is_readable());
+                *count += 1;
+            }).unwrap();
+            assert_eq!(handled, 1);
+        }
+
+        assert_eq!(epoll.state(&reader), Some(&2));
+        *epoll.state_mut(&idle).unwrap() += 1;
+        assert_eq!(epoll.state(&idle), Some(&101));
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 0);
+    }
+
+    #[test]
+    fn error_and_hangup_hooks() {
+        let (reader, writer) = pipe::Pipe::new().unwrap().split();
+        let (broken, broken_writer) = pipe::Pipe::new().unwrap().split();
+        drop(writer);
+        drop(broken);
+        let hooks = std::cell::RefCell::new(Vec::new());
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add_handler(&reader, |_| panic!("removed by its hook")).unwrap();
+        epoll.add_with_interest(&broken_writer, EPOLLOUT).unwrap();
+        epoll.on_hangup(|file, _| { hooks.borrow_mut().push(("hangup", file.as_raw_fd())); true });
+        epoll.on_error(|file, event| { hooks.borrow_mut().push(("error", file.as_raw_fd())); !event.is_writable() });
+
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 2);
+        hooks.borrow_mut().sort();
+        assert_eq!(*hooks.borrow(), [("error", broken_writer.as_raw_fd()), ("hangup", reader.as_raw_fd())]);
+
+        // The hung up file was removed, the broken one kept.
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 1);
+        assert_eq!(epoll.files.len(), 1);
+    }
+
+    #[test]
+    fn modify() {
+        let (reader, writer) = pipe::Pipe::new().unwrap().split();
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add(&writer).unwrap();
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 0);
+
+        epoll.modify(&writer, EPOLLOUT).unwrap();
+        let ready: Vec<_> = epoll.wait(Timeout::Immediate).unwrap().map(|(f, e)| (f.as_raw_fd(), e.readiness())).collect();
+        assert_eq!(ready, vec![(writer.as_raw_fd(), EPOLLOUT)]);
+
+        assert_eq!(epoll.modify(&reader, EPOLLIN).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn run_once_and_until() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let ticks = std::cell::Cell::new(0);
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add_handler(&reader, |_| {}).unwrap();
+        assert_eq!(epoll.run_once(Timeout::Immediate).unwrap(), WakeReason::Events(1));
+
+        epoll.remove(&reader).unwrap();
+        epoll.every(Duration::from_millis(1), || ticks.set(ticks.get() + 1)).unwrap();
+        epoll.run_until(|| ticks.get() == 3).unwrap();
+        assert_eq!(ticks.get(), 3);
+    }
+
+    #[test]
+    fn nested_loops() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let calls = std::cell::Cell::new(0);
+
+        let mut child = EventLoop::<dyn AsRawFd>::new().unwrap();
+        child.add_handler(&reader, |_| calls.set(calls.get() + 1)).unwrap();
+        assert_eq!(child.poll_nested().unwrap(), 1);
+
+        let mut parent = EventLoop::<dyn AsRawFd>::new().unwrap();
+        parent.add_nested(&mut child).unwrap();
+        assert_eq!(parent.dispatch(Timeout::Immediate).unwrap(), 1);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        epoll.add(&reader).unwrap();
+        epoll.pause(&reader).unwrap();
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 0);
+
+        // Changes while paused wait for the file to be resumed.
+        epoll.modify(&reader, EPOLLIN | EPOLLRDHUP).unwrap();
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 0);
+
+        epoll.resume(&reader).unwrap();
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 1);
+        assert_eq!(epoll.epoll.registrations().map(|(_, r)| r.events).collect::<Vec<_>>(), vec![EPOLLIN | EPOLLRDHUP]);
+    }
+
+    #[test]
+    fn changes_from_other_threads() {
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        let handle = epoll.handle().unwrap();
+        let (sender, received) = std::sync::mpsc::channel();
+
+        let adding = std::thread::spawn(move || {
+            let (reader, writer) = testing::readable_pipe(b"x").unwrap();
+            let fd = reader.as_raw_fd();
+            let remover = handle.clone();
+            handle.add_raw(fd, EPOLLIN, move |event| {
+                let _ = (&reader, &writer);
+                sender.send(event.is_readable()).unwrap();
+                remover.remove_raw(fd);
+                remover.stop();
+            });
+        });
+        epoll.run().unwrap();
+        adding.join().unwrap();
+        assert!(received.recv().unwrap());
+
+        // The removal was queued by the handler, and applied before the next wait.
+        assert_eq!(epoll.dispatch(Timeout::Immediate).unwrap(), 0);
+        assert_eq!(epoll.files.len(), 0);
+        assert!(received.recv().is_err());
+    }
+
+    #[test]
+    fn ordered_teardown() {
+        let (reader, _writer) = testing::readable_pipe(b"").unwrap();
+        // Taken from high up, so other tests don't reuse the descriptors once they are closed.
+        let owned = unsafe { OwnedFd::from_raw_fd(libc::fcntl(reader.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 1200)) };
+        let owned_fd = owned.as_raw_fd();
+        let closed = Fd(unsafe { libc::fcntl(reader.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 1200) });
+        let failures = std::cell::RefCell::new(Vec::new());
+        let hooked = Cell::new(false);
+
+        {
+            let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+            let epfd = epoll.as_raw_fd();
+            epoll.add(&reader).unwrap();
+            epoll.add(&closed).unwrap();
+            epoll.add_raw(owned_fd, EPOLLIN, move |_| { let _ = &owned; }).unwrap();
+
+            let (reader, hooked) = (&reader, &hooked);
+            epoll.on_drop(move || {
+                // The files are deregistered, but the epoll and the handlers' descriptors are still open.
+                let rc = unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_DEL, reader.as_raw_fd(), ptr::null_mut()) };
+                assert_eq!((rc, Error::last_os_error().raw_os_error()), (-1, Some(libc::ENOENT)));
+                assert!(unsafe { libc::fcntl(owned_fd, libc::F_GETFD) } >= 0);
+                hooked.set(true);
+            });
+            epoll.on_drop_error(|fd, error| failures.borrow_mut().push((fd, error.raw_os_error())));
+
+            assert_eq!(unsafe { libc::close(closed.0) }, 0);
+        }
+
+        assert!(hooked.get());
+        assert_eq!(*failures.borrow(), [(closed.0, Some(libc::EBADF))]);
+        assert_eq!(unsafe { libc::fcntl(owned_fd, libc::F_GETFD) }, -1);
+    }
+
+    #[test]
+    fn owned_files_are_closed_on_drop() {
+        let (reader, mut writer) = ::pipe::Pipe::new().unwrap().split();
+
+        let mut epoll = OwnedEventLoop::new().unwrap();
+        epoll.add(reader).unwrap();
+        drop(epoll);
+
+        // The only reader is gone.
+        let written = ::std::io::Write::write(&mut writer, b"x");
+        assert_eq!(written.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn wake_reasons() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        assert_eq!(epoll.run_once(Timeout::Immediate).unwrap(), WakeReason::TimedOut);
+
+        epoll.add_handler(&reader, |_| {}).unwrap();
+        assert_eq!(epoll.run_once(Timeout::Immediate).unwrap(), WakeReason::Events(1));
+        epoll.remove(&reader).unwrap();
+
+        epoll.after(Duration::from_millis(1), || {});
+        epoll.after(Duration::from_millis(1), || {});
+        assert_eq!(epoll.run_once(Timeout::Milliseconds(5000)).unwrap(), WakeReason::TimerFired(2));
+
+        let handle = epoll.handle().unwrap();
+        let waking = std::thread::spawn(move || handle.wake());
+        assert_eq!(epoll.run_once(Timeout::Milliseconds(5000)).unwrap(), WakeReason::Waker);
+        waking.join().unwrap();
+
+        // Keep signalling the loop's thread until its wait was interrupted.
+        extern "C" fn ignore(_: libc::c_int) {}
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = ignore as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGURG, &action, ptr::null_mut()), 0);
+        }
+        let thread = unsafe { libc::pthread_self() } as usize;
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let interrupting = {
+            let interrupted = interrupted.clone();
+            std::thread::spawn(move || while !interrupted.load(Ordering::SeqCst) {
+                unsafe { libc::pthread_kill(thread as libc::pthread_t, libc::SIGURG) };
+                std::thread::sleep(Duration::from_millis(10));
+            })
+        };
+        assert_eq!(epoll.run_once(Timeout::Milliseconds(5000)).unwrap(), WakeReason::Interrupted);
+        interrupted.store(true, Ordering::SeqCst);
+        interrupting.join().unwrap();
+    }
+
+    #[test]
+    fn context() {
+        let (first, _first_writer) = testing::readable_pipe(b"x").unwrap();
+        let (second, _second_writer) = testing::readable_pipe(b"x").unwrap();
+        let seen = std::cell::RefCell::new(Vec::new());
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        let ctx = epoll.ctx();
+        assert_eq!(ctx.tick(), 0);
+
+        for file in [&first, &second] {
+            let ctx = epoll.ctx();
+            let seen = &seen;
+            epoll.add_handler(file, move |_| seen.borrow_mut().push((ctx.tick(), ctx.now()))).unwrap();
+        }
+
+        epoll.dispatch(Timeout::Immediate).unwrap();
+        epoll.dispatch(Timeout::Immediate).unwrap();
+
+        let seen = seen.borrow();
+        assert_eq!(seen.iter().map(|&(tick, _)| tick).collect::<Vec<_>>(), [1, 1, 2, 2]);
+        assert_eq!(seen[0].1, seen[1].1);
+        assert!(seen[2].1 >= seen[1].1);
+        assert_eq!((ctx.tick(), ctx.now()), seen[3]);
+    }
+
+    #[test]
+    fn changes_from_handlers_apply_before_the_next_wait() {
+        let (trigger, _trigger_writer) = testing::readable_pipe(b"x").unwrap();
+        let (added, added_writer) = testing::readable_pipe(b"x").unwrap();
+        let added_fd = added.as_raw_fd();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let mut epoll = EventLoop::<dyn AsRawFd>::new().unwrap();
+        let handle = epoll.handle().unwrap();
+        let mut added = Some(added);
+        {
+            let calls = calls.clone();
+            epoll.add_handler(&trigger, move |_| {
+                if let Some(added) = added.take() {
+                    let calls = calls.clone();
+                    handle.add_raw(added_fd, EPOLLIN, move |_| { let _ = &added; calls.lock().unwrap().push("added"); });
+                }
+            }).unwrap();
+        }
+
+        epoll.run_once(Timeout::Immediate).unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        // The next cycle registers the descriptor, then waits on it.
+        assert_eq!(epoll.run_once(Timeout::Immediate).unwrap(), WakeReason::Events(2));
+        assert_eq!(*calls.lock().unwrap(), ["added"]);
+        drop(added_writer);
+    }
+
+    #[test]
+    fn coalesced_timers() {
+        let fired = Cell::new(0);
+        let start = Instant::now();
+
+        let mut epoll = EventLoopBuilder::new().timer_granularity(Duration::from_millis(50)).build::<dyn AsRawFd>().unwrap();
+        for delay in [1, 3, 5, 7, 9] {
+            epoll.after(Duration::from_millis(delay), || fired.set(fired.get() + 1));
+        }
+
+        assert_eq!(epoll.run_once(Timeout::Milliseconds(5000)).unwrap(), WakeReason::TimerFired(5));
+        assert!(start.elapsed() >= Duration::from_millis(9));
+        assert_eq!(epoll.timer_stats(), TimerStats { fired: 5, wakeups: 1 });
+    }
+
+    #[test]
+    fn slab_timers() {
+        let fired = std::cell::RefCell::new(Vec::new());
+
+        let mut epoll = EventLoopBuilder::new().timer_slab(2).build::<dyn AsRawFd>().unwrap();
+        assert!(EventLoop::<dyn AsRawFd>::new().unwrap().timer_slab().is_none());
+        let slab = epoll.timer_slab().unwrap();
+        assert_eq!(slab.capacity(), 2);
+
+        let (rearm, fired_ref) = (slab.clone(), &fired);
+        epoll.on_slab_timer(move |token| {
+            fired_ref.borrow_mut().push(token);
+            if token == 1 {
+                rearm.after(Duration::ZERO, 3).unwrap();
+            }
+        });
+
+        let cancelled = slab.after(Duration::from_secs(60), 2).unwrap();
+        slab.after(Duration::from_millis(10), 1).unwrap();
+        assert_eq!(slab.after(Duration::ZERO, 0).unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert!(slab.cancel(cancelled));
+        assert!(!slab.cancel(cancelled));
+
+        // A timer armed by the callback waits for the next cycle.
+        assert_eq!(epoll.run_once(Timeout::Milliseconds(5000)).unwrap(), WakeReason::TimerFired(1));
+        assert_eq!(*fired.borrow(), [1]);
+        assert_eq!(epoll.run_once(Timeout::Milliseconds(5000)).unwrap(), WakeReason::TimerFired(1));
+        assert_eq!(*fired.borrow(), [1, 3]);
+        assert!(slab.is_empty());
+    }
+}
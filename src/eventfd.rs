@@ -0,0 +1,143 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Non-blocking eventfds.
+//!
+//! An eventfd holds a 64-bit count: writes add to it, and it is readable
+//! (`EPOLLIN`) while the count is non-zero. `counter::Counter` builds on it
+//! for the common case of waking a loop from other threads.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let slots = EventFd::semaphore(4)?;
+//! epoll.add(&slots, EPOLLIN, SLOTS)?;
+//!
+//! // Once `SLOTS` is reported, each read takes a single slot:
+//! while slots.read().is_ok() {
+//!     start_job(&slots);
+//! }
+//!
+//! // When a job is done:
+//! slots.write(1)?;
+//! ```
+
+use super::*;
+use std::mem;
+
+/// An eventfd, created non-blocking and close-on-exec.
+#[derive(Debug)]
+pub struct EventFd {
+    fd: OwnedFd,
+}
+
+impl EventFd {
+    /// Creates an eventfd whose reads take the whole count.
+    pub fn new(initial: u32) -> io::Result<EventFd> {
+        EventFd::with_flags(initial, 0)
+    }
+
+    /// Creates an eventfd in semaphore mode, whose reads take one from the count.
+    pub fn semaphore(initial: u32) -> io::Result<EventFd> {
+        EventFd::with_flags(initial, libc::EFD_SEMAPHORE)
+    }
+
+    fn with_flags(initial: u32, flags: libc::c_int) -> io::Result<EventFd> {
+        let fd = unsafe { libc::eventfd(initial, flags | libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+
+        if fd < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(EventFd { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+        }
+    }
+
+    /// Takes the count, or one from it in semaphore mode.
+    ///
+    /// Fails with `WouldBlock` if the count is zero.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut value: u64 = 0;
+        let rc = unsafe {
+            libc::read(self.fd.as_raw_fd(), &mut value as *mut u64 as *mut libc::c_void, mem::size_of::<u64>())
+        };
+
+        if rc < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(value)
+        }
+    }
+
+    /// Adds `amount` to the count.
+    ///
+    /// Fails with `WouldBlock` if the count would exceed `u64::MAX - 1`,
+    /// and with `EINVAL` if `amount` is `u64::MAX`.
+    pub fn write(&self, amount: u64) -> io::Result<()> {
+        let rc = unsafe {
+            libc::write(self.fd.as_raw_fd(), &amount as *const u64 as *const libc::c_void, mem::size_of::<u64>())
+        };
+
+        if rc < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for EventFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_mode() {
+        let eventfd = EventFd::new(2).unwrap();
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&eventfd, EPOLLIN, 0).unwrap();
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_some());
+
+        eventfd.write(3).unwrap();
+        assert_eq!(eventfd.read().unwrap(), 5);
+        assert_eq!(eventfd.read().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+
+        eventfd.write(u64::MAX - 1).unwrap();
+        assert_eq!(eventfd.write(1).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(eventfd.write(u64::MAX).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn semaphore_mode() {
+        let eventfd = EventFd::semaphore(2).unwrap();
+
+        assert_eq!(eventfd.read().unwrap(), 1);
+        assert_eq!(eventfd.read().unwrap(), 1);
+        assert_eq!(eventfd.read().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
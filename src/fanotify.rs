@@ -0,0 +1,377 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Monitoring file access across whole mounts and filesystems, through fanotify.
+//!
+//! Unlike inotify, each event carries an open descriptor of the file that was
+//! accessed, and the process that accessed it. Listeners of the `Content`
+//! and `PreContent` classes may also allow or deny access, through `respond`.
+//!
+//! Requires `CAP_SYS_ADMIN`.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let fanotify = Fanotify::new(Class::Notification, InitFlags::empty())?;
+//! fanotify.add_mark("/srv", FAN_MARK_MOUNT, FAN_CLOSE_WRITE)?;
+//! epoll.add(&fanotify, EPOLLIN, WRITES)?;
+//!
+//! // Once `WRITES` is reported:
+//! let mut buffer = [0; 4096];
+//! for event in fanotify.read_events(&mut buffer)? {
+//!     if let Some(file) = event.fd {
+//!         scan(File::from(file), event.pid);
+//!     }
+//! }
+//! ```
+
+use super::*;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+bitflags! {
+    /// What a mark reports, and what an event is about; see fanotify(7).
+    pub flags FanotifyMask: u64 {
+        /// The file was read.
+        const FAN_ACCESS = libc::FAN_ACCESS,
+        /// The file was written to.
+        const FAN_MODIFY = libc::FAN_MODIFY,
+        /// A file opened for writing was closed.
+        const FAN_CLOSE_WRITE = libc::FAN_CLOSE_WRITE,
+        /// A file not opened for writing was closed.
+        const FAN_CLOSE_NOWRITE = libc::FAN_CLOSE_NOWRITE,
+        /// The file was opened.
+        const FAN_OPEN = libc::FAN_OPEN,
+        /// The file was opened to be executed.
+        const FAN_OPEN_EXEC = libc::FAN_OPEN_EXEC,
+
+        /// Permission: the file is about to be opened.
+        const FAN_OPEN_PERM = libc::FAN_OPEN_PERM,
+        /// Permission: the file is about to be read.
+        const FAN_ACCESS_PERM = libc::FAN_ACCESS_PERM,
+        /// Permission: the file is about to be opened to be executed.
+        const FAN_OPEN_EXEC_PERM = libc::FAN_OPEN_EXEC_PERM,
+
+        /// Both kinds of closing.
+        const FAN_CLOSE = libc::FAN_CLOSE,
+
+        /// Also report events about directories.
+        const FAN_ONDIR = libc::FAN_ONDIR,
+        /// Also report events about the direct children of a marked directory.
+        const FAN_EVENT_ON_CHILD = libc::FAN_EVENT_ON_CHILD,
+
+        /// Reported: events were dropped because the queue overflowed.
+        const FAN_Q_OVERFLOW = libc::FAN_Q_OVERFLOW,
+    }
+}
+
+bitflags! {
+    /// Options of a new `Fanotify`.
+    pub flags InitFlags: u32 {
+        /// Don't limit the amount of queued events.
+        const FAN_UNLIMITED_QUEUE = libc::FAN_UNLIMITED_QUEUE,
+        /// Don't limit the amount of marks.
+        const FAN_UNLIMITED_MARKS = libc::FAN_UNLIMITED_MARKS,
+        /// Allow `respond` to ask for permission decisions to be audited.
+        const FAN_ENABLE_AUDIT = libc::FAN_ENABLE_AUDIT,
+        /// Report thread ids instead of process ids.
+        const FAN_REPORT_TID = libc::FAN_REPORT_TID,
+    }
+}
+
+bitflags! {
+    /// What a mark applies to, and how its path is resolved.
+    ///
+    /// Without `FAN_MARK_MOUNT` or `FAN_MARK_FILESYSTEM`, the mark applies to
+    /// the file at the path itself.
+    pub flags MarkFlags: u32 {
+        /// Mark the mount that contains the path.
+        const FAN_MARK_MOUNT = libc::FAN_MARK_MOUNT,
+        /// Mark the filesystem that contains the path.
+        const FAN_MARK_FILESYSTEM = libc::FAN_MARK_FILESYSTEM,
+        /// Don't follow the path if it is a symbolic link.
+        const FAN_MARK_DONT_FOLLOW = libc::FAN_MARK_DONT_FOLLOW,
+        /// Fail unless the path is a directory.
+        const FAN_MARK_ONLYDIR = libc::FAN_MARK_ONLYDIR,
+        /// Change the mask of events to ignore, rather than the mask to report.
+        const FAN_MARK_IGNORED_MASK = libc::FAN_MARK_IGNORED_MASK,
+        /// Keep ignoring events after the file is modified.
+        const FAN_MARK_IGNORED_SURV_MODIFY = libc::FAN_MARK_IGNORED_SURV_MODIFY,
+    }
+}
+
+/// Which events a `Fanotify` can report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// Only notifications, after files were accessed.
+    Notification,
+    /// Also permission events, once the file's content is available.
+    Content,
+    /// Also permission events, before the file's content is available; e.g.
+    /// for a listener that fetches it.
+    PreContent,
+}
+
+/// A fanotify group, created non-blocking and close-on-exec.
+///
+/// It is readable while events are queued.
+#[derive(Debug)]
+pub struct Fanotify {
+    fd: OwnedFd,
+}
+
+/// An access reported by a `Fanotify`.
+#[derive(Debug)]
+pub struct FanotifyEvent {
+    /// What happened.
+    pub mask: FanotifyMask,
+    /// A read-only descriptor of the file that was accessed; `None` for
+    /// `FAN_Q_OVERFLOW`.
+    pub fd: Option<OwnedFd>,
+    /// The process that accessed the file, or its thread with `FAN_REPORT_TID`.
+    pub pid: u32,
+}
+
+impl Fanotify {
+    /// Creates a fanotify group without marks.
+    pub fn new(class: Class, flags: InitFlags) -> io::Result<Fanotify> {
+        let class = match class {
+            Class::Notification => libc::FAN_CLASS_NOTIF,
+            Class::Content => libc::FAN_CLASS_CONTENT,
+            Class::PreContent => libc::FAN_CLASS_PRE_CONTENT,
+        };
+        let event_flags = libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC;
+
+        let fd = unsafe {
+            libc::fanotify_init(class | flags.bits() | libc::FAN_NONBLOCK | libc::FAN_CLOEXEC, event_flags as libc::c_uint)
+        };
+
+        if fd < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(Fanotify { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+        }
+    }
+
+    /// Reports the events of `mask` for the path, or for its mount or
+    /// filesystem, in addition to the events already marked for it.
+    pub fn add_mark<P: AsRef<Path>>(&self, path: P, flags: MarkFlags, mask: FanotifyMask) -> io::Result<()> {
+        self.mark(libc::FAN_MARK_ADD | flags.bits(), mask, Some(path.as_ref()))
+    }
+
+    /// Stops reporting the events of `mask` for the path, or for its mount or filesystem.
+    pub fn remove_mark<P: AsRef<Path>>(&self, path: P, flags: MarkFlags, mask: FanotifyMask) -> io::Result<()> {
+        self.mark(libc::FAN_MARK_REMOVE | flags.bits(), mask, Some(path.as_ref()))
+    }
+
+    /// Removes all the marks of files, or of mounts or filesystems with
+    /// `FAN_MARK_MOUNT` or `FAN_MARK_FILESYSTEM`.
+    pub fn flush_marks(&self, flags: MarkFlags) -> io::Result<()> {
+        self.mark(libc::FAN_MARK_FLUSH | flags.bits(), FanotifyMask::empty(), None)
+    }
+
+    fn mark(&self, flags: libc::c_uint, mask: FanotifyMask, path: Option<&Path>) -> io::Result<()> {
+        let path = match path {
+            Some(path) => Some(CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?),
+            None => None,
+        };
+        let path_ptr = path.as_ref().map_or(ptr::null(), |path| path.as_ptr());
+
+        if unsafe { libc::fanotify_mark(self.fd.as_raw_fd(), flags, mask.bits(), libc::AT_FDCWD, path_ptr) } < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Reads the queued events into `buffer`, and returns an iterator over them;
+    /// it is empty if none are queued.
+    ///
+    /// The descriptors of events that aren't iterated over are closed along
+    /// with the iterator. Fails with `EINVAL` if `buffer` is too small for the
+    /// next event, which 4096 bytes always fit.
+    pub fn read_events<'a>(&self, buffer: &'a mut [u8]) -> io::Result<FanotifyEvents<'a>> {
+        let rc = unsafe { libc::read(self.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+
+        let length = if rc < 0 {
+            let error = Error::last_os_error();
+            if error.kind() != io::ErrorKind::WouldBlock {
+                return Err(error);
+            }
+            0
+        }
+        else {
+            rc as usize
+        };
+
+        Ok(FanotifyEvents { buffer: &buffer[..length] })
+    }
+
+    /// Allows or denies the access of a permission event.
+    ///
+    /// The accessing process is blocked until then, or until the event's
+    /// descriptor is closed without a response, which allows the access.
+    pub fn respond(&self, event: &FanotifyEvent, allow: bool) -> io::Result<()> {
+        let fd = match event.fd {
+            Some(ref fd) => fd.as_raw_fd(),
+            None => return Err(Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let response = libc::fanotify_response {
+            fd,
+            response: if allow { libc::FAN_ALLOW } else { libc::FAN_DENY },
+        };
+
+        let rc = unsafe {
+            libc::write(self.fd.as_raw_fd(), &response as *const _ as *const libc::c_void, mem::size_of::<libc::fanotify_response>())
+        };
+
+        if rc < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+impl AsRawFd for Fanotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Fanotify {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// The events of a single read; see `Fanotify::read_events`.
+pub struct FanotifyEvents<'a> {
+    /// The events that weren't yet iterated over.
+    buffer: &'a [u8],
+}
+
+impl<'a> Iterator for FanotifyEvents<'a> {
+    type Item = FanotifyEvent;
+
+    fn next(&mut self) -> Option<FanotifyEvent> {
+        if self.buffer.len() < mem::size_of::<libc::fanotify_event_metadata>() {
+            return None;
+        }
+
+        let metadata = unsafe {
+            ptr::read_unaligned(self.buffer.as_ptr() as *const libc::fanotify_event_metadata)
+        };
+
+        // `event_len` also covers any information records after the metadata.
+        let length = (metadata.event_len as usize).clamp(mem::size_of::<libc::fanotify_event_metadata>(), self.buffer.len());
+        self.buffer = &self.buffer[length..];
+
+        Some(FanotifyEvent {
+            mask: FanotifyMask::from_bits_truncate(metadata.mask),
+            fd: if metadata.fd < 0 { None } else { Some(unsafe { OwnedFd::from_raw_fd(metadata.fd) }) },
+            pid: metadata.pid as u32,
+        })
+    }
+}
+
+impl<'a> Drop for FanotifyEvents<'a> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::os::unix::fs::MetadataExt;
+    use std::thread;
+
+    /// Creates a group, or returns `None` without the privileges to.
+    fn fanotify(class: Class) -> Option<Fanotify> {
+        match Fanotify::new(class, InitFlags::empty()) {
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => None,
+            fanotify => Some(fanotify.unwrap()),
+        }
+    }
+
+    #[test]
+    fn reports_accesses() {
+        let fanotify = match fanotify(Class::Notification) {
+            Some(fanotify) => fanotify,
+            None => return,
+        };
+        let path = ::std::env::temp_dir().join(format!("epoll-fanotify-{}", ::std::process::id()));
+        fs::write(&path, b"").unwrap();
+        fanotify.add_mark(&path, MarkFlags::empty(), FAN_OPEN | FAN_CLOSE_NOWRITE).unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&fanotify, EPOLLIN, 0).unwrap();
+        let mut buffer = [0; 4096];
+        assert_eq!(fanotify.read_events(&mut buffer).unwrap().count(), 0);
+
+        drop(File::open(&path).unwrap());
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_some());
+
+        // Both accesses may be merged into a single event.
+        let mut mask = FanotifyMask::empty();
+        for event in fanotify.read_events(&mut buffer).unwrap() {
+            mask |= event.mask;
+            assert_eq!(event.pid, ::std::process::id());
+            let file = File::from(event.fd.unwrap());
+            assert_eq!(file.metadata().unwrap().ino(), fs::metadata(&path).unwrap().ino());
+        }
+        assert_eq!(mask, FAN_OPEN | FAN_CLOSE_NOWRITE);
+
+        fanotify.remove_mark(&path, MarkFlags::empty(), FAN_OPEN | FAN_CLOSE_NOWRITE).unwrap();
+        drop(File::open(&path).unwrap());
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn denies_access() {
+        let fanotify = match fanotify(Class::Content) {
+            Some(fanotify) => fanotify,
+            None => return,
+        };
+        let path = ::std::env::temp_dir().join(format!("epoll-fanotify-perm-{}", ::std::process::id()));
+        fs::write(&path, b"").unwrap();
+        fanotify.add_mark(&path, MarkFlags::empty(), FAN_OPEN_PERM).unwrap();
+
+        let opener = thread::spawn({
+            let path = path.clone();
+            move || File::open(path).map(drop)
+        });
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&fanotify, EPOLLIN, 0).unwrap();
+        assert!(epoll.wait_one(Timeout::Milliseconds(5000)).unwrap().is_some());
+
+        let mut buffer = [0; 4096];
+        let event = fanotify.read_events(&mut buffer).unwrap().next().unwrap();
+        assert_eq!(event.mask, FAN_OPEN_PERM);
+        fanotify.respond(&event, false).unwrap();
+
+        assert_eq!(opener.join().unwrap().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use libc::c_int;
+use std::os::unix::io::RawFd;
 
 bitflags! {
     /// Indicates the types of events an epoll can listen to.
@@ -97,18 +98,55 @@ bitflags! {
         /// Undocumented: Seems to be unused by anyone (including the kernel).
         const EPOLLMSG = 0x400,
     }
+}
+
+bitflags! {
+    /// Flags for creating an epoll, as taken by epoll_create1(2).
+    pub flags CreateFlags: c_int {
+        /// Set the close-on-exec flag on the new file descriptor,
+        /// so it is not leaked to programs started with exec.
+        const EPOLL_CLOEXEC = ::libc::EPOLL_CLOEXEC,
+    }
 }   
 
+impl EventType {
+    /// Converts from the underlying bit representation, keeping every bit.
+    ///
+    /// Unlike `from_bits_truncate`, bits this crate doesn't know about (e.g. flags added
+    /// by newer kernels) are preserved, and survive `|`, `&` and `-`.
+    /// Note that `!` and the `Debug` output only consider the known flags.
+    pub const fn from_bits_retain(bits: u32) -> EventType {
+        EventType { bits }
+    }
+
+    /// Returns the raw value of the mask, including unknown bits.
+    pub fn raw_bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Returns only the bits that don't correspond to any known flag.
+    pub fn unknown_bits(&self) -> u32 {
+        self.bits & !EventType::all().bits
+    }
+}
+
 /// This struct is returned by the Kernel to notify of an EPoll event.
 /// The data field is the same as supplied by the user on registeration.
-/// The events field contains events that occurd in practice.
+/// The events field contains events that occurd in practice, as reported by the kernel;
+/// bits unknown to this crate are kept (see `EventType::raw_bits`).
 ///
 /// This type is marked Copy so that an array could be initialised like so:
 /// ```rust
+/// # use epoll::Event;
 /// let events = [Event::default(); 1312];
 /// ```
+///
+/// Like the kernel's `struct epoll_event`, it is packed only on x86-64; elsewhere `data`
+/// is naturally aligned. Never take references to the fields, which may be unaligned;
+/// read them with `token` and `readiness` instead.
 #[derive(Clone, Copy, Debug)]
-#[repr(C,packed)]
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
 pub struct Event {
     pub events: EventType,
     pub data: u64
@@ -120,7 +158,121 @@ impl Default for Event {
     }
 }
 
-extern {
+impl Event {
+    /// An event carrying arbitrary data.
+    pub fn new(events: EventType, data: u64) -> Event {
+        Event { events, data }
+    }
+
+    /// An event carrying a file descriptor, like `epoll_data.fd`.
+    pub fn with_fd(events: EventType, fd: RawFd) -> Event {
+        Event { events, data: fd as u32 as u64 }
+    }
+
+    /// An event carrying a pointer, like `epoll_data.ptr`.
+    ///
+    /// Keeping the pointee alive while the event may be reported is up to the caller.
+    pub fn with_ptr<T>(events: EventType, ptr: *const T) -> Event {
+        Event { events, data: ptr as usize as u64 }
+    }
+
+    /// The data of the event, as given at registration.
+    pub fn token(&self) -> u64 {
+        // Copying the field out is an unaligned read; borrowing it would not be.
+        self.data
+    }
+
+    /// The events that occurred.
+    pub fn readiness(&self) -> EventType {
+        self.events
+    }
+
+    /// The data as a file descriptor, for events made by `with_fd`
+    /// (or files registered with `fd as u64`).
+    pub fn fd(&self) -> RawFd {
+        self.data as u32 as RawFd
+    }
+
+    /// The data as a pointer, for events made by `with_ptr`.
+    pub fn ptr<T>(&self) -> *mut T {
+        self.data as usize as *mut T
+    }
+
+    /// The file has data to read, or reached end-of-file (`EPOLLIN`).
+    pub fn is_readable(&self) -> bool {
+        let events = self.events;
+        events.contains(EPOLLIN)
+    }
+
+    /// The file can be written to (`EPOLLOUT`).
+    pub fn is_writable(&self) -> bool {
+        let events = self.events;
+        events.contains(EPOLLOUT)
+    }
+
+    /// The file has an error pending, e.g. a failed connect (`EPOLLERR`).
+    ///
+    /// Always reported, whether registered for or not.
+    pub fn is_error(&self) -> bool {
+        let events = self.events;
+        events.contains(EPOLLERR)
+    }
+
+    /// The file hung up, e.g. both halves of a socket were shut down (`EPOLLHUP`).
+    ///
+    /// Always reported, whether registered for or not.
+    pub fn is_hangup(&self) -> bool {
+        let events = self.events;
+        events.contains(EPOLLHUP)
+    }
+
+    /// Nothing more will be read from the file past what is pending (`EPOLLHUP` or `EPOLLRDHUP`).
+    ///
+    /// `EPOLLRDHUP` is only reported if it was part of the registered events.
+    pub fn is_read_closed(&self) -> bool {
+        self.disconnect().is_some()
+    }
+
+    /// The file has exceptional data, e.g. out-of-band TCP data (`EPOLLPRI`).
+    pub fn is_priority(&self) -> bool {
+        let events = self.events;
+        events.contains(EPOLLPRI)
+    }
+
+    /// Tells whether, and how far, the other side of the file has disconnected.
+    ///
+    /// `EPOLLRDHUP` is only reported if it was part of the registered events.
+    pub fn disconnect(&self) -> Option<Disconnect> {
+        let events = self.events;
+
+        if events.contains(EPOLLHUP) {
+            Some(Disconnect::Full)
+        }
+        else if events.contains(EPOLLRDHUP) {
+            Some(Disconnect::WriteStillPossible)
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// The kind of hangup reported by an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The peer shut down its writing half (`EPOLLRDHUP`), e.g. a half-closed TCP connection.
+    ///
+    /// Data sent before the shutdown may still be waiting to be read,
+    /// and the peer may still be reading; a response can be written.
+    WriteStillPossible,
+
+    /// The connection is gone in both directions (`EPOLLHUP`).
+    ///
+    /// Pending data can still be read, but nothing can be written anymore.
+    Full,
+}
+
+extern "C" {
     pub fn epoll_create(size: c_int) -> c_int;
 
     pub fn epoll_create1(flags: c_int) -> c_int;
@@ -134,4 +286,78 @@ extern {
                         events: *mut Event,
                         maxevents: c_int,
                         timeout: c_int) -> c_int;
-}
\ No newline at end of file
+
+    pub fn epoll_pwait(epfd: c_int,
+                        events: *mut Event,
+                        maxevents: c_int,
+                        timeout: c_int,
+                        sigmask: *const ::libc::sigset_t) -> c_int;
+}
+
+/// `epoll_pwait` with a nanosecond-precision timeout (since Linux 5.11).
+///
+/// Called through `syscall`, as older C libraries have no wrapper for it.
+///
+/// # Safety
+/// Same contract as the system call: `events` must have room for `maxevents` events,
+/// and `timeout` and `sigmask` must each be null or valid.
+pub unsafe fn epoll_pwait2(epfd: c_int,
+                           events: *mut Event,
+                           maxevents: c_int,
+                           timeout: *const ::libc::timespec,
+                           sigmask: *const ::libc::sigset_t) -> c_int {
+    ::libc::syscall(::libc::SYS_epoll_pwait2,
+                    epfd,
+                    events,
+                    maxevents,
+                    timeout,
+                    sigmask,
+                    ::std::mem::size_of::<::libc::sigset_t>()) as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn data_views() {
+        assert_eq!(Event::with_fd(EPOLLIN, 7).fd(), 7);
+        assert_eq!(Event::new(EPOLLIN, 7).fd(), 7);
+
+        let value = 42u32;
+        let event = Event::with_ptr(EPOLLIN, &value);
+        assert_eq!(unsafe { *event.ptr::<u32>() }, 42);
+    }
+
+    #[test]
+    fn accessors() {
+        let events = [Event::new(EPOLLIN, 1), Event::new(EPOLLOUT | EPOLLHUP, 2)];
+        let tokens: Vec<_> = events.iter().map(Event::token).collect();
+        assert_eq!(tokens, vec![1, 2]);
+        assert_eq!(events[1].readiness(), EPOLLOUT | EPOLLHUP);
+    }
+
+    #[test]
+    fn predicates() {
+        let event = Event { events: EPOLLIN | EPOLLRDHUP, data: 0 };
+        assert!(event.is_readable() && event.is_read_closed());
+        assert!(!event.is_writable() && !event.is_hangup() && !event.is_error() && !event.is_priority());
+
+        let event = Event { events: EPOLLOUT | EPOLLERR | EPOLLHUP | EPOLLPRI, data: 0 };
+        assert!(event.is_writable() && event.is_error() && event.is_hangup() && event.is_priority());
+        assert!(event.is_read_closed() && !event.is_readable());
+    }
+
+    #[test]
+    fn event_layout_matches_the_kernel() {
+        assert_eq!(mem::size_of::<Event>(), mem::size_of::<::libc::epoll_event>());
+        assert_eq!(mem::align_of::<Event>(), mem::align_of::<::libc::epoll_event>());
+        assert_eq!(mem::offset_of!(Event, data), mem::offset_of!(::libc::epoll_event, u64));
+
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(mem::size_of::<Event>(), 12);
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(mem::size_of::<Event>(), 16);
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detecting descriptors closed or replaced behind an epoll's back.
+//!
+//! When a library closes a registered descriptor without telling the epoll, the
+//! kernel quietly drops the registration, and the number may later be reused for an
+//! unrelated file. A `HealthChecker` remembers the identity (device and inode) of
+//! every registered descriptor, and reports the ones that are gone or changed.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let mut checker = HealthChecker::new();
+//!
+//! // Every now and then:
+//! for finding in checker.repair(&epoll) {
+//!     warn!("fd {} was {:?} behind our back", finding.fd, finding.problem);
+//! }
+//! ```
+//!
+//! An `EventLoop` runs the checks by itself with `EventLoop::check_health`,
+//! dropping the stale files from the loop as well:
+//!
+//! ```no-run
+//! event_loop.check_health(Duration::from_secs(10), |finding| {
+//!     warn!("fd {} was {:?} behind our back", finding.fd, finding.problem);
+//! })?;
+//! ```
+
+use super::*;
+use std::mem;
+
+/// What went wrong with a registered descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The descriptor was closed.
+    Closed,
+    /// The descriptor now refers to a different file than the one registered.
+    Replaced,
+}
+
+/// A registration found to be stale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub fd: RawFd,
+    pub problem: Problem,
+    pub registration: Registration,
+}
+
+/// Tracks the identity of registered descriptors between checks.
+#[derive(Debug, Default)]
+pub struct HealthChecker {
+    identities: HashMap<RawFd, (libc::dev_t, libc::ino_t)>,
+}
+
+impl HealthChecker {
+    pub fn new() -> HealthChecker {
+        HealthChecker::default()
+    }
+
+    /// Checks every registration of `epoll`.
+    ///
+    /// Descriptors registered since the previous check are remembered as they are now,
+    /// so a replacement is detected from the check after the descriptor was first seen.
+    pub fn check(&mut self, epoll: &EPoll) -> Vec<Finding> {
+        let mut findings = Vec::new();
+
+        self.identities.retain(|&fd, _| epoll.contains(fd));
+
+        for (fd, registration) in epoll.registrations() {
+            let problem = match identity(fd) {
+                None => Some(Problem::Closed),
+                Some(current) => match self.identities.insert(fd, current) {
+                    Some(previous) if previous != current => Some(Problem::Replaced),
+                    _ => None,
+                },
+            };
+
+            if let Some(problem) = problem {
+                self.identities.remove(&fd);
+                findings.push(Finding { fd, problem, registration });
+            }
+        }
+
+        findings
+    }
+
+    /// Checks every registration of `epoll`, and drops the stale ones from its table.
+    ///
+    /// A replaced descriptor can then be registered anew.
+    pub fn repair(&mut self, epoll: &EPoll) -> Vec<Finding> {
+        let findings = self.check(epoll);
+
+        for finding in &findings {
+            epoll.forget(finding.fd);
+        }
+
+        findings
+    }
+}
+
+/// The device and inode `fd` refers to, or `None` if it is not open.
+fn identity(fd: RawFd) -> Option<(libc::dev_t, libc::ino_t)> {
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return None;
+    }
+
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return None;
+    }
+
+    Some((stat.st_dev, stat.st_ino))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+    use testing;
+
+    #[test]
+    fn closed_and_replaced() {
+        // Closed descriptors are taken from high up, so other tests don't reuse them meanwhile.
+        let (reader, _w1) = testing::readable_pipe(b"").unwrap();
+        let closed = unsafe {
+            File::from_raw_fd(libc::fcntl(reader.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 1000))
+        };
+        let (replaced, _w2) = testing::readable_pipe(b"").unwrap();
+        let (healthy, _w3) = testing::readable_pipe(b"").unwrap();
+        let (other, _w4) = testing::readable_pipe(b"").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&closed, EPOLLIN, 1).unwrap();
+        epoll.add(&replaced, EPOLLIN, 2).unwrap();
+        epoll.add(&healthy, EPOLLIN, 3).unwrap();
+
+        let mut checker = HealthChecker::new();
+        assert!(checker.check(&epoll).is_empty());
+
+        let closed_fd = closed.as_raw_fd();
+        drop(closed);
+        assert!(unsafe { libc::dup2(other.as_raw_fd(), replaced.as_raw_fd()) } >= 0);
+
+        let mut findings = checker.repair(&epoll);
+        findings.sort_by_key(|f| f.registration.data);
+        assert_eq!(findings, vec![
+            Finding { fd: closed_fd, problem: Problem::Closed, registration: Registration { events: EPOLLIN, data: 1 } },
+            Finding { fd: replaced.as_raw_fd(), problem: Problem::Replaced, registration: Registration { events: EPOLLIN, data: 2 } },
+        ]);
+
+        assert_eq!(epoll.data_for(closed_fd), None);
+        assert_eq!(epoll.data_for(healthy.as_raw_fd()), Some(3));
+        assert!(checker.check(&epoll).is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Re-attaching to inherited descriptors.
+//!
+//! A process started by systemd, an exec-based handover or a fork may inherit
+//! descriptors it knows only by number. `classify` probes such a descriptor to
+//! find out what it is, and `register_inherited` registers a whole set of them
+//! with a sensible default interest for their kind, and wraps each in a type
+//! that fits it.
+//!
+//! # Example
+//!
+//! ```no-run
+//! // systemd socket activation passes descriptors starting at 3.
+//! let fds = (3..3 + listen_fds).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }).collect();
+//!
+//! let epoll = EPoll::new()?;
+//! for inherited in inherit::register_inherited(&epoll, fds)? {
+//!     if let Inherited::TcpListener(listener) = inherited {
+//!         listeners.push(listener);
+//!     }
+//! }
+//! ```
+
+use super::*;
+use pipe::{PipeReader, PipeWriter};
+use std::fs;
+use std::mem;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+
+/// What an inherited descriptor turned out to be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FdKind {
+    /// A socket that accepts connections.
+    Listener,
+    /// A connection-oriented socket.
+    Stream,
+    /// A datagram (or other message-oriented) socket.
+    Datagram,
+    /// The reading end of a pipe or FIFO.
+    PipeReader,
+    /// The writing end of a pipe or FIFO.
+    PipeWriter,
+    /// A timerfd.
+    TimerFd,
+    /// A signalfd.
+    SignalFd,
+    /// An eventfd.
+    EventFd,
+    /// A pidfd.
+    PidFd,
+    /// Another epoll.
+    EPoll,
+    /// A regular file or directory, which epoll cannot watch.
+    File,
+    /// Anything else (terminals, devices, ...).
+    Other,
+}
+
+impl FdKind {
+    /// The events a descriptor of this kind is registered with by `register_inherited`,
+    /// or `None` if such descriptors are not registered at all.
+    pub fn default_interest(&self) -> Option<EventType> {
+        match *self {
+            FdKind::Listener => Some(Interest::ACCEPT.events()),
+            FdKind::Stream => Some(EPOLLIN | EPOLLRDHUP),
+            FdKind::PipeWriter => Some(EPOLLOUT),
+            FdKind::Datagram | FdKind::PipeReader | FdKind::TimerFd | FdKind::SignalFd |
+            FdKind::EventFd | FdKind::PidFd | FdKind::EPoll => Some(EPOLLIN),
+            FdKind::File | FdKind::Other => None,
+        }
+    }
+}
+
+/// An inherited descriptor, wrapped in a type that fits its kind.
+pub enum Inherited {
+    TcpListener(TcpListener),
+    UnixListener(UnixListener),
+    TcpStream(TcpStream),
+    UnixStream(UnixStream),
+    UdpSocket(UdpSocket),
+    UnixDatagram(UnixDatagram),
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    /// Its registration table starts out empty.
+    EPoll(EPoll),
+    /// A regular file or directory.
+    File(fs::File),
+    /// Anything without a fitting type, e.g. sockets of other families.
+    ///
+    /// Timerfds, signalfds, eventfds and pidfds end up here too: this crate's
+    /// types for them expect the non-blocking descriptors they create
+    /// themselves, and an inherited descriptor shares its blocking mode with
+    /// the process it came from.
+    Fd(FdKind, OwnedFd),
+}
+
+impl Inherited {
+    /// Wraps `fd`, whose kind is `kind`.
+    fn wrap(fd: OwnedFd, kind: FdKind) -> io::Result<Inherited> {
+        let socket = match kind {
+            FdKind::Listener | FdKind::Stream | FdKind::Datagram => {
+                Some((socket_family(fd.as_raw_fd())?, socket_option(fd.as_raw_fd(), libc::SO_TYPE)?))
+            },
+            _ => None,
+        };
+        let inet = |family| family == libc::AF_INET || family == libc::AF_INET6;
+
+        Ok(match (kind, socket) {
+            (FdKind::Listener, Some((family, _))) if inet(family) => Inherited::TcpListener(fd.into()),
+            (FdKind::Listener, Some((libc::AF_UNIX, libc::SOCK_STREAM))) => Inherited::UnixListener(fd.into()),
+            (FdKind::Stream, Some((family, libc::SOCK_STREAM))) if inet(family) => Inherited::TcpStream(fd.into()),
+            (FdKind::Stream, Some((libc::AF_UNIX, libc::SOCK_STREAM))) => Inherited::UnixStream(fd.into()),
+            (FdKind::Datagram, Some((family, libc::SOCK_DGRAM))) if inet(family) => Inherited::UdpSocket(fd.into()),
+            (FdKind::Datagram, Some((libc::AF_UNIX, libc::SOCK_DGRAM))) => Inherited::UnixDatagram(fd.into()),
+            (FdKind::PipeReader, _) => Inherited::PipeReader(fd.into()),
+            (FdKind::PipeWriter, _) => Inherited::PipeWriter(fd.into()),
+            (FdKind::EPoll, _) => Inherited::EPoll(EPoll::from_fd(fd)),
+            (FdKind::File, _) => Inherited::File(fd.into()),
+            (kind, _) => Inherited::Fd(kind, fd),
+        })
+    }
+
+    /// What the descriptor was classified as.
+    pub fn kind(&self) -> FdKind {
+        match *self {
+            Inherited::TcpListener(_) | Inherited::UnixListener(_) => FdKind::Listener,
+            Inherited::TcpStream(_) | Inherited::UnixStream(_) => FdKind::Stream,
+            Inherited::UdpSocket(_) | Inherited::UnixDatagram(_) => FdKind::Datagram,
+            Inherited::PipeReader(_) => FdKind::PipeReader,
+            Inherited::PipeWriter(_) => FdKind::PipeWriter,
+            Inherited::EPoll(_) => FdKind::EPoll,
+            Inherited::File(_) => FdKind::File,
+            Inherited::Fd(kind, _) => kind,
+        }
+    }
+}
+
+impl AsFd for Inherited {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match *self {
+            Inherited::TcpListener(ref file) => file.as_fd(),
+            Inherited::UnixListener(ref file) => file.as_fd(),
+            Inherited::TcpStream(ref file) => file.as_fd(),
+            Inherited::UnixStream(ref file) => file.as_fd(),
+            Inherited::UdpSocket(ref file) => file.as_fd(),
+            Inherited::UnixDatagram(ref file) => file.as_fd(),
+            Inherited::PipeReader(ref file) => file.as_fd(),
+            Inherited::PipeWriter(ref file) => file.as_fd(),
+            Inherited::EPoll(ref file) => file.as_fd(),
+            Inherited::File(ref file) => file.as_fd(),
+            Inherited::Fd(_, ref file) => file.as_fd(),
+        }
+    }
+}
+
+impl AsRawFd for Inherited {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+/// Probes `fd` to find out what kind of file it refers to.
+pub fn classify(fd: RawFd) -> io::Result<FdKind> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFSOCK => classify_socket(fd),
+        libc::S_IFIFO => {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            match flags & libc::O_ACCMODE {
+                libc::O_WRONLY => Ok(FdKind::PipeWriter),
+                _ => Ok(FdKind::PipeReader),
+            }
+        },
+        libc::S_IFREG | libc::S_IFDIR => Ok(FdKind::File),
+        _ => classify_anonymous(fd),
+    }
+}
+
+/// Classifies, wraps and registers every descriptor in `fds` onto `epoll`,
+/// using the descriptor itself as the data, and returns them in order.
+///
+/// Descriptors that cannot be watched (see `FdKind::default_interest`) are
+/// returned without being registered. Stops at the first failure, closing the
+/// descriptors that weren't returned.
+pub fn register_inherited(epoll: &EPoll, fds: Vec<OwnedFd>) -> io::Result<Vec<Inherited>> {
+    let mut inherited = Vec::with_capacity(fds.len());
+
+    for fd in fds {
+        let kind = classify(fd.as_raw_fd())?;
+        let wrapped = Inherited::wrap(fd, kind)?;
+
+        if let Some(events) = kind.default_interest() {
+            epoll.add(&wrapped, events, wrapped.as_raw_fd() as u64)?;
+        }
+        inherited.push(wrapped);
+    }
+
+    Ok(inherited)
+}
+
+fn classify_socket(fd: RawFd) -> io::Result<FdKind> {
+    if socket_option(fd, libc::SO_ACCEPTCONN)? != 0 {
+        return Ok(FdKind::Listener);
+    }
+
+    match socket_option(fd, libc::SO_TYPE)? {
+        libc::SOCK_STREAM | libc::SOCK_SEQPACKET => Ok(FdKind::Stream),
+        _ => Ok(FdKind::Datagram),
+    }
+}
+
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    if unsafe { libc::getsockname(fd, &mut address as *mut _ as *mut libc::sockaddr, &mut len) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(address.ss_family as libc::c_int)
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let rc = unsafe {
+        libc::getsockopt(fd,
+                         libc::SOL_SOCKET,
+                         option,
+                         &mut value as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(value)
+    }
+}
+
+/// Anonymous-inode files (timerfd, signalfd, ...) are only told apart by their name.
+pub(crate) fn classify_anonymous(fd: RawFd) -> io::Result<FdKind> {
+    let target = fs::read_link(format!("/proc/self/fd/{}", fd))?;
+    let target = target.to_string_lossy();
+
+    let kind = match &*target {
+        "anon_inode:[timerfd]" => FdKind::TimerFd,
+        "anon_inode:[signalfd]" => FdKind::SignalFd,
+        "anon_inode:[eventfd]" => FdKind::EventFd,
+        "anon_inode:[eventpoll]" => FdKind::EPoll,
+        t if t.starts_with("anon_inode:[pidfd]") || t.starts_with("pidfd:") => FdKind::PidFd,
+        _ => FdKind::Other,
+    };
+
+    Ok(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pipe::Pipe;
+    use std::net::{TcpListener, UdpSocket};
+
+    #[test]
+    fn classification() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (stream, _peer) = unix::duplex().unwrap();
+        let (reader, writer) = Pipe::new().unwrap().split();
+        let epoll = EPoll::new().unwrap();
+        let timerfd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        let file = fs::File::open("/proc/self/status").unwrap();
+
+        assert_eq!(classify(listener.as_raw_fd()).unwrap(), FdKind::Listener);
+        assert_eq!(classify(udp.as_raw_fd()).unwrap(), FdKind::Datagram);
+        assert_eq!(classify(stream.as_raw_fd()).unwrap(), FdKind::Stream);
+        assert_eq!(classify(reader.as_raw_fd()).unwrap(), FdKind::PipeReader);
+        assert_eq!(classify(writer.as_raw_fd()).unwrap(), FdKind::PipeWriter);
+        assert_eq!(classify(epoll.as_raw_fd()).unwrap(), FdKind::EPoll);
+        assert_eq!(classify(timerfd).unwrap(), FdKind::TimerFd);
+        assert_eq!(classify(file.as_raw_fd()).unwrap(), FdKind::File);
+        assert!(classify(-1).is_err());
+
+        unsafe { libc::close(timerfd) };
+    }
+
+    #[test]
+    fn registration() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stream, _peer) = unix::duplex().unwrap();
+        let (reader, _writer) = Pipe::new().unwrap().split();
+        let timerfd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        let file = fs::File::open("/proc/self/status").unwrap();
+        let fds = vec![OwnedFd::from(listener), OwnedFd::from(stream), unsafe { OwnedFd::from_raw_fd(timerfd) },
+                       OwnedFd::from(file), reader.as_fd().try_clone_to_owned().unwrap()];
+
+        let epoll = EPoll::new().unwrap();
+        let inherited = register_inherited(&epoll, fds).unwrap();
+
+        let kinds: Vec<_> = inherited.iter().map(Inherited::kind).collect();
+        assert_eq!(kinds, [FdKind::Listener, FdKind::Stream, FdKind::TimerFd, FdKind::File, FdKind::PipeReader]);
+        match inherited[0] {
+            Inherited::TcpListener(ref listener) => assert_eq!(listener.local_addr().unwrap(), address),
+            ref other => panic!("expected a TCP listener, got a {:?}", other.kind()),
+        }
+        assert!(matches!(inherited[1], Inherited::UnixStream(_)));
+        assert!(matches!(inherited[2], Inherited::Fd(FdKind::TimerFd, _)));
+        assert!(matches!(inherited[3], Inherited::File(_)));
+        assert!(matches!(inherited[4], Inherited::PipeReader(_)));
+
+        // Everything but the file is registered.
+        for file in &inherited {
+            let data = epoll.data_for(file.as_raw_fd());
+            assert_eq!(data, if file.kind() == FdKind::File { None } else { Some(file.as_raw_fd() as u64) });
+        }
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watching files and directories for changes, through inotify.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let inotify = Inotify::new()?;
+//! let uploads = inotify.add_watch("/srv/uploads", IN_CLOSE_WRITE | IN_MOVED_TO)?;
+//! epoll.add(&inotify, EPOLLIN, UPLOADS)?;
+//!
+//! // Once `UPLOADS` is reported:
+//! let mut buffer = [0; 4096];
+//! for event in inotify.read_events(&mut buffer)? {
+//!     if let Some(name) = event.name {
+//!         process(Path::new("/srv/uploads").join(name));
+//!     }
+//! }
+//! ```
+
+use super::*;
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+bitflags! {
+    /// What a watch reports, and what an event is about; see inotify(7).
+    pub flags WatchMask: u32 {
+        /// The file was read.
+        const IN_ACCESS = libc::IN_ACCESS,
+        /// The file was written to.
+        const IN_MODIFY = libc::IN_MODIFY,
+        /// The file's metadata changed, e.g. its permissions or timestamps.
+        const IN_ATTRIB = libc::IN_ATTRIB,
+        /// A file opened for writing was closed.
+        const IN_CLOSE_WRITE = libc::IN_CLOSE_WRITE,
+        /// A file not opened for writing was closed.
+        const IN_CLOSE_NOWRITE = libc::IN_CLOSE_NOWRITE,
+        /// The file was opened.
+        const IN_OPEN = libc::IN_OPEN,
+        /// A file was moved out of the watched directory.
+        const IN_MOVED_FROM = libc::IN_MOVED_FROM,
+        /// A file was moved into the watched directory.
+        const IN_MOVED_TO = libc::IN_MOVED_TO,
+        /// A file was created in the watched directory.
+        const IN_CREATE = libc::IN_CREATE,
+        /// A file was deleted from the watched directory.
+        const IN_DELETE = libc::IN_DELETE,
+        /// The watched file itself was deleted.
+        const IN_DELETE_SELF = libc::IN_DELETE_SELF,
+        /// The watched file itself was moved.
+        const IN_MOVE_SELF = libc::IN_MOVE_SELF,
+
+        /// Both kinds of closing.
+        const IN_CLOSE = libc::IN_CLOSE,
+        /// Both directions of moving.
+        const IN_MOVE = libc::IN_MOVE,
+        /// Every event above.
+        const IN_ALL_EVENTS = libc::IN_ALL_EVENTS,
+
+        /// Only watch the path if it is a directory.
+        const IN_ONLYDIR = libc::IN_ONLYDIR,
+        /// Don't follow the path if it is a symbolic link.
+        const IN_DONT_FOLLOW = libc::IN_DONT_FOLLOW,
+        /// Stop reporting files once they are unlinked from the watched directory.
+        const IN_EXCL_UNLINK = libc::IN_EXCL_UNLINK,
+        /// Add to the mask of an existing watch of the path, instead of replacing it.
+        const IN_MASK_ADD = libc::IN_MASK_ADD,
+        /// Remove the watch after its first event.
+        const IN_ONESHOT = libc::IN_ONESHOT,
+
+        /// Reported: the event is about a directory.
+        const IN_ISDIR = libc::IN_ISDIR,
+        /// Reported: the watch was removed, explicitly or because its file is gone.
+        const IN_IGNORED = libc::IN_IGNORED,
+        /// Reported: events were dropped because the queue overflowed.
+        const IN_Q_OVERFLOW = libc::IN_Q_OVERFLOW,
+        /// Reported: the filesystem of the watched file was unmounted.
+        const IN_UNMOUNT = libc::IN_UNMOUNT,
+    }
+}
+
+/// Identifies a watch of an `Inotify`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Watch(libc::c_int);
+
+/// An inotify instance, created non-blocking and close-on-exec.
+///
+/// It is readable while events are queued.
+#[derive(Debug)]
+pub struct Inotify {
+    fd: OwnedFd,
+}
+
+/// A change reported by an `Inotify`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InotifyEvent<'a> {
+    /// The watch the event is about; unused for `IN_Q_OVERFLOW`.
+    pub watch: Watch,
+    /// What happened, along with flags such as `IN_ISDIR`.
+    pub mask: WatchMask,
+    /// Pairs the `IN_MOVED_FROM` and `IN_MOVED_TO` of a single rename.
+    pub cookie: u32,
+    /// The file in the watched directory that the event is about, if any.
+    pub name: Option<&'a OsStr>,
+}
+
+/// The size of an `inotify_event` before its name.
+const HEADER: usize = 16;
+
+impl Inotify {
+    /// Creates an inotify instance without watches.
+    pub fn new() -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+
+        if fd < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(Inotify { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+        }
+    }
+
+    /// Watches a path for the events of `mask`.
+    ///
+    /// Watching a path again returns the same watch, with its mask replaced
+    /// (or extended, with `IN_MASK_ADD`).
+    pub fn add_watch<P: AsRef<Path>>(&self, path: P, mask: WatchMask) -> io::Result<Watch> {
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())
+            .map_err(|_| Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), mask.bits()) };
+
+        if wd < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(Watch(wd))
+        }
+    }
+
+    /// Removes a watch, which reports a last `IN_IGNORED` event.
+    pub fn remove_watch(&self, watch: Watch) -> io::Result<()> {
+        if unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), watch.0) } < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Reads the queued events into `buffer`, and returns an iterator over them;
+    /// it is empty if none are queued.
+    ///
+    /// Fails with `EINVAL` if `buffer` is too small for the next event, which
+    /// 4096 bytes always fit.
+    pub fn read_events<'a>(&self, buffer: &'a mut [u8]) -> io::Result<InotifyEvents<'a>> {
+        let rc = unsafe { libc::read(self.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+
+        let length = if rc < 0 {
+            let error = Error::last_os_error();
+            if error.kind() != io::ErrorKind::WouldBlock {
+                return Err(error);
+            }
+            0
+        }
+        else {
+            rc as usize
+        };
+
+        Ok(InotifyEvents { buffer: &buffer[..length] })
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Inotify {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// The events of a single read; see `Inotify::read_events`.
+pub struct InotifyEvents<'a> {
+    /// The events that weren't yet iterated over.
+    buffer: &'a [u8],
+}
+
+impl<'a> Iterator for InotifyEvents<'a> {
+    type Item = InotifyEvent<'a>;
+
+    fn next(&mut self) -> Option<InotifyEvent<'a>> {
+        if self.buffer.len() < HEADER {
+            return None;
+        }
+
+        let field = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&self.buffer[offset..offset + 4]);
+            u32::from_ne_bytes(bytes)
+        };
+        let (wd, mask, cookie, length) = (field(0), field(4), field(8), field(12) as usize);
+
+        // The name is padded with nul bytes, and absent if `length` is zero.
+        let name = &self.buffer[HEADER..HEADER + length];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        self.buffer = &self.buffer[HEADER + length..];
+
+        Some(InotifyEvent {
+            watch: Watch(wd as libc::c_int),
+            mask: WatchMask::from_bits_truncate(mask),
+            cookie,
+            name: if name.is_empty() { None } else { Some(OsStr::from_bytes(name)) },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn directory_changes() {
+        let directory = ::std::env::temp_dir().join(format!("epoll-inotify-{}", ::std::process::id()));
+        fs::create_dir(&directory).unwrap();
+
+        let inotify = Inotify::new().unwrap();
+        let watch = inotify.add_watch(&directory, IN_CREATE | IN_DELETE).unwrap();
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&inotify, EPOLLIN, 0).unwrap();
+
+        let mut buffer = [0; 4096];
+        assert_eq!(inotify.read_events(&mut buffer).unwrap().count(), 0);
+
+        fs::write(directory.join("a-rather-long-file-name"), b"").unwrap();
+        fs::create_dir(directory.join("b")).unwrap();
+        fs::remove_file(directory.join("a-rather-long-file-name")).unwrap();
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_some());
+
+        let events: Vec<_> = inotify.read_events(&mut buffer).unwrap()
+            .map(|e| (e.watch, e.mask, e.name.map(|name| name.to_os_string())))
+            .collect();
+        assert_eq!(events, vec![
+            (watch, IN_CREATE, Some("a-rather-long-file-name".into())),
+            (watch, IN_CREATE | IN_ISDIR, Some("b".into())),
+            (watch, IN_DELETE, Some("a-rather-long-file-name".into())),
+        ]);
+
+        inotify.remove_watch(watch).unwrap();
+        let ignored: Vec<_> = inotify.read_events(&mut buffer).unwrap().map(|e| (e.watch, e.mask, e.name)).collect();
+        assert_eq!(ignored, vec![(watch, IN_IGNORED, None)]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ready-made event masks for common registration patterns.
+//!
+//! # Example
+//!
+//! ```no-run
+//! epoll.add(&listener, Interest::ACCEPT, 0)?;
+//! epoll.add(&connection, Interest::CLIENT_STREAM, 1)?;
+//! epoll.add(&pipe, (Interest::READABLE | Interest::RDHUP, Trigger::Edge), 2)?;
+//! ```
+
+use super::*;
+use std::ops::BitOr;
+
+/// An event mask to register a file with.
+///
+/// Anywhere an `EventType` is expected, an `Interest` can be used as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Interest(EventType);
+
+const fn mask(bits: libc::c_int) -> EventType {
+    EventType::from_bits_retain(bits as u32)
+}
+
+impl Interest {
+    /// The file has data to read (`EPOLLIN`).
+    pub const READABLE: Interest = Interest(mask(libc::EPOLLIN));
+
+    /// The file can be written to (`EPOLLOUT`).
+    pub const WRITABLE: Interest = Interest(mask(libc::EPOLLOUT));
+
+    /// The file has exceptional data, e.g. out-of-band TCP data (`EPOLLPRI`).
+    pub const PRIORITY: Interest = Interest(mask(libc::EPOLLPRI));
+
+    /// The peer of a stream socket shut down its writing half (`EPOLLRDHUP`).
+    pub const RDHUP: Interest = Interest(mask(libc::EPOLLRDHUP));
+
+    /// Edge-triggered readability, disarmed after every event.
+    ///
+    /// Suited for sharing one epoll between worker threads: only one thread is woken
+    /// per event, and the file must be re-armed with `EPoll::modify` once handled.
+    pub const READ_EDGE_ONESHOT: Interest =
+        Interest(mask(libc::EPOLLIN | libc::EPOLLET | libc::EPOLLONESHOT));
+
+    /// Level-triggered readability, for listening sockets.
+    ///
+    /// Level triggering guarantees that pending connections are reported again
+    /// if not all of them were accepted.
+    pub const ACCEPT: Interest = Interest(mask(libc::EPOLLIN));
+
+    /// Like `ACCEPT`, but only one of several epolls watching the listener is woken
+    /// per connection, which avoids a thundering herd between worker threads.
+    ///
+    /// Must only be used with `EPoll::add`, never with `EPoll::modify`. (since Linux 4.5)
+    pub const ACCEPT_EXCLUSIVE: Interest = Interest(mask(libc::EPOLLIN | libc::EPOLLEXCLUSIVE));
+
+    /// Edge-triggered readability and writability of a connected stream,
+    /// including notification of the peer shutting down its writing half.
+    pub const CLIENT_STREAM: Interest =
+        Interest(mask(libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLRDHUP | libc::EPOLLET));
+
+    /// Returns the event mask.
+    pub fn events(&self) -> EventType {
+        self.0
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+impl From<Interest> for EventType {
+    fn from(interest: Interest) -> EventType {
+        interest.0
+    }
+}
+
+/// When a file is reported as ready.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    /// Whenever the file is ready, until it is drained.
+    Level,
+    /// Only when the file becomes ready; it must be drained until `WouldBlock` before waiting again.
+    Edge,
+}
+
+/// Registers with an interest and an explicit trigger mode, e.g. `(Interest::READABLE, Trigger::Edge)`.
+impl From<(Interest, Trigger)> for EventType {
+    fn from((interest, trigger): (Interest, Trigger)) -> EventType {
+        match trigger {
+            Trigger::Level => interest.0,
+            Trigger::Edge => interest.0 | EPOLLET,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        assert_eq!(Interest::READ_EDGE_ONESHOT.events(), EPOLLIN | EPOLLET | EPOLLONESHOT);
+        assert_eq!(Interest::ACCEPT.events(), EPOLLIN);
+        assert_eq!(Interest::ACCEPT_EXCLUSIVE.events(), EPOLLIN | EPOLLEXCLUSIVE);
+        assert_eq!(Interest::CLIENT_STREAM.events(), EPOLLIN | EPOLLOUT | EPOLLRDHUP | EPOLLET);
+
+        assert_eq!(EventType::from((Interest::READABLE | Interest::WRITABLE, Trigger::Level)), EPOLLIN | EPOLLOUT);
+        assert_eq!(EventType::from((Interest::PRIORITY | Interest::RDHUP, Trigger::Edge)), EPOLLPRI | EPOLLRDHUP | EPOLLET);
+    }
+
+    #[test]
+    fn usable_for_registration() {
+        let (_left, right) = unix::duplex().unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&right, Interest::CLIENT_STREAM, 0).unwrap();
+
+        // The socket is writable right away, but has nothing to read.
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert!({ events[0].events }.contains(EPOLLOUT));
+
+        epoll.modify(&right, Interest::READ_EDGE_ONESHOT, 0).unwrap();
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 0);
+
+        epoll.modify(&right, (Interest::WRITABLE, Trigger::Edge), 0).unwrap();
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+    }
+}
@@ -24,7 +24,7 @@
 //! # Example
 //! 
 //! ```no-run
-//! let mut epoll = EPoll::new().unwrap();
+//! let epoll = EPoll::new().unwrap();
 //! 
 //! // Register a file-like object onto the epoll.
 //! // The last parameter is a user-defined identifier
@@ -34,7 +34,7 @@
 //! let mut events = [Event::default(); 2];
 //! let event_count = epoll.wait(&mut events, Timeout::Milliseconds(500))?;
 //! for e in &events[..event_count] {
-//!     match e.data {
+//!     match e.token() {
 //!         0 => { /* Do something with the pipe  */ },
 //!         1 => { /* Do something with the timer */ },
 //!         _ => unreachable!()
@@ -45,91 +45,464 @@
 #[macro_use] extern crate bitflags;
 extern crate libc;
 
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, Error};
-use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, IntoRawFd, AsFd, BorrowedFd, OwnedFd};
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::slice;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
+// bitflags 0.7 expands to the deprecated `try!` macro.
+#[allow(deprecated)]
 mod ffi;
 pub use ffi::*;
 
+mod interest;
+pub use interest::{Interest, Trigger};
+
+mod error;
+pub use error::CtlError;
+
+mod waker;
+pub use waker::Waker;
+
 pub mod event_loop;
+pub mod unix;
+pub mod pipe;
+pub mod sigset;
+pub mod signalfd;
+pub mod pidfd;
+#[allow(deprecated)] // See `ffi`.
+pub mod inotify;
+#[allow(deprecated)] // See `ffi`.
+pub mod fanotify;
+pub mod eventfd;
+pub mod counter;
+pub mod mqueue;
+pub mod ring;
+pub mod typed;
+pub mod health;
+pub mod privilege;
+pub mod inherit;
+pub mod mirror;
+pub mod testing;
+pub mod syscalls;
+pub mod compat;
+
+pub use syscalls::syscalls_used;
+use sigset::SigSet;
+
+#[cfg(any(test, feature = "alloc-audit"))]
+pub mod alloc_audit;
 
 /// An object used to poll for many events at once.
+///
+/// `EPoll` is `Send` and `Sync`: an `Arc<EPoll>` can be waited on by one or more
+/// threads while others add, modify and remove registrations, as the kernel allows.
+/// Changes are serialized by a lock around the registration table; waits don't
+/// take it, and neither does the journal while it runs.
 pub struct EPoll {
-    fd: RawFd
+    fd: OwnedFd,
+    table: Mutex<Table>,
+
+    /// Held while the journal runs. When both are held, it is taken before the
+    /// table's lock; changes only try it once they have released the table.
+    journal: Mutex<Option<Journal>>,
+
+    /// The buffer of `wait_iter`, allocated on first use.
+    buffer: Vec<Event>,
+}
+
+/// The registrations made through an `EPoll`, and the changes to them that
+/// the journal is yet to be told about.
+#[derive(Default)]
+pub(crate) struct Table {
+    pub(crate) registrations: HashMap<RawFd, Registration>,
+
+    /// Whether there is a journal, and changes are queued for it.
+    pub(crate) journaled: bool,
+    pub(crate) pending: VecDeque<JournalEntry>,
+
+    /// The eventfd behind `EPoll::waker`, once created.
+    pub(crate) waker: Option<std::sync::Arc<counter::Counter>>,
+}
+
+pub(crate) type Journal = Box<dyn FnMut(&JournalEntry) + Send>;
+
+/// The event mask and data a file was registered with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registration {
+    pub events: EventType,
+    pub data: u64,
+}
+
+/// A change to the registrations of an `EPoll`, as reported to its journal.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// When the change was made.
+    pub time: SystemTime,
+
+    /// The descriptor whose registration changed.
+    pub fd: RawFd,
+
+    /// The registration before the change, or `None` if the descriptor was just added.
+    pub old: Option<Registration>,
+
+    /// The registration after the change, or `None` if the descriptor was removed.
+    pub new: Option<Registration>,
+
+    /// Why the change was made, if it was made through `EPoll::with_reason`.
+    pub reason: Option<String>,
+}
+
+/// Control operations of an `EPoll` whose journal entries carry a reason;
+/// see `EPoll::with_reason`.
+pub struct WithReason<'e> {
+    epoll: &'e EPoll,
+    reason: &'e str,
+}
+
+impl<'e> WithReason<'e> {
+    /// Like `EPoll::add`.
+    pub fn add<T, E>(&self, file: &T, events: E, data: u64) -> Result<(), CtlError>
+        where T: AsFd + ?Sized, E: Into<EventType>
+    {
+        self.add_raw(file.as_fd().as_raw_fd(), events, data)
+    }
+
+    /// Like `EPoll::remove`.
+    pub fn remove<T: AsFd + ?Sized>(&self, file: &T) -> Result<(), CtlError> {
+        self.remove_raw(file.as_fd().as_raw_fd())
+    }
+
+    /// Like `EPoll::modify`.
+    pub fn modify<T, E>(&self, file: &T, events: E, data: u64) -> Result<(), CtlError>
+        where T: AsFd + ?Sized, E: Into<EventType>
+    {
+        self.modify_raw(file.as_fd().as_raw_fd(), events, data)
+    }
+
+    /// Like `EPoll::add_raw`.
+    pub fn add_raw<E: Into<EventType>>(&self, fd: RawFd, events: E, data: u64) -> Result<(), CtlError> {
+        let events = events.into();
+        self.epoll.change(libc::EPOLL_CTL_ADD, fd, Some(Registration { events, data }), Some(self.reason))
+    }
+
+    /// Like `EPoll::remove_raw`.
+    pub fn remove_raw(&self, fd: RawFd) -> Result<(), CtlError> {
+        self.epoll.change(libc::EPOLL_CTL_DEL, fd, None, Some(self.reason))
+    }
+
+    /// Like `EPoll::modify_raw`.
+    pub fn modify_raw<E: Into<EventType>>(&self, fd: RawFd, events: E, data: u64) -> Result<(), CtlError> {
+        let events = events.into();
+        self.epoll.change(libc::EPOLL_CTL_MOD, fd, Some(Registration { events, data }), Some(self.reason))
+    }
 }
 
 impl EPoll {
     /// Creates a new EPoll object.
+    ///
+    /// The descriptor is inherited across exec; use `with_flags(EPOLL_CLOEXEC)` to prevent that.
     pub fn new() -> io::Result<Self> {
-        let fd = unsafe {
-            ffi::epoll_create1(0)
+        EPoll::with_flags(CreateFlags::empty())
+    }
 
+    /// Creates a new EPoll object with the given creation flags.
+    pub fn with_flags(flags: CreateFlags) -> io::Result<Self> {
+        let fd = unsafe {
+            ffi::epoll_create1(flags.bits())
         };
 
         if fd < 0 {
             Err(Error::last_os_error())            
         }
         else {
-            Ok(EPoll { fd: fd })
+            Ok(EPoll::from_fd(unsafe { OwnedFd::from_raw_fd(fd) }))
         }
     }
 
-    /// Adds a new file-like-object onto the epoll.
+    /// Creates a second handle to the same epoll, e.g. to wait on one thread while
+    /// another changes the registrations.
     ///
-    /// The data parameter is a user-defined identification of the object;
-    /// for example, it can be an index to an array, the file-descriptor itself, etc.
-    pub fn add<T: AsRawFd + ?Sized>(&mut self, file: &T, events: EventType, data: u64) -> io::Result<()> {
-        let mut event = Event { events: events, data: data };
-        
-        let rc = unsafe { 
-            ffi::epoll_ctl(self.fd, 
-                            libc::EPOLL_CTL_ADD, 
-                            file.as_raw_fd(), 
-                            &mut event) 
-        };
+    /// The new handle starts with a copy of the registration table, but each handle
+    /// tracks only its own changes from then on, and the journal is not carried over.
+    /// The new descriptor is close-on-exec.
+    pub fn try_clone(&self) -> io::Result<EPoll> {
+        let clone = EPoll::from_fd(self.fd.try_clone()?);
+        {
+            let table = self.table();
+            let mut cloned = clone.table();
+            cloned.registrations = table.registrations.clone();
+            cloned.waker = table.waker.clone();
+        }
 
-        if rc < 0 {
-            Err(Error::last_os_error())            
+        Ok(clone)
+    }
+
+    /// Closes the epoll, reporting failures that dropping it would ignore.
+    ///
+    /// The descriptor is closed even if an error is returned; it is not retried.
+    pub fn close(self) -> io::Result<()> {
+        if unsafe { libc::close(self.into_raw_fd()) } < 0 {
+            Err(Error::last_os_error())
         }
         else {
             Ok(())
         }
     }
 
+    /// Wraps an existing epoll descriptor, with an empty registration table.
+    fn from_fd(fd: OwnedFd) -> EPoll {
+        EPoll { fd, table: Mutex::new(Table::default()), journal: Mutex::new(None), buffer: Vec::new() }
+    }
+
+    /// Locks the registration table.
+    pub(crate) fn table(&self) -> MutexGuard<'_, Table> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Locks the journal, waiting for it to return if it is running; a journal
+    /// that panicked doesn't poison it.
+    pub(crate) fn journal(&self) -> MutexGuard<'_, Option<Journal>> {
+        self.journal.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sets a hook that is invoked after every successful change to the registrations,
+    /// e.g. for auditing what the process was watching and when.
+    ///
+    /// Failed operations are not reported. The journal is called without the
+    /// registration table locked, so it may use this `EPoll`, and even change
+    /// the registrations; it must not set or clear the journal, though.
+    ///
+    /// Changes are reported one at a time and in the order they were made, but
+    /// not necessarily on the thread that made them: while the journal runs,
+    /// changes made on other threads are reported by the thread running it.
+    pub fn set_journal<F: FnMut(&JournalEntry) + Send + 'static>(&self, journal: F) {
+        let mut installed = self.journal();
+        self.table().journaled = true;
+        *installed = Some(Box::new(journal));
+    }
+
+    /// Removes the journal hook, if any, dropping the changes it wasn't told about yet.
+    pub fn clear_journal(&self) {
+        let mut installed = self.journal();
+        let mut table = self.table();
+        table.journaled = false;
+        table.pending.clear();
+        *installed = None;
+    }
+
+    /// Tells the journal about the pending changes, unless another call to
+    /// this is already doing so, and will see them.
+    fn flush_journal(&self) {
+        loop {
+            let mut journal = match self.journal.try_lock() {
+                Ok(journal) => journal,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => return,
+            };
+
+            // Changes the journal makes itself queue up behind the current one.
+            loop {
+                let entry = match self.table().pending.pop_front() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                if let Some(ref mut journal) = *journal {
+                    journal(&entry);
+                }
+            }
+            drop(journal);
+
+            // A change queued after the last look, while the journal was still
+            // locked, was left to this call.
+            if self.table().pending.is_empty() {
+                return;
+            }
+        }
+    }
+
+    /// Control operations whose journal entries carry `reason`, e.g.
+    /// `epoll.with_reason("accepting clients").add(&listener, EPOLLIN, 0)`.
+    pub fn with_reason<'e>(&'e self, reason: &'e str) -> WithReason<'e> {
+        WithReason { epoll: self, reason }
+    }
+
+    /// Adds a new file-like-object onto the epoll.
+    ///
+    /// The data parameter is a user-defined identification of the object;
+    /// for example, it can be an index to an array, the file-descriptor itself, etc.
+    ///
+    /// The events can be given either as an `EventType` or as an `Interest`.
+    pub fn add<T, E>(&self, file: &T, events: E, data: u64) -> Result<(), CtlError>
+        where T: AsFd + ?Sized, E: Into<EventType>
+    {
+        self.add_raw(file.as_fd().as_raw_fd(), events.into(), data)
+    }
+
     /// Removes an existing file-like-object from the epoll.
-    pub fn remove<T: AsRawFd + ?Sized>(&mut self, file: &T) -> io::Result<()> {
-        // This syscall doesn't actually use the "event" pointer, but earlier kernel versions
-        // required it to be non-null.
-        let mut event = Event::default();
-        
-        let rc = unsafe { 
-            ffi::epoll_ctl(self.fd, 
-                            libc::EPOLL_CTL_DEL, 
-                            file.as_raw_fd(),
-                            &mut event) 
+    pub fn remove<T: AsFd + ?Sized>(&self, file: &T) -> Result<(), CtlError> {
+        self.remove_raw(file.as_fd().as_raw_fd())
+    }
+
+    /// Modifies the event mask and the associated data of a registered file.
+    pub fn modify<T, E>(&self, file: &T, events: E, data: u64) -> Result<(), CtlError>
+        where T: AsFd + ?Sized, E: Into<EventType>
+    {
+        self.modify_raw(file.as_fd().as_raw_fd(), events.into(), data)
+    }
+
+    /// Adds a descriptor that isn't wrapped in an `AsFd` type, e.g. one owned by a C library.
+    ///
+    /// The descriptor must stay open while registered; see `forget` otherwise.
+    pub fn add_raw<E: Into<EventType>>(&self, fd: RawFd, events: E, data: u64) -> Result<(), CtlError> {
+        let events = events.into();
+        self.change(libc::EPOLL_CTL_ADD, fd, Some(Registration { events, data }), None)
+    }
+
+    /// Removes a descriptor added with `add_raw` (or any other way).
+    pub fn remove_raw(&self, fd: RawFd) -> Result<(), CtlError> {
+        self.change(libc::EPOLL_CTL_DEL, fd, None, None)
+    }
+
+    /// Modifies the event mask and the associated data of a registered descriptor.
+    pub fn modify_raw<E: Into<EventType>>(&self, fd: RawFd, events: E, data: u64) -> Result<(), CtlError> {
+        let events = events.into();
+        self.change(libc::EPOLL_CTL_MOD, fd, Some(Registration { events, data }), None)
+    }
+
+    /// Watches another epoll, which is reported readable whenever it has events ready.
+    ///
+    /// Fails with `WouldLoop` if `inner` watches this epoll, directly or through other epolls.
+    pub fn add_epoll(&self, inner: &EPoll, data: u64) -> Result<(), CtlError> {
+        self.add(inner, EPOLLIN, data)
+    }
+
+    /// Stops reporting events of a registered file, keeping its data and its place in the epoll.
+    ///
+    /// Returns the registration it had, to be handed back to `rearm` later.
+    pub fn disable<T: AsFd + ?Sized>(&self, file: &T) -> Result<Registration, CtlError> {
+        let fd = file.as_fd().as_raw_fd();
+
+        // Reading the registration under the same lock as changing it keeps a
+        // concurrent change from being overwritten, or returned as the previous one.
+        let mut table = self.table();
+        let previous = match table.registrations.get(&fd) {
+            Some(&registration) => registration,
+            None => return Err(CtlError::NotRegistered),
         };
 
-        if rc < 0 {
-            Err(Error::last_os_error())            
+        let disabled = Registration { events: EventType::empty(), data: previous.data };
+        self.ctl(libc::EPOLL_CTL_MOD, fd, Event { events: disabled.events, data: disabled.data })?;
+        table.record(fd, Some(disabled), None);
+        drop(table);
+
+        self.flush_journal();
+        Ok(previous)
+    }
+
+    /// Re-arms a file registered with `EPOLLONESHOT` (or disabled), for one more event.
+    ///
+    /// `EPOLLONESHOT` is added to `events` if missing.
+    pub fn rearm<T, E>(&self, file: &T, events: E, data: u64) -> Result<(), CtlError>
+        where T: AsFd + ?Sized, E: Into<EventType>
+    {
+        self.modify_raw(file.as_fd().as_raw_fd(), events.into() | EPOLLONESHOT, data)
+    }
+
+    /// Returns whether a descriptor is registered through this object.
+    pub fn contains(&self, fd: RawFd) -> bool {
+        self.table().registrations.contains_key(&fd)
+    }
+
+    /// The amount of descriptors registered through this object.
+    pub fn len(&self) -> usize {
+        self.table().registrations.len()
+    }
+
+    /// Returns whether no descriptors are registered through this object.
+    pub fn is_empty(&self) -> bool {
+        self.table().registrations.is_empty()
+    }
+
+    /// Iterates over a snapshot of the registered descriptors and their registrations,
+    /// in no particular order.
+    pub fn registrations(&self) -> impl Iterator<Item = (RawFd, Registration)> {
+        let snapshot: Vec<_> = self.table().registrations.iter().map(|(&fd, &registration)| (fd, registration)).collect();
+        snapshot.into_iter()
+    }
+
+    /// Returns the data associated with a registered descriptor,
+    /// or `None` if it was not registered through this object.
+    pub fn data_for(&self, fd: RawFd) -> Option<u64> {
+        self.table().registrations.get(&fd).map(|r| r.data)
+    }
+
+    /// Replaces the data associated with a registered descriptor, keeping its event mask.
+    ///
+    /// Fails with `NotRegistered` if the descriptor was not registered through this object.
+    pub fn set_data_for(&self, fd: RawFd, data: u64) -> Result<(), CtlError> {
+        let events = match self.table().registrations.get(&fd) {
+            Some(registration) => registration.events,
+            None => return Err(CtlError::NotRegistered),
+        };
+
+        self.modify_raw(fd, events, data)
+    }
+
+    /// Drops a descriptor from the registration table without deregistering it,
+    /// and returns its registration.
+    ///
+    /// For descriptors that were closed (or replaced) without being removed first, whose
+    /// registration the kernel already dropped or no longer reaches by descriptor.
+    pub fn forget(&self, fd: RawFd) -> Option<Registration> {
+        let mut table = self.table();
+        let old = table.registrations.get(&fd).cloned();
+        if old.is_some() {
+            table.record(fd, None, None);
         }
-        else {
-            Ok(())
+        drop(table);
+
+        self.flush_journal();
+        old
+    }
+
+    /// Applies a control operation and records it, under the table's lock so that
+    /// concurrent changes are recorded in the order the kernel applied them,
+    /// then tells the journal.
+    fn change(&self, op: libc::c_int, fd: RawFd, new: Option<Registration>, reason: Option<&str>) -> Result<(), CtlError> {
+        // EPOLL_CTL_DEL doesn't actually use the "event" pointer, but earlier kernel versions
+        // required it to be non-null.
+        let event = new.map(|r| Event { events: r.events, data: r.data }).unwrap_or_default();
+
+        // The kernel says EINVAL, which could mean many things.
+        if fd == self.fd.as_raw_fd() && op != libc::EPOLL_CTL_DEL {
+            return Err(CtlError::SelfRegistration);
         }
+
+        let mut table = self.table();
+        self.ctl(op, fd, event)?;
+        table.record(fd, new, reason);
+        drop(table);
+
+        self.flush_journal();
+        Ok(())
     }
 
-    /// Modifies the event mask and the associated data of a registered file.
-    pub fn modify<T: AsRawFd + ?Sized>(&mut self, file: &T, events: EventType, data: u64) -> io::Result<()> {
-        let mut event = Event { events: events, data: data };
-        
+    fn ctl(&self, op: libc::c_int, fd: RawFd, mut event: Event) -> Result<(), CtlError> {
         let rc = unsafe { 
-            ffi::epoll_ctl(self.fd, 
-                            libc::EPOLL_CTL_MOD, 
-                            file.as_raw_fd(), 
+            ffi::epoll_ctl(self.fd.as_raw_fd(), 
+                            op, 
+                            fd, 
                             &mut event) 
         };
 
         if rc < 0 {
-            Err(Error::last_os_error())            
+            Err(CtlError::from(Error::last_os_error()))
         }
         else {
             Ok(())
@@ -142,6 +515,8 @@ impl EPoll {
     /// is currently able to accept.
     /// The return value is the amount that are ready to be processed, and is in the range 0...events.len().
     ///
+    /// The timeout can also be given as a `Duration`; see `Timeout`'s `From` impl.
+    ///
     /// # Example
     /// ```no-run
     /// let mut events = [Event::default(); 3];
@@ -150,25 +525,182 @@ impl EPoll {
     ///     // ...
     /// }
     /// ```
-    pub fn wait(&self, events: &mut [Event], timeout: Timeout) -> io::Result<usize> {
-        let timeout = match timeout {
-            Timeout::Indefinite => -1,
-            Timeout::Immediate => 0,
-            Timeout::Milliseconds(amount) => {
-                if amount >= std::i32::MAX as usize {
-                    std::i32::MAX
-                }
-                else {
-                    amount as i32
-                }
+    pub fn wait<T: Into<Timeout>>(&self, events: &mut [Event], timeout: T) -> io::Result<usize> {
+        let rc = unsafe {
+            ffi::epoll_wait(self.fd.as_raw_fd(), 
+                             events.as_mut_ptr(),
+                             events.len() as libc::c_int,
+                             timeout.into().as_millis())
+        };
+
+        if rc < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(rc as usize)
+        }
+    }
+
+    /// Waits for a single event; returns `None` if the timeout expired first.
+    pub fn wait_one<T: Into<Timeout>>(&self, timeout: T) -> io::Result<Option<Event>> {
+        let mut events = [Event::default(); 1];
+
+        match self.wait(&mut events, timeout)? {
+            0 => Ok(None),
+            _ => Ok(Some(events[0])),
+        }
+    }
+
+    /// Waits for events into a buffer kept by the `EPoll`, and iterates over them.
+    ///
+    /// Up to 64 events are reported per wait.
+    pub fn wait_iter<T: Into<Timeout>>(&mut self, timeout: T) -> io::Result<impl Iterator<Item = Event> + '_> {
+        if self.buffer.is_empty() {
+            self.buffer = vec![Event::default(); 64];
+        }
+
+        let mut buffer = mem::take(&mut self.buffer);
+        let result = self.wait(&mut buffer, timeout);
+        self.buffer = buffer;
+
+        Ok(self.buffer[..result?].iter().cloned())
+    }
+
+    /// Waits for events like `wait`, but retries when interrupted by a signal.
+    ///
+    /// Each retry waits only for what is left of the timeout, as measured on the monotonic clock.
+    pub fn wait_uninterruptible<T: Into<Timeout>>(&self, events: &mut [Event], timeout: T) -> io::Result<usize> {
+        let timeout = timeout.into();
+        let start = Instant::now();
+        let mut remaining = timeout;
+
+        loop {
+            match self.wait(events, remaining) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                result => return result,
             }
+
+            if let Some(duration) = timeout.as_duration() {
+                remaining = Timeout::from(duration.saturating_sub(start.elapsed()));
+            }
+        }
+    }
+
+    /// Waits for events, and calls `f` with each of them.
+    ///
+    /// Up to 64 events are handled per wait; returns their amount.
+    pub fn wait_with<T, F>(&self, timeout: T, mut f: F) -> io::Result<usize>
+        where T: Into<Timeout>, F: FnMut(Event)
+    {
+        let mut events = [Event::default(); 64];
+        let amount = self.wait(&mut events, timeout)?;
+
+        for event in &events[..amount] {
+            f(*event);
+        }
+
+        Ok(amount)
+    }
+
+    /// Waits for up to `max_events` events, and appends them to `events`.
+    ///
+    /// Returns the amount appended; `events` grows as needed.
+    /// A `max_events` of zero returns `Ok(0)` at once, without waiting;
+    /// the kernel would reject it with `EINVAL`.
+    pub fn wait_append<T: Into<Timeout>>(&self, events: &mut Vec<Event>, max_events: usize, timeout: T) -> io::Result<usize> {
+        if max_events == 0 {
+            return Ok(0);
+        }
+
+        events.reserve(max_events);
+
+        let len = events.len();
+        let amount = self.wait_uninit(&mut events.spare_capacity_mut()[..max_events], timeout)?.len();
+
+        // The kernel initialized the first `amount` events past the old length.
+        unsafe { events.set_len(len + amount) };
+        Ok(amount)
+    }
+
+    /// Waits for events like `wait`, into a buffer that needn't be initialized,
+    /// and returns the ready events.
+    ///
+    /// Saves zeroing large buffers before every wait.
+    ///
+    /// # Example
+    /// ```no-run
+    /// let mut events = vec![MaybeUninit::uninit(); 4096];
+    /// for e in epoll.wait_uninit(&mut events, Timeout::Indefinite)? {
+    ///     // ...
+    /// }
+    /// ```
+    pub fn wait_uninit<'a, T: Into<Timeout>>(&self, events: &'a mut [MaybeUninit<Event>], timeout: T) -> io::Result<&'a mut [Event]> {
+        let rc = unsafe {
+            ffi::epoll_wait(self.fd.as_raw_fd(),
+                             events.as_mut_ptr() as *mut Event,
+                             events.len().min(libc::c_int::MAX as usize) as libc::c_int,
+                             timeout.into().as_millis())
+        };
+
+        if rc < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            // The kernel initialized the first `rc` events.
+            Ok(unsafe { slice::from_raw_parts_mut(events.as_mut_ptr() as *mut Event, rc as usize) })
+        }
+    }
+
+    /// Waits for an event like `wait`, honoring the timeout with nanosecond precision.
+    ///
+    /// Uses `epoll_pwait2` (since Linux 5.11). On older kernels this falls back to `wait`,
+    /// with the timeout rounded up to whole milliseconds.
+    pub fn wait2<T: Into<Timeout>>(&self, events: &mut [Event], timeout: T) -> io::Result<usize> {
+        static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+        let timeout = timeout.into();
+        if UNSUPPORTED.load(Ordering::Relaxed) {
+            return self.wait(events, timeout);
+        }
+
+        let timespec = timeout.as_timespec();
+        let rc = unsafe {
+            ffi::epoll_pwait2(self.fd.as_raw_fd(),
+                              events.as_mut_ptr(),
+                              events.len() as libc::c_int,
+                              timespec.as_ref().map_or(ptr::null(), |t| t as *const libc::timespec),
+                              ptr::null())
         };
 
+        if rc < 0 {
+            let error = Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ENOSYS) {
+                UNSUPPORTED.store(true, Ordering::Relaxed);
+                return self.wait(events, timeout);
+            }
+
+            Err(error)
+        }
+        else {
+            Ok(rc as usize)
+        }
+    }
+
+    /// Waits for an event like `wait`, with the thread's signal mask replaced by `sigmask`
+    /// for the duration of the wait.
+    ///
+    /// Swapping the mask and waiting happen atomically, so a signal that is blocked
+    /// otherwise and unblocked by `sigmask` is either handled before the wait starts
+    /// or interrupts it (with `ErrorKind::Interrupted`); it is never missed.
+    pub fn wait_with_sigmask<T>(&self, events: &mut [Event], timeout: T, sigmask: &SigSet) -> io::Result<usize>
+        where T: Into<Timeout>
+    {
         let rc = unsafe {
-            ffi::epoll_wait(self.fd, 
+            ffi::epoll_pwait(self.fd.as_raw_fd(),
                              events.as_mut_ptr(),
                              events.len() as libc::c_int,
-                             timeout)
+                             timeout.into().as_millis(),
+                             sigmask.as_ptr())
         };
 
         if rc < 0 {
@@ -182,16 +714,53 @@ impl EPoll {
 
 impl AsRawFd for EPoll {
     fn as_raw_fd(&self) -> RawFd {
-        self.fd
+        self.fd.as_raw_fd()
     }
 }
 
-impl Drop for EPoll {
-    fn drop (&mut self) {
-        unsafe { libc::close(self.fd as libc::c_int); }
+impl AsFd for EPoll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
 
-        // Poison the file descriptor.
-        self.fd = -1;
+impl IntoRawFd for EPoll {
+    /// Releases the descriptor without closing it. The registration table is discarded.
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+impl From<EPoll> for OwnedFd {
+    fn from(epoll: EPoll) -> OwnedFd {
+        epoll.fd
+    }
+}
+
+impl Table {
+    /// Updates the registration table after a successful control operation,
+    /// and queues the change for the journal.
+    fn record(&mut self, fd: RawFd, new: Option<Registration>, reason: Option<&str>) {
+        let old = match new {
+            Some(registration) => self.registrations.insert(fd, registration),
+            None => self.registrations.remove(&fd),
+        };
+
+        if self.journaled {
+            self.pending.push_back(JournalEntry { time: SystemTime::now(), fd, old, new, reason: reason.map(String::from) });
+        }
+    }
+}
+
+impl FromRawFd for EPoll {
+    /// Takes ownership of an epoll descriptor, e.g. one inherited from a parent process.
+    ///
+    /// The registration table starts out empty, whatever the kernel's interest list holds.
+    ///
+    /// # Safety
+    /// `fd` must be an open epoll descriptor that nothing else closes.
+    unsafe fn from_raw_fd(fd: RawFd) -> EPoll {
+        EPoll::from_fd(OwnedFd::from_raw_fd(fd))
     }
 }
 
@@ -207,9 +776,73 @@ pub enum Timeout {
     /// The wait operation will wait `usize` milliseconds for new events before giving up.
     ///
     /// # Notes
-    /// This variant is of type `usize`, but is actually capped to std::i32::MAX due to API
+    /// This variant is of type `usize`, but is actually capped to i32::MAX due to API
     /// restrictions.
-    Milliseconds(usize)
+    Milliseconds(usize),
+
+    /// The wait operation will wait `u64` nanoseconds for new events before giving up.
+    ///
+    /// # Notes
+    /// Only `EPoll::wait2` is this precise; other waits round up to whole milliseconds.
+    Nanoseconds(u64),
+}
+
+impl Timeout {
+    /// The timeout as passed to `epoll_wait`; nanoseconds are rounded up.
+    fn as_millis(self) -> libc::c_int {
+        let amount = match self {
+            Timeout::Indefinite => return -1,
+            Timeout::Immediate => return 0,
+            Timeout::Milliseconds(amount) => amount as u64,
+            Timeout::Nanoseconds(amount) => amount.div_ceil(1_000_000),
+        };
+
+        if amount >= i32::MAX as u64 {
+            i32::MAX
+        }
+        else {
+            amount as i32
+        }
+    }
+
+    /// How long the timeout is, or `None` if it is indefinite.
+    fn as_duration(self) -> Option<Duration> {
+        match self {
+            Timeout::Indefinite => None,
+            Timeout::Immediate => Some(Duration::from_millis(0)),
+            Timeout::Milliseconds(amount) => Some(Duration::from_millis(amount as u64)),
+            Timeout::Nanoseconds(amount) => Some(Duration::from_nanos(amount)),
+        }
+    }
+
+    /// The timeout as passed to `epoll_pwait2`, where `None` waits indefinitely.
+    fn as_timespec(self) -> Option<libc::timespec> {
+        let (secs, nanos) = match self {
+            Timeout::Indefinite => return None,
+            Timeout::Immediate => (0, 0),
+            Timeout::Milliseconds(amount) => ((amount / 1000) as u64, (amount % 1000) as u64 * 1_000_000),
+            Timeout::Nanoseconds(amount) => (amount / 1_000_000_000, amount % 1_000_000_000),
+        };
+
+        Some(libc::timespec {
+            tv_sec: if secs > libc::time_t::MAX as u64 { libc::time_t::MAX } else { secs as libc::time_t },
+            tv_nsec: nanos as _,
+        })
+    }
+}
+
+/// Converts a `Duration` into `Timeout::Nanoseconds`.
+///
+/// Waits that take milliseconds round sub-millisecond remainders up, so a wait never
+/// returns before the duration elapses; durations too long to represent saturate.
+impl From<Duration> for Timeout {
+    fn from(duration: Duration) -> Timeout {
+        let nanos = duration.as_secs()
+            .saturating_mul(1_000_000_000)
+            .saturating_add(duration.subsec_nanos() as u64);
+
+        Timeout::Nanoseconds(nanos)
+    }
 }
 
 #[cfg(test)]
@@ -221,6 +854,10 @@ mod tests {
     impl AsRawFd for Fd {
         fn as_raw_fd(&self) -> RawFd { self.0 }
     }
+
+    impl AsFd for Fd {
+        fn as_fd(&self) -> BorrowedFd<'_> { unsafe { BorrowedFd::borrow_raw(self.0) } }
+    }
     
     #[repr(C)]
     pub struct itimerspec {
@@ -234,9 +871,63 @@ mod tests {
                         new_value: *const itimerspec, old_value: *mut itimerspec) -> libc::c_int;
     }
 
+    #[test]
+    fn duration_timeouts() {
+        let millis = |duration| Timeout::from(duration).as_millis();
+
+        assert_eq!(millis(Duration::from_secs(2)), 2000);
+        assert_eq!(millis(Duration::from_micros(1)), 1);
+        assert_eq!(millis(Duration::new(1, 1_000_001)), 1002);
+        assert_eq!(millis(Duration::ZERO), 0);
+        assert_eq!(millis(Duration::MAX), i32::MAX);
+
+        let epoll = EPoll::new().unwrap();
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Duration::from_micros(100)).unwrap(), 0);
+    }
+
+    #[test]
+    fn nanosecond_timeouts() {
+        let timespec = Timeout::from(Duration::new(3, 250_000)).as_timespec().unwrap();
+        assert_eq!((timespec.tv_sec, timespec.tv_nsec), (3, 250_000));
+        assert!(Timeout::Indefinite.as_timespec().is_none());
+
+        let epoll = EPoll::new().unwrap();
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait2(&mut events, Timeout::Nanoseconds(100_000)).unwrap(), 0);
+        assert_eq!(epoll.wait2(&mut events, Timeout::Immediate).unwrap(), 0);
+    }
+
+    #[test]
+    fn creation_flags() {
+        let cloexec = |epoll: &EPoll| unsafe { libc::fcntl(epoll.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC;
+
+        assert_eq!(cloexec(&EPoll::new().unwrap()), 0);
+        assert_eq!(cloexec(&EPoll::with_flags(EPOLL_CLOEXEC).unwrap()), libc::FD_CLOEXEC);
+    }
+
+    #[test]
+    fn raw_fd_round_trip() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 7).unwrap();
+
+        let fd = epoll.into_raw_fd();
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+
+        // The kernel's interest list survives the trip, the table doesn't.
+        let epoll = unsafe { EPoll::from_raw_fd(fd) };
+        assert_eq!(epoll.data_for(reader.as_raw_fd()), None);
+
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!({ events[0].data }, 7);
+    }
+
     #[test]
     fn no_event() {
-        let mut epoll = EPoll::new().unwrap();
+        let epoll = EPoll::new().unwrap();
         
         let timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
         assert!(timerfd >= 0);
@@ -253,7 +944,7 @@ mod tests {
 
     #[test]
     fn yes_event() {
-        let mut epoll = EPoll::new().unwrap();
+        let epoll = EPoll::new().unwrap();
         
         let timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
         assert!(timerfd >= 0);
@@ -280,4 +971,371 @@ mod tests {
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn single_event() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let (idle, _idle_writer) = testing::readable_pipe(b"").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&idle, EPOLLIN, 1).unwrap();
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+
+        epoll.add(&reader, EPOLLIN, 2).unwrap();
+        let event = epoll.wait_one(Timeout::Immediate).unwrap().unwrap();
+        assert_eq!({ event.data }, 2);
+    }
+
+    #[test]
+    fn appending_events() {
+        let (first, _w1) = testing::readable_pipe(b"x").unwrap();
+        let (second, _w2) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&first, EPOLLIN, 1).unwrap();
+
+        let mut events = Vec::new();
+        assert_eq!(epoll.wait_append(&mut events, 4, Timeout::Immediate).unwrap(), 1);
+
+        epoll.remove(&first).unwrap();
+        epoll.add(&second, EPOLLIN, 2).unwrap();
+        assert_eq!(epoll.wait_append(&mut events, 4, Timeout::Immediate).unwrap(), 1);
+
+        let data: Vec<_> = events.iter().map(|e| e.data).collect();
+        assert_eq!(data, vec![1, 2]);
+
+        assert_eq!(epoll.wait_append(&mut events, 0, Timeout::Indefinite).unwrap(), 0);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn uninitialized_buffer() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 8).unwrap();
+
+        let mut events = [MaybeUninit::uninit(); 16];
+        let ready = epoll.wait_uninit(&mut events, Timeout::Immediate).unwrap();
+        assert_eq!(ready.len(), 1);
+        assert_eq!({ ready[0].data }, 8);
+    }
+
+    #[test]
+    fn iterating_events() {
+        let (first, _w1) = testing::readable_pipe(b"x").unwrap();
+        let (second, _w2) = testing::readable_pipe(b"x").unwrap();
+
+        let mut epoll = EPoll::new().unwrap();
+        epoll.add(&first, EPOLLIN, 1).unwrap();
+        epoll.add(&second, EPOLLIN, 2).unwrap();
+
+        let mut data: Vec<_> = epoll.wait_iter(Timeout::Immediate).unwrap().map(|e| e.data).collect();
+        data.sort();
+        assert_eq!(data, vec![1, 2]);
+
+        epoll.modify(&first, EPOLLOUT, 1).unwrap();
+        let data: Vec<_> = epoll.wait_iter(Timeout::Immediate).unwrap().map(|e| e.data).collect();
+        assert_eq!(data, vec![2]);
+    }
+
+    #[test]
+    fn retries_when_interrupted() {
+        extern "C" fn ignore(_: libc::c_int) {}
+
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = ignore as *const () as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()), 0);
+        }
+
+        let epoll = EPoll::new().unwrap();
+        let waiter = unsafe { libc::pthread_self() };
+        let signaler = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            unsafe { libc::pthread_kill(waiter, libc::SIGUSR1) };
+        });
+
+        let start = Instant::now();
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait_uninterruptible(&mut events, Duration::from_millis(100)).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        signaler.join().unwrap();
+    }
+
+    #[test]
+    fn events_through_callback() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 5).unwrap();
+
+        let mut seen = Vec::new();
+        assert_eq!(epoll.wait_with(Timeout::Immediate, |e| seen.push(e.data)).unwrap(), 1);
+        assert_eq!(seen, vec![5]);
+    }
+
+    #[test]
+    fn unknown_event_bits_are_kept() {
+        let unknown = 1 << 20;
+        let events = EventType::from_bits_retain(EPOLLIN.bits() | unknown);
+
+        assert_eq!(events.raw_bits(), EPOLLIN.bits() | unknown);
+        assert_eq!(events.unknown_bits(), unknown);
+        assert!(events.contains(EPOLLIN));
+        assert_eq!((events | EPOLLOUT).unknown_bits(), unknown);
+        assert_eq!((events - EPOLLIN).raw_bits(), unknown);
+        assert_eq!(EventType::from_bits_truncate(events.raw_bits()), EPOLLIN);
+    }
+
+    #[test]
+    fn disconnect_kinds() {
+        let mut events = [Event::default(); 1];
+
+        let (socket, _peer) = testing::half_closed_socket(b"").unwrap();
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&socket, EPOLLIN | EPOLLRDHUP, 0).unwrap();
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!(events[0].disconnect(), Some(Disconnect::WriteStillPossible));
+
+        let socket = testing::hung_up_socket().unwrap();
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&socket, EPOLLIN | EPOLLRDHUP, 0).unwrap();
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!(events[0].disconnect(), Some(Disconnect::Full));
+
+        assert_eq!(Event::default().disconnect(), None);
+    }
+
+    #[test]
+    fn journal() {
+        use std::sync::{Arc, Mutex};
+
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let epoll = EPoll::new().unwrap();
+        {
+            let entries = entries.clone();
+            epoll.set_journal(move |entry| entries.lock().unwrap().push(entry.clone()));
+        }
+
+        let timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        assert!(timerfd >= 0);
+        let timerfd = Fd(timerfd as RawFd);
+
+        epoll.with_reason("timer armed").add(&timerfd, EPOLLIN, 1).unwrap();
+        epoll.modify(&timerfd, EPOLLIN | EPOLLOUT, 2).unwrap();
+        assert!(epoll.with_reason("never recorded").add(&timerfd, EPOLLIN, 3).is_err());
+        epoll.with_reason("timer cancelled").remove(&timerfd).unwrap();
+
+        let entries = entries.lock().unwrap();
+        let changes: Vec<_> = entries.iter().map(|e| (e.fd, e.old, e.new, e.reason.as_ref().map(|r| &r[..]))).collect();
+        let first = Registration { events: EPOLLIN, data: 1 };
+        let second = Registration { events: EPOLLIN | EPOLLOUT, data: 2 };
+        assert_eq!(changes, vec![(timerfd.0, None, Some(first), Some("timer armed")),
+                                 (timerfd.0, Some(first), Some(second), None),
+                                 (timerfd.0, Some(second), None, Some("timer cancelled"))]);
+    }
+
+    #[test]
+    fn journal_uses_the_epoll() {
+        use std::sync::{Arc, Mutex};
+
+        let epoll = Arc::new(EPoll::new().unwrap());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        assert!(timerfd >= 0);
+        let other = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        assert!(other >= 0);
+
+        // The journal queries the epoll, and follows every addition of `timerfd` with one of `other`.
+        {
+            let (weak, seen) = (Arc::downgrade(&epoll), seen.clone());
+            epoll.set_journal(move |entry| {
+                let epoll = weak.upgrade().unwrap();
+                seen.lock().unwrap().push((entry.fd, epoll.contains(entry.fd), epoll.len()));
+                if entry.fd == timerfd && entry.old.is_none() && entry.new.is_some() {
+                    epoll.add_raw(other, EPOLLIN, 0).unwrap();
+                }
+            });
+        }
+
+        epoll.add_raw(timerfd, EPOLLIN, 0).unwrap();
+        epoll.remove_raw(timerfd).unwrap();
+        epoll.remove_raw(other).unwrap();
+
+        assert_eq!(*seen.lock().unwrap(), [(timerfd, true, 1), (other, true, 2), (timerfd, false, 1), (other, false, 0)]);
+        epoll.clear_journal();
+        unsafe { libc::close(timerfd); libc::close(other); }
+    }
+
+    #[test]
+    fn data_for_registration() {
+        let epoll = EPoll::new().unwrap();
+
+        let timerfd = unsafe { timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        assert!(timerfd >= 0);
+        let timerfd = Fd(timerfd as RawFd);
+
+        assert_eq!(epoll.data_for(timerfd.0), None);
+        match epoll.set_data_for(timerfd.0, 1) {
+            Err(CtlError::NotRegistered) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+
+        epoll.add(&timerfd, EPOLLIN, 1312).unwrap();
+        assert_eq!(epoll.data_for(timerfd.0), Some(1312));
+
+        epoll.set_data_for(timerfd.0, 1313).unwrap();
+        assert_eq!(epoll.data_for(timerfd.0), Some(1313));
+
+        epoll.remove(&timerfd).unwrap();
+        assert_eq!(epoll.data_for(timerfd.0), None);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        fn send_and_sync<T: Send + Sync>() {}
+        send_and_sync::<EPoll>();
+
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let epoll = std::sync::Arc::new(EPoll::new().unwrap());
+
+        let waiter = {
+            let epoll = epoll.clone();
+            std::thread::spawn(move || epoll.wait_one(Timeout::Milliseconds(5000)).unwrap().map(|e| e.data))
+        };
+        epoll.add(&reader, EPOLLIN, 4).unwrap();
+
+        assert_eq!(waiter.join().unwrap(), Some(4));
+        assert_eq!(epoll.data_for(reader.as_raw_fd()), Some(4));
+    }
+
+    #[test]
+    fn explicit_close() {
+        EPoll::new().unwrap().close().unwrap();
+
+        // Way above any descriptor limit, so nothing else is closed by accident.
+        let bogus = unsafe { EPoll::from_raw_fd(1 << 30) };
+        assert_eq!(bogus.close().unwrap_err().raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn cloned_handles() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLOUT, 1).unwrap();
+
+        let waiter = epoll.try_clone().unwrap();
+        assert_ne!(waiter.as_raw_fd(), epoll.as_raw_fd());
+        assert_eq!(waiter.data_for(reader.as_raw_fd()), Some(1));
+
+        let waiting = std::thread::spawn(move || waiter.wait_one(Timeout::Milliseconds(5000)).unwrap().map(|e| e.data));
+        epoll.modify(&reader, EPOLLIN, 2).unwrap();
+        assert_eq!(waiting.join().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn nested_epolls() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let outer = EPoll::new().unwrap();
+        let inner = EPoll::new().unwrap();
+        inner.add(&reader, EPOLLIN, 1).unwrap();
+        outer.add_epoll(&inner, 2).unwrap();
+
+        assert_eq!(outer.wait_one(Timeout::Immediate).unwrap().map(|e| e.data), Some(2));
+        match inner.add_epoll(&outer, 3) {
+            Err(CtlError::WouldLoop) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn raw_registration() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let fd = reader.as_raw_fd();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add_raw(fd, EPOLLOUT, 1).unwrap();
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+
+        epoll.modify_raw(fd, EPOLLIN, 2).unwrap();
+        assert_eq!(epoll.wait_one(Timeout::Immediate).unwrap().map(|e| e.data), Some(2));
+
+        epoll.remove_raw(fd).unwrap();
+        assert!(!epoll.contains(fd));
+    }
+
+    #[test]
+    fn registration_table() {
+        let (reader, _writer) = testing::readable_pipe(b"").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        assert!(epoll.is_empty());
+
+        epoll.add(&reader, EPOLLIN, 9).unwrap();
+        assert!(epoll.contains(reader.as_raw_fd()));
+        assert_eq!(epoll.len(), 1);
+        assert_eq!(epoll.registrations().collect::<Vec<_>>(),
+                   vec![(reader.as_raw_fd(), Registration { events: EPOLLIN, data: 9 })]);
+
+        epoll.remove(&reader).unwrap();
+        assert!(!epoll.contains(reader.as_raw_fd()));
+        assert_eq!(epoll.len(), 0);
+    }
+
+    #[test]
+    fn disable_and_rearm() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.rearm(&reader, EPOLLIN, 3).err().unwrap();
+        epoll.add(&reader, EPOLLIN | EPOLLONESHOT, 3).unwrap();
+
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_some());
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+
+        epoll.rearm(&reader, EPOLLIN, 3).unwrap();
+        let previous = epoll.disable(&reader).unwrap();
+        assert_eq!(previous, Registration { events: EPOLLIN | EPOLLONESHOT, data: 3 });
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+
+        epoll.rearm(&reader, previous.events, previous.data).unwrap();
+        assert_eq!(epoll.wait_one(Timeout::Immediate).unwrap().map(|e| e.data), Some(3));
+    }
+
+    #[test]
+    fn disable_races_modify() {
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let epoll = Arc::new(EPoll::new().unwrap());
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+
+        let replaced = Arc::new(Mutex::new(Vec::new()));
+        {
+            let replaced = replaced.clone();
+            epoll.set_journal(move |entry| if entry.new.is_some_and(|new| new.events.is_empty()) {
+                replaced.lock().unwrap().push(entry.old.unwrap());
+            });
+        }
+
+        let fd = reader.as_raw_fd();
+        let modifier = {
+            let epoll = epoll.clone();
+            thread::spawn(move || for data in 1..10_000 { epoll.modify_raw(fd, EPOLLIN, data).unwrap() })
+        };
+        let mut returned: Vec<_> = (0..10_000).map(|_| epoll.disable(&reader).unwrap()).collect();
+        modifier.join().unwrap();
+
+        // Each disable returns the registration it replaced.
+        let mut replaced = replaced.lock().unwrap().clone();
+        returned.sort_by_key(|registration| (registration.data, registration.events.bits()));
+        replaced.sort_by_key(|registration| (registration.data, registration.events.bits()));
+        assert_eq!(returned, replaced);
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Crash-surviving copies of the registration table.
+//!
+//! A `Mirror` keeps the registrations of an epoll in a memory-mapped file.
+//! Changes land in the kernel's page cache as they are made, so the file
+//! describes what the process was watching even if it dies abruptly.
+//! `read` parses such a file afterwards.
+//!
+//! The mirror is fed through the journal hook, so it replaces any journal
+//! the epoll had.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let mut epoll = EPoll::new()?;
+//! mirror::attach(&epoll, "/run/mydaemon/epoll.mirror", 1024)?;
+//!
+//! // ... after a crash:
+//! for entry in mirror::read("/run/mydaemon/epoll.mirror")?.entries {
+//!     println!("fd {} watched {:?} since {:?}", entry.fd, entry.registration.events, entry.changed);
+//! }
+//! ```
+
+use super::*;
+use std::fs::{self, OpenOptions};
+use std::mem;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{self, AtomicI32};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: [u8; 8] = *b"EPOLLMIR";
+const OVERFLOWED: u32 = 1;
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    capacity: u32,
+    flags: u32,
+}
+
+/// A slot of the file; free slots have a negative descriptor.
+#[repr(C)]
+struct Record {
+    fd: i32,
+    events: u32,
+    data: u64,
+    secs: u64,
+    nanos: u32,
+    _reserved: u32,
+}
+
+/// A registration table kept in a memory-mapped file.
+pub struct Mirror {
+    map: *mut u8,
+    len: usize,
+    capacity: usize,
+    slots: HashMap<RawFd, usize>,
+    free: Vec<usize>,
+}
+
+// The mapping is owned exclusively by the mirror.
+unsafe impl Send for Mirror {}
+
+impl Mirror {
+    /// Creates (or truncates) the file at `path`, with room for `capacity` registrations.
+    ///
+    /// Registrations beyond the capacity are not mirrored, and the file is marked as overflowed.
+    pub fn create<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<Mirror> {
+        let len = match capacity.checked_mul(mem::size_of::<Record>()) {
+            Some(records) if capacity <= u32::MAX as usize => mem::size_of::<Header>() + records,
+            _ => return Err(Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(len as u64)?;
+
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                       file.as_raw_fd(), 0)
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        let mirror = Mirror {
+            map: map as *mut u8,
+            len,
+            capacity,
+            slots: HashMap::new(),
+            free: (0..capacity).rev().collect(),
+        };
+
+        unsafe {
+            ptr::write(mirror.header(), Header { magic: MAGIC, capacity: capacity as u32, flags: 0 });
+            for slot in 0..capacity {
+                (*mirror.record_at(slot)).fd = -1;
+            }
+        }
+
+        Ok(mirror)
+    }
+
+    /// Applies a change to the mirrored table.
+    pub fn record(&mut self, entry: &JournalEntry) {
+        let registration = match entry.new {
+            Some(registration) => registration,
+            None => {
+                if let Some(slot) = self.slots.remove(&entry.fd) {
+                    self.fd_at(slot).store(-1, Ordering::Release);
+                    self.free.push(slot);
+                }
+                return;
+            },
+        };
+
+        let slot = match self.slots.get(&entry.fd) {
+            Some(&slot) => slot,
+            None => match self.free.pop() {
+                Some(slot) => {
+                    self.slots.insert(entry.fd, slot);
+                    slot
+                },
+                None => {
+                    unsafe { (*self.header()).flags |= OVERFLOWED };
+                    return;
+                },
+            },
+        };
+
+        let changed = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        // The slot is freed first and the descriptor is written last, so that
+        // a crash mid-update leaves either the old record or a free slot, never
+        // a mix. The fences keep the other fields' stores between the two.
+        let fd = self.fd_at(slot);
+        fd.store(-1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        unsafe {
+            let record = self.record_at(slot);
+            (*record).events = registration.events.raw_bits();
+            (*record).data = registration.data;
+            (*record).secs = changed.as_secs();
+            (*record).nanos = changed.subsec_nanos();
+        }
+        fd.store(entry.fd, Ordering::Release);
+    }
+
+    /// The amount of registrations the file has room for.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn header(&self) -> *mut Header {
+        self.map as *mut Header
+    }
+
+    fn record_at(&self, slot: usize) -> *mut Record {
+        debug_assert!(slot < self.capacity);
+        unsafe { (self.map.add(mem::size_of::<Header>()) as *mut Record).add(slot) }
+    }
+
+    /// The descriptor of a slot, which publishes the rest of its record.
+    fn fd_at(&self, slot: usize) -> &AtomicI32 {
+        // `AtomicI32` has the layout of an `i32`.
+        unsafe { &*(ptr::addr_of_mut!((*self.record_at(slot)).fd) as *const AtomicI32) }
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len); }
+    }
+}
+
+/// Mirrors the registrations of `epoll` into a new file at `path`,
+/// starting with the ones it already has.
+///
+/// The mirror is installed as the epoll's journal.
+pub fn attach<P: AsRef<Path>>(epoll: &EPoll, path: P, capacity: usize) -> io::Result<()> {
+    let mut mirror = Mirror::create(path, capacity)?;
+
+    // Holding the table while installing the journal keeps changes from slipping in between.
+    let mut journal = epoll.journal();
+    let mut table = epoll.table();
+    let time = SystemTime::now();
+    for (&fd, &registration) in &table.registrations {
+        mirror.record(&JournalEntry { time, fd, old: None, new: Some(registration), reason: None });
+    }
+
+    // Changes still queued for the previous journal are in the copy already.
+    table.pending.clear();
+    table.journaled = true;
+    *journal = Some(Box::new(move |entry| mirror.record(entry)));
+
+    Ok(())
+}
+
+/// A registration read back from a mirror file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MirroredRegistration {
+    pub fd: RawFd,
+    pub registration: Registration,
+
+    /// When the registration was last changed.
+    pub changed: SystemTime,
+}
+
+/// The contents of a mirror file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The mirrored registrations, in no particular order.
+    pub entries: Vec<MirroredRegistration>,
+
+    /// Whether some registrations did not fit in the file, and are missing from `entries`.
+    pub overflowed: bool,
+}
+
+/// Parses a mirror file.
+///
+/// Fails with `InvalidData` if the file is not a mirror, is truncated, or
+/// holds timestamps out of range.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+    let bytes = fs::read(path)?;
+    let invalid = || Error::new(io::ErrorKind::InvalidData, "not a registration mirror");
+
+    let header_len = mem::size_of::<Header>();
+    if bytes.len() < header_len || bytes[..8] != MAGIC {
+        return Err(invalid());
+    }
+
+    let u32_at = |offset: usize| {
+        let mut field = [0u8; 4];
+        field.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_ne_bytes(field)
+    };
+    let u64_at = |offset: usize| {
+        let mut field = [0u8; 8];
+        field.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_ne_bytes(field)
+    };
+
+    let capacity = u32_at(8) as usize;
+    let flags = u32_at(12);
+    let record_len = mem::size_of::<Record>();
+    let records_len = capacity.checked_mul(record_len).and_then(|len| len.checked_add(header_len));
+    if records_len.is_none_or(|len| bytes.len() < len) {
+        return Err(invalid());
+    }
+
+    let mut entries = Vec::new();
+    for slot in 0..capacity {
+        let offset = header_len + slot * record_len;
+        let fd = u32_at(offset) as i32;
+        if fd < 0 {
+            continue;
+        }
+
+        let nanos = u32_at(offset + 24);
+        if nanos >= 1_000_000_000 {
+            return Err(invalid());
+        }
+        let changed = UNIX_EPOCH.checked_add(Duration::new(u64_at(offset + 16), nanos)).ok_or_else(invalid)?;
+
+        entries.push(MirroredRegistration {
+            fd,
+            registration: Registration {
+                events: EventType::from_bits_retain(u32_at(offset + 4)),
+                data: u64_at(offset + 8),
+            },
+            changed,
+        });
+    }
+
+    Ok(Snapshot { entries, overflowed: flags & OVERFLOWED != 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    fn mirror_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("epoll-mirror-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn survives_the_epoll() {
+        let path = mirror_path("survives");
+        let (first, _w1) = testing::readable_pipe(b"").unwrap();
+        let (second, _w2) = testing::readable_pipe(b"").unwrap();
+        let (third, _w3) = testing::readable_pipe(b"").unwrap();
+
+        {
+            let epoll = EPoll::new().unwrap();
+            epoll.add(&first, EPOLLIN, 1).unwrap();
+            attach(&epoll, &path, 4).unwrap();
+
+            epoll.add(&second, EPOLLIN | EPOLLET, 2).unwrap();
+            epoll.add(&third, EPOLLIN, 3).unwrap();
+            epoll.modify(&third, EPOLLIN | EPOLLONESHOT, 33).unwrap();
+            epoll.remove(&second).unwrap();
+        }
+
+        let mut snapshot = read(&path).unwrap();
+        snapshot.entries.sort_by_key(|e| e.registration.data);
+        fs::remove_file(&path).unwrap();
+
+        let registrations: Vec<_> = snapshot.entries.iter().map(|e| (e.fd, e.registration)).collect();
+        assert_eq!(registrations, vec![
+            (first.as_raw_fd(), Registration { events: EPOLLIN, data: 1 }),
+            (third.as_raw_fd(), Registration { events: EPOLLIN | EPOLLONESHOT, data: 33 }),
+        ]);
+        assert!(!snapshot.overflowed);
+        assert!(snapshot.entries[1].changed >= snapshot.entries[0].changed);
+    }
+
+    #[test]
+    fn overflow_is_flagged() {
+        let path = mirror_path("overflow");
+        let (first, _w1) = testing::readable_pipe(b"").unwrap();
+        let (second, _w2) = testing::readable_pipe(b"").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        attach(&epoll, &path, 1).unwrap();
+        epoll.add(&first, EPOLLIN, 1).unwrap();
+        epoll.add(&second, EPOLLIN, 2).unwrap();
+
+        let snapshot = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(snapshot.entries.len(), 1);
+        assert!(snapshot.overflowed);
+        assert!(read("/proc/self/status").is_err());
+    }
+
+    #[test]
+    fn corrupted_files() {
+        let path = mirror_path("corrupted");
+        let (file, _writer) = testing::readable_pipe(b"").unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&file, EPOLLIN, 1).unwrap();
+        attach(&epoll, &path, 1).unwrap();
+        let valid = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let record = mem::size_of::<Header>();
+        let corrupt = |offset: usize, field: &[u8]| {
+            let mut bytes = valid.clone();
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            fs::write(&path, bytes).unwrap();
+            let kind = read(&path).unwrap_err().kind();
+            fs::remove_file(&path).unwrap();
+            kind
+        };
+
+        assert_eq!(corrupt(record + 24, &1_000_000_000u32.to_ne_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(record + 16, &u64::MAX.to_ne_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(8, &u32::MAX.to_ne_bytes()), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Non-blocking POSIX message queues of fixed-size messages.
+//!
+//! On Linux a message queue is a descriptor: it is readable (`EPOLLIN`) while
+//! messages are queued, and writable (`EPOLLOUT`) while it isn't full. Queues
+//! are named, so unrelated processes can open the same one.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let jobs = MessageQueue::<[u8; 64]>::create("/jobs", 16)?;
+//! epoll.add(&jobs, EPOLLIN, JOBS)?;
+//!
+//! // In another process:
+//! MessageQueue::<[u8; 64]>::open("/jobs")?.send(&job, 0)?;
+//!
+//! // Once `JOBS` is reported:
+//! while let Some((job, _priority)) = jobs.receive()? {
+//!     run(job);
+//! }
+//! ```
+
+use super::*;
+use std::ffi::CString;
+use std::marker::PhantomData;
+
+/// Types that can be sent through a `MessageQueue` as their bytes.
+///
+/// # Safety
+///
+/// Implementors must have no padding, no pointers, and no invalid bit patterns;
+/// e.g. `#[repr(C)]` structures of integers.
+pub unsafe trait Message: Copy {}
+
+unsafe impl Message for u8 {}
+unsafe impl Message for u16 {}
+unsafe impl Message for u32 {}
+unsafe impl Message for u64 {}
+unsafe impl Message for usize {}
+unsafe impl Message for i8 {}
+unsafe impl Message for i16 {}
+unsafe impl Message for i32 {}
+unsafe impl Message for i64 {}
+unsafe impl Message for isize {}
+unsafe impl<T: Message, const N: usize> Message for [T; N] {}
+
+/// A message queue of `T`s, opened non-blocking and close-on-exec for both
+/// sending and receiving.
+#[derive(Debug)]
+pub struct MessageQueue<T> {
+    fd: OwnedFd,
+    messages: PhantomData<fn(T) -> T>,
+}
+
+impl<T: Message> MessageQueue<T> {
+    /// Opens the queue `name`, e.g. `"/jobs"`, creating it with room for
+    /// `capacity` messages if it doesn't exist.
+    ///
+    /// Fails with `EINVAL` if the queue exists with messages of another size.
+    pub fn create(name: &str, capacity: usize) -> io::Result<MessageQueue<T>> {
+        let mut attr: libc::mq_attr = unsafe { mem::zeroed() };
+        attr.mq_maxmsg = capacity as _;
+        attr.mq_msgsize = mem::size_of::<T>() as _;
+
+        MessageQueue::open_with(name, libc::O_CREAT, &attr)
+    }
+
+    /// Opens the existing queue `name`.
+    ///
+    /// Fails with `ENOENT` if there is no such queue, and with `EINVAL` if
+    /// its messages are of another size.
+    pub fn open(name: &str) -> io::Result<MessageQueue<T>> {
+        MessageQueue::open_with(name, 0, ptr::null())
+    }
+
+    fn open_with(name: &str, flags: libc::c_int, attr: *const libc::mq_attr) -> io::Result<MessageQueue<T>> {
+        let name = CString::new(name)
+            .map_err(|_| Error::new(io::ErrorKind::InvalidInput, "name contains a nul byte"))?;
+        let flags = flags | libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC;
+
+        let fd = unsafe { libc::mq_open(name.as_ptr(), flags, 0o600 as libc::mode_t, attr) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let queue = MessageQueue { fd: unsafe { OwnedFd::from_raw_fd(fd) }, messages: PhantomData };
+        if queue.attributes()?.mq_msgsize as usize != mem::size_of::<T>() {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(queue)
+    }
+
+    /// Queues `message`, ahead of the messages of lower priorities.
+    ///
+    /// Fails with `WouldBlock` if the queue is full.
+    pub fn send(&self, message: &T, priority: u32) -> io::Result<()> {
+        let rc = unsafe {
+            libc::mq_send(self.fd.as_raw_fd(), message as *const T as *const libc::c_char, mem::size_of::<T>(), priority)
+        };
+
+        if rc < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Takes the oldest message of the highest priority, along with its
+    /// priority, or returns `None` if the queue is empty.
+    ///
+    /// Fails with `InvalidData` if the message is shorter than a `T`; it is
+    /// taken off the queue regardless.
+    pub fn receive(&self) -> io::Result<Option<(T, u32)>> {
+        let mut message = mem::MaybeUninit::<T>::uninit();
+        let mut priority = 0;
+        let rc = unsafe {
+            libc::mq_receive(self.fd.as_raw_fd(), message.as_mut_ptr() as *mut libc::c_char, mem::size_of::<T>(), &mut priority)
+        };
+
+        if rc < 0 {
+            let error = Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+
+            return Err(error);
+        }
+
+        // The queue also takes shorter messages, e.g. from a raw `mq_send`.
+        if rc as usize != mem::size_of::<T>() {
+            return Err(Error::new(io::ErrorKind::InvalidData, "message is shorter than its type"));
+        }
+
+        Ok(Some((unsafe { message.assume_init() }, priority)))
+    }
+
+    /// The amount of queued messages.
+    pub fn len(&self) -> io::Result<usize> {
+        Ok(self.attributes()?.mq_curmsgs as usize)
+    }
+
+    /// Whether no messages are queued.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The amount of messages the queue has room for.
+    pub fn capacity(&self) -> io::Result<usize> {
+        Ok(self.attributes()?.mq_maxmsg as usize)
+    }
+
+    fn attributes(&self) -> io::Result<libc::mq_attr> {
+        let mut attr: libc::mq_attr = unsafe { mem::zeroed() };
+
+        if unsafe { libc::mq_getattr(self.fd.as_raw_fd(), &mut attr) } < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(attr)
+        }
+    }
+}
+
+/// Removes the queue `name`; it is destroyed once every descriptor of it is closed.
+pub fn unlink(name: &str) -> io::Result<()> {
+    let name = CString::new(name)
+        .map_err(|_| Error::new(io::ErrorKind::InvalidInput, "name contains a nul byte"))?;
+
+    if unsafe { libc::mq_unlink(name.as_ptr()) } < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(())
+    }
+}
+
+impl<T> AsRawFd for MessageQueue<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl<T> AsFd for MessageQueue<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_by_priority() {
+        let name = format!("/epoll-mqueue-{}", ::std::process::id());
+        let queue = MessageQueue::<[u32; 2]>::create(&name, 2).unwrap();
+        assert_eq!(queue.capacity().unwrap(), 2);
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&queue, EPOLLIN, 0).unwrap();
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+        assert_eq!(queue.receive().unwrap(), None);
+
+        let sender = MessageQueue::<[u32; 2]>::open(&name).unwrap();
+        sender.send(&[1, 2], 0).unwrap();
+        sender.send(&[3, 4], 7).unwrap();
+        assert_eq!(sender.send(&[5, 6], 0).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_some());
+        assert_eq!(queue.len().unwrap(), 2);
+
+        assert_eq!(queue.receive().unwrap(), Some(([3, 4], 7)));
+        assert_eq!(queue.receive().unwrap(), Some(([1, 2], 0)));
+        assert!(queue.is_empty().unwrap());
+
+        unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn checks_message_size() {
+        let name = format!("/epoll-mqueue-size-{}", ::std::process::id());
+        let _queue = MessageQueue::<u64>::create(&name, 1).unwrap();
+
+        assert_eq!(MessageQueue::<u32>::open(&name).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+        unlink(&name).unwrap();
+        assert_eq!(MessageQueue::<u64>::open(&name).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn rejects_short_messages() {
+        let name = format!("/epoll-mqueue-short-{}", ::std::process::id());
+        let queue = MessageQueue::<u64>::create(&name, 2).unwrap();
+        unlink(&name).unwrap();
+
+        let short = [7u8; 4];
+        assert_eq!(unsafe { libc::mq_send(queue.as_raw_fd(), short.as_ptr() as *const libc::c_char, short.len(), 0) }, 0);
+        queue.send(&42, 0).unwrap();
+
+        assert_eq!(queue.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(queue.receive().unwrap(), Some((42, 0)));
+    }
+}
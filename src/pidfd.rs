@@ -0,0 +1,150 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watching processes exit, through a pidfd.
+//!
+//! A pidfd is readable once its process exits. Unlike `SIGCHLD`, it names the
+//! process, and can't be confused with a new process that reused the pid.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let child = Command::new("worker").spawn()?;
+//! let pidfd = PidFd::open(child.id())?;
+//! epoll.add(&pidfd, EPOLLIN, WORKER)?;
+//!
+//! // Once `WORKER` is reported:
+//! let status = pidfd.exit_status()?.expect("the worker exited");
+//! child.wait()?; // Reaps it, returning the same status.
+//! ```
+
+use super::*;
+use std::mem;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+/// A descriptor that is readable once its process exits.
+#[derive(Debug)]
+pub struct PidFd {
+    fd: OwnedFd,
+}
+
+impl PidFd {
+    /// Opens a pidfd for the process `pid`, e.g. `child.id()`.
+    ///
+    /// Requires Linux 5.3 or newer.
+    pub fn open(pid: u32) -> io::Result<PidFd> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+
+        if fd < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(PidFd { fd: unsafe { OwnedFd::from_raw_fd(fd as RawFd) } })
+        }
+    }
+
+    /// Reaps the process and returns its exit status, or `None` if it is still running.
+    ///
+    /// Only children of the calling process can be waited for, and only once;
+    /// fails with `ECHILD` otherwise.
+    pub fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        self.waitid(libc::WEXITED | libc::WNOHANG)
+    }
+
+    /// Returns the exit status of the process, or `None` if it is still running,
+    /// without reaping it; e.g. to leave that to `Child::wait`.
+    ///
+    /// Fails with `ECHILD` for processes that aren't children of the calling
+    /// process, or were already reaped.
+    pub fn exit_status(&self) -> io::Result<Option<ExitStatus>> {
+        self.waitid(libc::WEXITED | libc::WNOHANG | libc::WNOWAIT)
+    }
+
+    fn waitid(&self, options: libc::c_int) -> io::Result<Option<ExitStatus>> {
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let rc = unsafe {
+            libc::waitid(libc::P_PIDFD, self.fd.as_raw_fd() as libc::id_t, &mut info, options)
+        };
+
+        if rc < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // Without a state change to report, waitid leaves the pid at zero.
+        if unsafe { info.si_pid() } == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(exit_status(&info)))
+    }
+}
+
+/// Encodes the status of a `waitid` as `waitpid` would, which `ExitStatus` expects.
+fn exit_status(info: &libc::siginfo_t) -> ExitStatus {
+    let status = unsafe { info.si_status() };
+
+    ExitStatus::from_raw(match info.si_code {
+        libc::CLD_EXITED => (status & 0xff) << 8,
+        libc::CLD_DUMPED => status | 0x80,
+        _ => status,
+    })
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    #[allow(clippy::zombie_processes)] // Reaped through the pidfd.
+    fn readable_once_exited() {
+        let child = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        let pidfd = PidFd::open(child.id()).unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&pidfd, EPOLLIN, 0).unwrap();
+        assert!(epoll.wait_one(Timeout::Milliseconds(5000)).unwrap().is_some());
+
+        assert_eq!(pidfd.try_wait().unwrap().and_then(|status| status.code()), Some(3));
+        assert_eq!(pidfd.try_wait().unwrap_err().raw_os_error(), Some(libc::ECHILD));
+    }
+
+    #[test]
+    fn status_without_reaping() {
+        let mut child = Command::new("sh").args(["-c", "kill -9 $$"]).spawn().unwrap();
+        let pidfd = PidFd::open(child.id()).unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&pidfd, EPOLLIN, 0).unwrap();
+        assert!(epoll.wait_one(Timeout::Milliseconds(5000)).unwrap().is_some());
+
+        let status = pidfd.exit_status().unwrap().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert_eq!(pidfd.exit_status().unwrap(), Some(status));
+        assert_eq!(child.wait().unwrap(), status);
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Non-blocking pipes.
+//!
+//! Both ends are created with `O_NONBLOCK | O_CLOEXEC` and implement `AsRawFd`,
+//! so they can be registered on an `EPoll` as-is.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let (mut reader, mut writer) = Pipe::new()?.split();
+//! epoll.add(&reader, EPOLLIN, 0)?;
+//!
+//! writer.write_all(b"wake up")?;
+//! ```
+
+use super::*;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::ptr;
+
+/// A non-blocking pipe.
+pub struct Pipe {
+    reader: PipeReader,
+    writer: PipeWriter,
+}
+
+/// The reading end of a pipe.
+pub struct PipeReader(File);
+
+/// The writing end of a pipe.
+pub struct PipeWriter(File);
+
+impl Pipe {
+    /// Creates a new pipe.
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+
+        let rc = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+
+        if rc < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(unsafe {
+                Pipe {
+                    reader: PipeReader(File::from_raw_fd(fds[0])),
+                    writer: PipeWriter(File::from_raw_fd(fds[1])),
+                }
+            })
+        }
+    }
+
+    /// Returns the reading end.
+    pub fn reader(&self) -> &PipeReader {
+        &self.reader
+    }
+
+    /// Returns the writing end.
+    pub fn writer(&self) -> &PipeWriter {
+        &self.writer
+    }
+
+    /// Separates the pipe into its two ends.
+    pub fn split(self) -> (PipeReader, PipeWriter) {
+        (self.reader, self.writer)
+    }
+
+    /// Returns the capacity of the pipe in bytes.
+    pub fn capacity(&self) -> io::Result<usize> {
+        capacity(self.reader.as_raw_fd())
+    }
+
+    /// Sets the capacity of the pipe (`F_SETPIPE_SZ`).
+    ///
+    /// The kernel rounds the size up to a power-of-two number of pages;
+    /// the actual capacity is returned.
+    pub fn set_capacity(&self, size: usize) -> io::Result<usize> {
+        set_capacity(self.reader.as_raw_fd(), size)
+    }
+}
+
+impl PipeReader {
+    /// Returns the capacity of the pipe in bytes.
+    pub fn capacity(&self) -> io::Result<usize> {
+        capacity(self.as_raw_fd())
+    }
+
+    /// Sets the capacity of the pipe, returning the actual capacity.
+    pub fn set_capacity(&self, size: usize) -> io::Result<usize> {
+        set_capacity(self.as_raw_fd(), size)
+    }
+
+    /// Moves up to `len` bytes from the pipe into `file` without copying them
+    /// through userspace, returning the amount moved.
+    pub fn splice_to<T: AsRawFd + ?Sized>(&self, file: &T, len: usize) -> io::Result<usize> {
+        splice(self.as_raw_fd(), file.as_raw_fd(), len)
+    }
+}
+
+impl PipeWriter {
+    /// Returns the capacity of the pipe in bytes.
+    pub fn capacity(&self) -> io::Result<usize> {
+        capacity(self.as_raw_fd())
+    }
+
+    /// Sets the capacity of the pipe, returning the actual capacity.
+    pub fn set_capacity(&self, size: usize) -> io::Result<usize> {
+        set_capacity(self.as_raw_fd(), size)
+    }
+
+    /// Moves up to `len` bytes from `file` into the pipe without copying them
+    /// through userspace, returning the amount moved.
+    pub fn splice_from<T: AsRawFd + ?Sized>(&self, file: &T, len: usize) -> io::Result<usize> {
+        splice(file.as_raw_fd(), self.as_raw_fd(), len)
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsFd for PipeReader {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl AsFd for PipeWriter {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl AsRawFd for PipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsRawFd for PipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl From<OwnedFd> for PipeReader {
+    /// Adopts the reading end of a pipe, e.g. an inherited one.
+    fn from(fd: OwnedFd) -> PipeReader {
+        PipeReader(File::from(fd))
+    }
+}
+
+impl From<OwnedFd> for PipeWriter {
+    /// Adopts the writing end of a pipe, e.g. an inherited one.
+    fn from(fd: OwnedFd) -> PipeWriter {
+        PipeWriter(File::from(fd))
+    }
+}
+
+fn capacity(fd: RawFd) -> io::Result<usize> {
+    let rc = unsafe { libc::fcntl(fd, libc::F_GETPIPE_SZ) };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(rc as usize)
+    }
+}
+
+fn set_capacity(fd: RawFd, size: usize) -> io::Result<usize> {
+    let size = if size >= i32::MAX as usize { i32::MAX } else { size as i32 };
+
+    let rc = unsafe { libc::fcntl(fd, libc::F_SETPIPE_SZ, size) };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(rc as usize)
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let rc = unsafe {
+        libc::splice(from,
+                     ptr::null_mut(),
+                     to,
+                     ptr::null_mut(),
+                     len,
+                     libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(rc as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_blocking_and_pollable() {
+        let (mut reader, mut writer) = Pipe::new().unwrap().split();
+
+        let mut buffer = [0u8; 4];
+        assert_eq!(reader.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+        writer.write_all(b"ping").unwrap();
+
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 4);
+    }
+
+    #[test]
+    fn capacity_and_splice() {
+        let first = Pipe::new().unwrap();
+        let second = Pipe::new().unwrap();
+
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        assert_eq!(first.set_capacity(page).unwrap(), page);
+        assert_eq!(first.capacity().unwrap(), page);
+
+        let (first_reader, mut first_writer) = first.split();
+        let (mut second_reader, second_writer) = second.split();
+
+        first_writer.write_all(b"splice").unwrap();
+        assert_eq!(first_reader.splice_to(&second_writer, 64).unwrap(), 6);
+
+        let mut buffer = [0u8; 6];
+        second_reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"splice");
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Register-then-drop privilege separation.
+//!
+//! Permissions are checked when a descriptor is opened, not when it is polled.
+//! A daemon can therefore open its privileged files (raw sockets, low ports),
+//! register them on an `EPoll`, and only then give up its privileges.
+//! The registrations remain in effect after the drop.
+//!
+//! `drop_privileges` leaves nothing to regain: besides the user and groups it
+//! clears the process's capabilities, including the bounding set, and sets
+//! `no_new_privs` so that executing a set-user-ID binary can't restore them.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let listener = TcpListener::bind("0.0.0.0:80")?;
+//!
+//! let epoll = EPoll::new()?;
+//! privilege::register_all(&epoll, &[&listener], EPOLLIN)?;
+//! privilege::drop_privileges(nobody_uid, nobody_gid)?;
+//!
+//! // From here on the process runs unprivileged.
+//! let event_count = epoll.wait(&mut events, Timeout::Indefinite)?;
+//! ```
+
+use super::*;
+
+/// Registers every file in `files` onto `epoll` with the same event mask.
+///
+/// Each file is identified by its descriptor in the data field.
+/// Stops at the first failure.
+pub fn register_all(epoll: &EPoll, files: &[&dyn AsFd], events: EventType) -> io::Result<()> {
+    for file in files {
+        epoll.add(*file, events, file.as_fd().as_raw_fd() as u64)?;
+    }
+
+    Ok(())
+}
+
+/// Permanently switches the process to `uid` and `gid`, without capabilities.
+///
+/// Supplementary groups are cleared first and the group is changed before the user,
+/// since changing the group requires the privileges that changing the user gives up.
+/// The bounding set is cleared while the process is still root, as that takes
+/// `CAP_SETPCAP`; the other capability sets are cleared after the switch.
+///
+/// When dropping away from root, the function verifies that root cannot be
+/// regained, and aborts the process if it can: a process that is still root
+/// must not go on believing it isn't.
+pub fn drop_privileges(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    if unsafe { libc::setgroups(0, std::ptr::null()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    if unsafe { libc::geteuid() } == 0 {
+        drop_bounding_set()?;
+    }
+
+    if unsafe { libc::setgid(gid) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    if unsafe { libc::setuid(uid) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        unsafe { libc::abort() };
+    }
+
+    clear_capabilities()?;
+
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Drops every capability the kernel knows of from the bounding set.
+fn drop_bounding_set() -> io::Result<()> {
+    // Reading a capability past the last one the kernel knows fails with `EINVAL`.
+    let mut capability = 0;
+    while unsafe { libc::prctl(libc::PR_CAPBSET_READ, capability, 0, 0, 0) } >= 0 {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, capability, 0, 0, 0) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        capability += 1;
+    }
+
+    Ok(())
+}
+
+/// `struct __user_cap_header_struct`.
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// `_LINUX_CAPABILITY_VERSION_3`, whose sets take two `CapData`.
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Clears the effective, permitted and inheritable capabilities of the calling thread.
+fn clear_capabilities() -> io::Result<()> {
+    let mut header = CapHeader { version: CAPABILITY_VERSION_3, pid: 0 };
+    let data = [CapData::default(); 2];
+
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::panic;
+
+    /// The uid and gid of `nobody`.
+    const NOBODY: u32 = 65534;
+
+    #[test]
+    fn registrations_survive_the_drop() {
+        // Dropping privileges needs them in the first place.
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+
+        let secret = ::std::env::temp_dir().join(format!("epoll-privilege-{}", ::std::process::id()));
+        OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&secret).unwrap();
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let reader = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+        let writer = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+
+        let epoll = EPoll::new().unwrap();
+        register_all(&epoll, &[&reader], EPOLLIN).unwrap();
+
+        // The drop changes the whole process, so it happens in a child.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+
+        if pid == 0 {
+            let dropped = panic::catch_unwind(|| {
+                drop_privileges(NOBODY, NOBODY).unwrap();
+                assert_eq!(unsafe { (libc::getuid(), libc::getgid()) }, (NOBODY, NOBODY));
+
+                assert_eq!(unsafe { libc::write(writer.as_raw_fd(), b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+                let mut events = [Event::default(); 1];
+                assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+                assert_eq!({ events[0].data }, reader.as_raw_fd() as u64);
+
+                let reopened = OpenOptions::new().read(true).open(&secret);
+                assert_eq!(reopened.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+                // No capability is left, nor a way to gain one.
+                let status = fs::read_to_string("/proc/self/status").unwrap();
+                for set in ["CapInh", "CapPrm", "CapEff", "CapBnd", "CapAmb"] {
+                    let line = status.lines().find(|line| line.starts_with(set)).unwrap();
+                    assert_eq!(u64::from_str_radix(line[set.len() + 1..].trim(), 16).unwrap(), 0, "{}", line);
+                }
+                assert_eq!(unsafe { libc::prctl(libc::PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) }, 1);
+            });
+            unsafe { libc::_exit(if dropped.is_ok() { 0 } else { 1 }) };
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        fs::remove_file(&secret).unwrap();
+
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        assert_eq!(unsafe { (libc::getuid(), libc::getgid()) }, (0, 0));
+    }
+}
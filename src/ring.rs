@@ -0,0 +1,658 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded ring-buffer channels of fixed-size records, from producer threads to an epoll.
+//!
+//! The records live in an anonymous memory mapping shared by both ends, and are
+//! passed through it without locks. The receiving end is readable whenever records
+//! are queued, but only the send that makes the ring non-empty signals it; sends
+//! that find records already queued cost no system call. On the other side,
+//! `recv_batch` takes everything queued at once, so the loop pays a single read per batch.
+//!
+//! `channel` creates a ring for several producers, which claim room in it with
+//! atomic operations; `single_producer` creates one for a single producer,
+//! which doesn't need them. Capacities are rounded up to a power of two.
+//!
+//! A full ring pushes back on its producers: `try_send` hands the record back
+//! at once, `send` blocks until there is room, and `send_timeout` blocks up to
+//! a timeout. `stats` tells how deep the queue is, and how often it was full.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let (sender, mut receiver) = ring::channel::<Quote>(4096)?;
+//! epoll.add(&receiver, EPOLLIN, QUOTES)?;
+//!
+//! // On a producer thread:
+//! if let Err(TrySendError::Full(quote)) = sender.try_send(quote) {
+//!     dropped(quote);
+//! }
+//!
+//! // On the loop, once `QUOTES` is reported:
+//! receiver.recv_batch(&mut quotes)?;
+//! ```
+
+use super::*;
+use counter::Counter;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::thread;
+
+/// Keeps a field on a cache line of its own, so the producers and the receiver
+/// don't contend over the fields they don't share.
+#[repr(C, align(64))]
+struct Line<T>(T);
+
+/// The start of the mapping. It is followed by the records and, when there are
+/// several producers, by the sequences of the records.
+#[repr(C)]
+struct Header {
+    /// The position of the next record to take; only the receiver advances it.
+    head: Line<AtomicUsize>,
+
+    /// The position of the next record to send.
+    tail: Line<AtomicUsize>,
+
+    /// Whether the receiver was signaled since it last took the records.
+    signaled: Line<AtomicBool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Producers {
+    Single,
+    Many,
+}
+
+struct Ring<T> {
+    map: *mut u8,
+    len: usize,
+
+    /// The offsets of the records and of their sequences in the mapping.
+    records: usize,
+    sequences: usize,
+
+    /// The capacity minus one; positions are taken modulo the capacity by masking.
+    mask: usize,
+    producers: Producers,
+    signal: Counter,
+
+    /// Set once the receiver is dropped.
+    closed: AtomicBool,
+    /// The amount of records turned away, or waited for room, because the ring was full.
+    full: AtomicU64,
+    marker: PhantomData<T>,
+}
+
+// The records are only moved through the mapping, which the ring owns.
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T: Copy> Ring<T> {
+    fn new(capacity: usize, producers: Producers) -> io::Result<Ring<T>> {
+        let invalid = || Error::from_raw_os_error(libc::EINVAL);
+
+        // The mapping is page-aligned, and so are the records within it at most.
+        if capacity == 0 || mem::align_of::<T>() > 4096 {
+            return Err(invalid());
+        }
+
+        let capacity = capacity.checked_next_power_of_two().ok_or_else(invalid)?;
+        let records = align_up(mem::size_of::<Header>(), mem::align_of::<T>()).ok_or_else(invalid)?;
+        let sequences = capacity.checked_mul(mem::size_of::<T>())
+            .and_then(|len| len.checked_add(records))
+            .and_then(|end| align_up(end, mem::align_of::<AtomicUsize>()))
+            .ok_or_else(invalid)?;
+        let len = match producers {
+            Producers::Single => sequences,
+            Producers::Many => capacity.checked_mul(mem::size_of::<AtomicUsize>())
+                .and_then(|len| len.checked_add(sequences))
+                .ok_or_else(invalid)?,
+        };
+
+        let signal = Counter::new()?;
+
+        // Anonymous mappings are zeroed: the ring starts empty and unsignaled,
+        // and no sequence marks its position as written.
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Ring {
+            map: map as *mut u8,
+            len,
+            records,
+            sequences,
+            mask: capacity - 1,
+            producers,
+            signal,
+            closed: AtomicBool::new(false),
+            full: AtomicU64::new(0),
+            marker: PhantomData,
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.map as *const Header) }
+    }
+
+    fn record_at(&self, position: usize) -> *mut T {
+        unsafe { (self.map.add(self.records) as *mut T).add(position & self.mask) }
+    }
+
+    /// The position (plus one) of the record last written at `position`'s slot.
+    fn sequence_at(&self, position: usize) -> &AtomicUsize {
+        debug_assert_eq!(self.producers, Producers::Many);
+        unsafe { &*(self.map.add(self.sequences) as *const AtomicUsize).add(position & self.mask) }
+    }
+
+    /// The amount of records that fit after `tail`, or `None` if `tail` is
+    /// already outdated.
+    fn room_after(&self, tail: usize) -> Option<usize> {
+        // Acquiring the head ensures the receiver is done with the slots it freed.
+        let queued = tail.wrapping_sub(self.header().head.0.load(Ordering::Acquire));
+        self.capacity().checked_sub(queued)
+    }
+
+    /// The amount of records queued, including ones claimed but still being written.
+    fn len(&self) -> usize {
+        let head = self.header().head.0.load(Ordering::Acquire);
+        self.header().tail.0.load(Ordering::Acquire).wrapping_sub(head).min(self.capacity())
+    }
+
+    fn stats(&self) -> ChannelStats {
+        ChannelStats { depth: self.len(), capacity: self.capacity(), full: self.full.load(Ordering::Relaxed) }
+    }
+
+    /// Queues `record` with `send`, and tells why it couldn't.
+    fn try_send<F: FnOnce(T) -> bool>(&self, record: T, send: F) -> Result<(), TrySendError<T>> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(record));
+        }
+
+        if send(record) {
+            Ok(())
+        }
+        else {
+            self.full.fetch_add(1, Ordering::Relaxed);
+            Err(TrySendError::Full(record))
+        }
+    }
+
+    /// Retries `send` until there is room, the receiver is gone, or `deadline` passes.
+    ///
+    /// Backs off by yielding at first, then by sleeping up to a millisecond at a time.
+    fn send_until<F: FnMut(T) -> bool>(&self, record: T, deadline: Option<Instant>, mut send: F) -> Result<(), SendTimeoutError<T>> {
+        let mut full = false;
+        let mut attempt = 0u32;
+        loop {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(SendTimeoutError::Disconnected(record));
+            }
+            if send(record) {
+                return Ok(());
+            }
+            if !full {
+                full = true;
+                self.full.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let pause = Duration::from_micros(50 << attempt.saturating_sub(8).min(4)).min(Duration::from_millis(1));
+            match deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+                Some(remaining) if remaining.is_zero() => return Err(SendTimeoutError::Timeout(record)),
+                Some(remaining) if attempt >= 8 => thread::sleep(pause.min(remaining)),
+                None if attempt >= 8 => thread::sleep(pause),
+                _ => thread::yield_now(),
+            }
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// Signals the receiver, unless it was already signaled and hasn't taken the records since.
+    fn signal(&self) {
+        // Releases the records just sent to the receiver, which takes them
+        // only after clearing the flag.
+        if !self.header().signaled.0.swap(true, Ordering::AcqRel) {
+            // The counter only overflows after 2^64 - 1 signals without a read.
+            let _ = self.signal.increment(1);
+        }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len); }
+    }
+}
+
+fn align_up(offset: usize, align: usize) -> Option<usize> {
+    offset.checked_add(align - 1).map(|end| end & !(align - 1))
+}
+
+/// Why `try_send` couldn't queue a record, which it hands back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The ring is full.
+    Full(T),
+    /// The receiver was dropped.
+    Disconnected(T),
+}
+
+/// Why `send_timeout` couldn't queue a record, which it hands back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    /// The ring stayed full until the timeout passed.
+    Timeout(T),
+    /// The receiver was dropped.
+    Disconnected(T),
+}
+
+impl<T> SendTimeoutError<T> {
+    /// The record that wasn't sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            SendTimeoutError::Timeout(record) | SendTimeoutError::Disconnected(record) => record,
+        }
+    }
+}
+
+/// A snapshot of a ring's queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// The amount of records queued.
+    pub depth: usize,
+    /// The amount of records the ring holds at most.
+    pub capacity: usize,
+    /// The amount of sends that found the ring full.
+    pub full: u64,
+}
+
+/// The sending end of a ring for several producers; clone it to have more of them.
+pub struct Sender<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// The sending end of a ring for a single producer.
+pub struct SingleSender<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// The receiving end of a ring, to be registered on an `EPoll`.
+pub struct Receiver<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Creates a channel for several producers that holds up to `capacity` records.
+///
+/// Fails with `EINVAL` if `capacity` is zero.
+pub fn channel<T: Copy + Send>(capacity: usize) -> io::Result<(Sender<T>, Receiver<T>)> {
+    let ring = Arc::new(Ring::new(capacity, Producers::Many)?);
+    Ok((Sender { ring: ring.clone() }, Receiver { ring }))
+}
+
+/// Creates a channel for a single producer that holds up to `capacity` records.
+///
+/// Fails with `EINVAL` if `capacity` is zero.
+pub fn single_producer<T: Copy + Send>(capacity: usize) -> io::Result<(SingleSender<T>, Receiver<T>)> {
+    let ring = Arc::new(Ring::new(capacity, Producers::Single)?);
+    Ok((SingleSender { ring: ring.clone() }, Receiver { ring }))
+}
+
+impl<T: Copy + Send> Sender<T> {
+    /// Queues a record, or hands it back at once if the ring is full or the
+    /// receiver is gone.
+    pub fn try_send(&self, record: T) -> Result<(), TrySendError<T>> {
+        self.ring.try_send(record, |record| self.send_batch(&[record]) == 1)
+    }
+
+    /// Queues a record, waiting for room in the ring; hands it back only if
+    /// the receiver is gone.
+    ///
+    /// Never call this from the thread that receives, as it would wait for itself.
+    pub fn send(&self, record: T) -> Result<(), T> {
+        self.ring.send_until(record, None, |record| self.send_batch(&[record]) == 1).map_err(SendTimeoutError::into_inner)
+    }
+
+    /// Queues a record like `send`, but waits for room for `timeout` at most.
+    pub fn send_timeout(&self, record: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        let deadline = Instant::now().checked_add(timeout);
+        self.ring.send_until(record, deadline, |record| self.send_batch(&[record]) == 1)
+    }
+
+    /// The depth and capacity of the queue.
+    pub fn stats(&self) -> ChannelStats {
+        self.ring.stats()
+    }
+
+    /// Queues records from the start of `records` until the ring is full,
+    /// and returns how many were queued.
+    ///
+    /// The whole batch costs at most one system call.
+    pub fn send_batch(&self, records: &[T]) -> usize {
+        let ring = &*self.ring;
+        let tail = &ring.header().tail.0;
+
+        // Claim room for the batch; the records become visible one by one,
+        // as their sequences are stored.
+        let mut start = tail.load(Ordering::Relaxed);
+        let amount = loop {
+            let amount = match ring.room_after(start) {
+                Some(room) => room.min(records.len()),
+                None => {
+                    start = tail.load(Ordering::Relaxed);
+                    continue;
+                },
+            };
+
+            if amount == 0 {
+                return 0;
+            }
+
+            match tail.compare_exchange_weak(start, start.wrapping_add(amount), Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break amount,
+                Err(current) => start = current,
+            }
+        };
+
+        for (offset, record) in records[..amount].iter().enumerate() {
+            let position = start.wrapping_add(offset);
+            unsafe { ptr::write(ring.record_at(position), *record) };
+            ring.sequence_at(position).store(position.wrapping_add(1), Ordering::Release);
+        }
+
+        ring.signal();
+        amount
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender { ring: self.ring.clone() }
+    }
+}
+
+impl<T: Copy + Send> SingleSender<T> {
+    /// Queues a record, or hands it back at once if the ring is full or the
+    /// receiver is gone.
+    pub fn try_send(&mut self, record: T) -> Result<(), TrySendError<T>> {
+        let ring = &*self.ring;
+        ring.try_send(record, |record| SingleSender::push(ring, &[record]) == 1)
+    }
+
+    /// Queues a record, waiting for room in the ring; hands it back only if
+    /// the receiver is gone.
+    ///
+    /// Never call this from the thread that receives, as it would wait for itself.
+    pub fn send(&mut self, record: T) -> Result<(), T> {
+        let ring = &*self.ring;
+        ring.send_until(record, None, |record| SingleSender::push(ring, &[record]) == 1).map_err(SendTimeoutError::into_inner)
+    }
+
+    /// Queues a record like `send`, but waits for room for `timeout` at most.
+    pub fn send_timeout(&mut self, record: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        let ring = &*self.ring;
+        let deadline = Instant::now().checked_add(timeout);
+        ring.send_until(record, deadline, |record| SingleSender::push(ring, &[record]) == 1)
+    }
+
+    /// The depth and capacity of the queue.
+    pub fn stats(&self) -> ChannelStats {
+        self.ring.stats()
+    }
+
+    /// Queues records from the start of `records` until the ring is full,
+    /// and returns how many were queued.
+    ///
+    /// The whole batch costs at most one system call.
+    pub fn send_batch(&mut self, records: &[T]) -> usize {
+        SingleSender::push(&self.ring, records)
+    }
+
+    /// Sends `records` on behalf of the ring's only sender.
+    fn push(ring: &Ring<T>, records: &[T]) -> usize {
+        let tail = &ring.header().tail.0;
+
+        // Only the sender moves the tail.
+        let start = tail.load(Ordering::Relaxed);
+        let amount = ring.room_after(start).unwrap_or(0).min(records.len());
+        if amount == 0 {
+            return 0;
+        }
+
+        for (offset, record) in records[..amount].iter().enumerate() {
+            unsafe { ptr::write(ring.record_at(start.wrapping_add(offset)), *record) };
+        }
+
+        tail.store(start.wrapping_add(amount), Ordering::Release);
+        ring.signal();
+        amount
+    }
+}
+
+impl<T: Copy + Send> Receiver<T> {
+    /// Appends every queued record to `out`, and returns how many there were.
+    pub fn recv_batch(&mut self, out: &mut Vec<T>) -> io::Result<usize> {
+        let ring = &*self.ring;
+
+        // Clearing the signal before taking the records means a record sent from
+        // here on either is taken now or signals anew.
+        ring.signal.take()?;
+        ring.header().signaled.0.swap(false, Ordering::AcqRel);
+
+        let head = ring.header().head.0.load(Ordering::Relaxed);
+        let amount = match ring.producers {
+            Producers::Single => ring.header().tail.0.load(Ordering::Acquire).wrapping_sub(head),
+            Producers::Many => {
+                // Producers write their claims in any order; stop at the first one
+                // not written yet, whose producer signals once it is.
+                let mut amount = 0;
+                while amount < ring.capacity() {
+                    let position = head.wrapping_add(amount);
+                    if ring.sequence_at(position).load(Ordering::Acquire) != position.wrapping_add(1) {
+                        break;
+                    }
+                    amount += 1;
+                }
+                amount
+            },
+        };
+
+        out.reserve(amount);
+        for offset in 0..amount {
+            out.push(unsafe { ptr::read(ring.record_at(head.wrapping_add(offset))) });
+        }
+
+        // Releases the slots to the producers.
+        ring.header().head.0.store(head.wrapping_add(amount), Ordering::Release);
+        Ok(amount)
+    }
+
+    /// The amount of records the ring holds at most.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// The depth and capacity of the queue.
+    pub fn stats(&self) -> ChannelStats {
+        self.ring.stats()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // Wakes blocked senders, to fail.
+        self.ring.closed.store(true, Ordering::Release);
+    }
+}
+
+impl<T> AsRawFd for Receiver<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ring.signal.as_raw_fd()
+    }
+}
+
+impl<T> AsFd for Receiver<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.ring.signal.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn batches() {
+        let (sender, mut receiver) = channel(3).unwrap();
+        assert_eq!(receiver.capacity(), 4);
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&receiver, EPOLLIN, 0).unwrap();
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 0);
+
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(sender.send_batch(&[2, 3, 4, 5]), 3);
+        assert_eq!(sender.try_send(6), Err(TrySendError::Full(6)));
+        assert_eq!(sender.stats(), ChannelStats { depth: 4, capacity: 4, full: 1 });
+
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+
+        let mut received = Vec::new();
+        assert_eq!(receiver.recv_batch(&mut received).unwrap(), 4);
+        assert_eq!(received, vec![1, 2, 3, 4]);
+        assert_eq!(receiver.stats().depth, 0);
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 0);
+
+        // The freed slots are reused.
+        assert_eq!(sender.send_batch(&[7, 8]), 2);
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!(receiver.recv_batch(&mut received).unwrap(), 2);
+        assert_eq!(received[4..], [7, 8]);
+    }
+
+    #[test]
+    fn rejects_zero_capacity() {
+        assert_eq!(channel::<u64>(0).err().unwrap().raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(single_producer::<u64>(0).err().unwrap().raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn single_producer_wraps_around() {
+        let (mut sender, mut receiver) = single_producer::<[u64; 3]>(16).unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&receiver, EPOLLIN, 0).unwrap();
+
+        let producer = thread::spawn(move || {
+            let mut next = 0;
+            while next < 10_000 {
+                if sender.try_send([next, next * 2, next * 3]).is_ok() {
+                    next += 1;
+                }
+                else {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut received = Vec::new();
+        let mut events = [Event::default(); 1];
+        while received.len() < 10_000 {
+            assert_eq!(epoll.wait(&mut events, Timeout::Milliseconds(5000)).unwrap(), 1);
+            receiver.recv_batch(&mut received).unwrap();
+        }
+        producer.join().unwrap();
+
+        assert!(received.iter().enumerate().all(|(i, &record)| record == [i as u64, i as u64 * 2, i as u64 * 3]));
+    }
+
+    #[test]
+    fn many_producers() {
+        let (sender, mut receiver) = channel(64).unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&receiver, EPOLLIN, 0).unwrap();
+
+        let producers: Vec<_> = (0..4).map(|p| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let records: Vec<u64> = (0..5000).map(|i| p * 5000 + i).collect();
+                let mut sent = 0;
+                while sent < records.len() {
+                    sent += sender.send_batch(&records[sent..(sent + 7).min(records.len())]);
+                    thread::yield_now();
+                }
+            })
+        }).collect();
+
+        let mut received = Vec::new();
+        let mut events = [Event::default(); 1];
+        while received.len() < 20_000 {
+            assert_eq!(epoll.wait(&mut events, Timeout::Milliseconds(5000)).unwrap(), 1);
+            receiver.recv_batch(&mut received).unwrap();
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        // Each producer's records arrive in order.
+        for p in 0..4 {
+            let mine: Vec<_> = received.iter().cloned().filter(|record| record / 5000 == p).collect();
+            assert_eq!(mine, (p * 5000..(p + 1) * 5000).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let (sender, mut receiver) = channel::<u64>(2).unwrap();
+        let (mut single, single_receiver) = single_producer::<u64>(1).unwrap();
+
+        assert_eq!(sender.send_batch(&[1, 2]), 2);
+        assert_eq!(sender.send_timeout(3, Duration::from_millis(20)), Err(SendTimeoutError::Timeout(3)));
+        assert_eq!(single.send(1), Ok(()));
+        assert_eq!(single.send_timeout(2, Duration::ZERO), Err(SendTimeoutError::Timeout(2)));
+
+        // A blocked send goes through once the receiver makes room.
+        let blocked = thread::spawn(move || sender.send(3).map(|()| sender));
+        thread::sleep(Duration::from_millis(20));
+        let mut received = Vec::new();
+        receiver.recv_batch(&mut received).unwrap();
+        let sender = blocked.join().unwrap().unwrap();
+        receiver.recv_batch(&mut received).unwrap();
+        assert_eq!(received, [1, 2, 3]);
+        assert!(sender.stats().full >= 2);
+
+        // And fails once the receiver is gone.
+        sender.send_batch(&[4, 5]);
+        let blocked = thread::spawn(move || sender.send(6));
+        thread::sleep(Duration::from_millis(20));
+        drop(receiver);
+        assert_eq!(blocked.join().unwrap(), Err(6));
+
+        drop(single_receiver);
+        assert_eq!(single.try_send(2), Err(TrySendError::Disconnected(2)));
+        assert_eq!(single.send_timeout(2, Duration::from_secs(5)), Err(SendTimeoutError::Disconnected(2)));
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Receiving signals as events, through a signalfd.
+//!
+//! The signals must be blocked, or they are delivered as usual rather than
+//! queued for the descriptor.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let signalfd = SignalFd::blocking(&[libc::SIGINT, libc::SIGTERM])?;
+//! epoll.add(&signalfd, EPOLLIN, SIGNALS)?;
+//!
+//! // Once `SIGNALS` is reported:
+//! while let Some(info) = signalfd.read()? {
+//!     shut_down(info.signal);
+//! }
+//! ```
+
+use super::*;
+use sigset::SigSet;
+use std::mem;
+
+/// A descriptor that is readable while a signal of its set is pending.
+#[derive(Debug)]
+pub struct SignalFd {
+    fd: OwnedFd,
+}
+
+/// A signal read from a `SignalFd`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SignalInfo {
+    /// The signal number, e.g. `libc::SIGTERM`.
+    pub signal: libc::c_int,
+    /// Why the signal was sent, e.g. `libc::SI_USER` for `kill`.
+    pub code: i32,
+    /// The process that sent the signal, or the child that changed state for `SIGCHLD`.
+    pub pid: u32,
+    /// The real user id of the sender.
+    pub uid: u32,
+    /// The exit status or signal of a child, for `SIGCHLD`.
+    pub status: i32,
+    /// The value sent along with `sigqueue`.
+    pub value: u64,
+    /// The descriptor that became ready, for `SIGIO`.
+    pub fd: RawFd,
+    /// The amount of expirations missed by a POSIX timer.
+    pub overrun: u32,
+}
+
+impl SignalFd {
+    /// Blocks `signals` in the calling thread, and creates a signalfd for them.
+    ///
+    /// Threads spawned afterwards inherit the mask; threads that already run
+    /// must block the signals on their own. The mask is restored on failure.
+    pub fn blocking(signals: &[libc::c_int]) -> io::Result<SignalFd> {
+        let set = SigSet::of(signals)?;
+        let previous = set.block()?;
+
+        SignalFd::new(&set).or_else(|e| {
+            previous.set_thread_mask()?;
+            Err(e)
+        })
+    }
+
+    /// Creates a non-blocking signalfd for the signals of `signals`.
+    pub fn new(signals: &SigSet) -> io::Result<SignalFd> {
+        let fd = unsafe { libc::signalfd(-1, signals.as_ptr(), libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+
+        if fd < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(SignalFd { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+        }
+    }
+
+    /// Replaces the signals the descriptor reports.
+    pub fn set_signals(&self, signals: &SigSet) -> io::Result<()> {
+        if unsafe { libc::signalfd(self.fd.as_raw_fd(), signals.as_ptr(), 0) } < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Takes a pending signal, or returns `None` if there is none.
+    pub fn read(&self) -> io::Result<Option<SignalInfo>> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let rc = unsafe {
+            libc::read(self.fd.as_raw_fd(), &mut info as *mut _ as *mut libc::c_void, mem::size_of::<libc::signalfd_siginfo>())
+        };
+
+        if rc < 0 {
+            let error = Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+
+            return Err(error);
+        }
+
+        Ok(Some(SignalInfo {
+            signal: info.ssi_signo as libc::c_int,
+            code: info.ssi_code,
+            pid: info.ssi_pid,
+            uid: info.ssi_uid,
+            status: info.ssi_status,
+            value: info.ssi_ptr,
+            fd: info.ssi_fd,
+            overrun: info.ssi_overrun,
+        }))
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for SignalFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_blocked_signals() {
+        let mut winch = SigSet::empty();
+        winch.add(libc::SIGWINCH).unwrap();
+        winch.block().unwrap();
+
+        let signalfd = SignalFd::new(&winch).unwrap();
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&signalfd, EPOLLIN, 0).unwrap();
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+        assert_eq!(signalfd.read().unwrap(), None);
+
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGWINCH) };
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_some());
+
+        let info = signalfd.read().unwrap().unwrap();
+        assert_eq!(info.signal, libc::SIGWINCH);
+        assert_eq!(info.pid, ::std::process::id());
+        assert_eq!(signalfd.read().unwrap(), None);
+
+        winch.unblock().unwrap();
+    }
+
+    #[test]
+    fn blocking_and_changing_signals() {
+        let signalfd = SignalFd::blocking(&[libc::SIGURG]).unwrap();
+        assert!(SigSet::thread_mask().unwrap().contains(libc::SIGURG));
+
+        unsafe {
+            let value = libc::sigval { sival_ptr: 42 as *mut libc::c_void };
+            assert_eq!(libc::pthread_sigqueue(libc::pthread_self(), libc::SIGURG, value), 0);
+        }
+        let info = signalfd.read().unwrap().unwrap();
+        assert_eq!((info.signal, info.code, info.value), (libc::SIGURG, libc::SI_QUEUE, 42));
+
+        // Signals left out of the descriptor stay pending for the thread.
+        signalfd.set_signals(&SigSet::empty()).unwrap();
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGURG) };
+        assert_eq!(signalfd.read().unwrap(), None);
+
+        signalfd.set_signals(&SigSet::of(&[libc::SIGURG]).unwrap()).unwrap();
+        assert_eq!(signalfd.read().unwrap().map(|info| info.signal), Some(libc::SIGURG));
+        SigSet::of(&[libc::SIGURG]).unwrap().unblock().unwrap();
+    }
+}
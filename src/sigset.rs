@@ -0,0 +1,199 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sets of signals, for use as signal masks.
+//!
+//! # Example
+//!
+//! ```no-run
+//! // Keep SIGCHLD blocked, except while waiting.
+//! let mut blocked = SigSet::empty();
+//! blocked.add(libc::SIGCHLD)?;
+//! let unblocked = blocked.block()?;
+//!
+//! loop {
+//!     match epoll.wait_with_sigmask(&mut events, Timeout::Indefinite, &unblocked) {
+//!         Err(ref e) if e.kind() == io::ErrorKind::Interrupted => reap_children(),
+//!         result => { /* ... */ },
+//!     }
+//! }
+//! ```
+
+use super::*;
+use std::fmt;
+use std::mem;
+use std::ptr;
+
+/// A set of signals.
+#[derive(Clone, Copy)]
+pub struct SigSet(libc::sigset_t);
+
+impl SigSet {
+    /// A set without any signals.
+    pub fn empty() -> SigSet {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+
+        SigSet(set)
+    }
+
+    /// A set with every signal.
+    pub fn full() -> SigSet {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigfillset(&mut set) };
+
+        SigSet(set)
+    }
+
+    /// A set of the given signals; fails with `EINVAL` for invalid signal numbers.
+    pub fn of(signals: &[libc::c_int]) -> io::Result<SigSet> {
+        let mut set = SigSet::empty();
+        for &signal in signals {
+            set.add(signal)?;
+        }
+
+        Ok(set)
+    }
+
+    /// The signal mask of the calling thread.
+    pub fn thread_mask() -> io::Result<SigSet> {
+        let mut set = SigSet::empty();
+        sigmask(libc::SIG_BLOCK, ptr::null(), &mut set.0)?;
+
+        Ok(set)
+    }
+
+    /// Adds a signal to the set; fails with `EINVAL` for invalid signal numbers.
+    pub fn add(&mut self, signal: libc::c_int) -> io::Result<()> {
+        if unsafe { libc::sigaddset(&mut self.0, signal) } < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Removes a signal from the set; fails with `EINVAL` for invalid signal numbers.
+    pub fn remove(&mut self, signal: libc::c_int) -> io::Result<()> {
+        if unsafe { libc::sigdelset(&mut self.0, signal) } < 0 {
+            Err(Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Returns whether the set has `signal`.
+    pub fn contains(&self, signal: libc::c_int) -> bool {
+        unsafe { libc::sigismember(&self.0, signal) == 1 }
+    }
+
+    /// Blocks the signals of the set in the calling thread, in addition to those already blocked.
+    ///
+    /// Returns the previous mask, which can be passed to `EPoll::wait_with_sigmask`
+    /// to let the signals through only while waiting.
+    pub fn block(&self) -> io::Result<SigSet> {
+        let mut old = SigSet::empty();
+        sigmask(libc::SIG_BLOCK, &self.0, &mut old.0)?;
+
+        Ok(old)
+    }
+
+    /// Unblocks the signals of the set in the calling thread.
+    pub fn unblock(&self) -> io::Result<()> {
+        sigmask(libc::SIG_UNBLOCK, &self.0, ptr::null_mut())
+    }
+
+    /// Replaces the signal mask of the calling thread with this set, returning the previous mask.
+    pub fn set_thread_mask(&self) -> io::Result<SigSet> {
+        let mut old = SigSet::empty();
+        sigmask(libc::SIG_SETMASK, &self.0, &mut old.0)?;
+
+        Ok(old)
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const libc::sigset_t {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SigSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let signals: Vec<_> = (1..=libc::SIGRTMAX()).filter(|&s| self.contains(s)).collect();
+        f.debug_tuple("SigSet").field(&signals).finish()
+    }
+}
+
+fn sigmask(how: libc::c_int, set: *const libc::sigset_t, old: *mut libc::sigset_t) -> io::Result<()> {
+    // pthread_sigmask returns the error rather than setting errno.
+    match unsafe { libc::pthread_sigmask(how, set, old) } {
+        0 => Ok(()),
+        errno => Err(Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn membership() {
+        let mut set = SigSet::empty();
+        assert!(!set.contains(libc::SIGUSR1));
+
+        set.add(libc::SIGUSR1).unwrap();
+        assert!(set.contains(libc::SIGUSR1));
+        assert!(!set.contains(libc::SIGUSR2));
+
+        set.remove(libc::SIGUSR1).unwrap();
+        assert!(!set.contains(libc::SIGUSR1));
+
+        let both = SigSet::of(&[libc::SIGINT, libc::SIGTERM]).unwrap();
+        assert!(both.contains(libc::SIGINT) && both.contains(libc::SIGTERM));
+        assert!(SigSet::of(&[libc::SIGINT, 0]).is_err());
+
+        assert!(SigSet::full().contains(libc::SIGUSR2));
+        assert!(set.add(-1).is_err());
+    }
+
+    #[test]
+    fn mask_applies_only_while_waiting() {
+        extern "C" fn ignore(_: libc::c_int) {}
+
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = ignore as *const () as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()), 0);
+        }
+
+        let mut usr1 = SigSet::empty();
+        usr1.add(libc::SIGUSR1).unwrap();
+        let unblocked = usr1.block().unwrap();
+        assert!(SigSet::thread_mask().unwrap().contains(libc::SIGUSR1));
+        assert!(!unblocked.contains(libc::SIGUSR1));
+
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR1) };
+
+        let epoll = EPoll::new().unwrap();
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 0);
+
+        let interrupted = epoll.wait_with_sigmask(&mut events, Timeout::Indefinite, &unblocked).unwrap_err();
+        assert_eq!(interrupted.kind(), io::ErrorKind::Interrupted);
+
+        // The mask is back in place once the wait returns.
+        assert!(SigSet::thread_mask().unwrap().contains(libc::SIGUSR1));
+        usr1.unblock().unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The system calls issued by this crate.
+//!
+//! Daemons that confine themselves with seccomp need to know precisely what to allow.
+//! Each module of the crate has its own list, so a filter only has to allow what
+//! is actually used.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let mut allowed: Vec<_> = syscalls::CORE.iter().map(|s| s.number).collect();
+//! allowed.extend(syscalls::UNIX.iter().map(|s| s.number));
+//! ```
+
+use libc::{self, c_long};
+
+/// A system call, by name and by number on the current architecture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Syscall {
+    pub name: &'static str,
+    pub number: c_long,
+}
+
+macro_rules! syscalls {
+    ($($name:ident => $number:ident),*) => {
+        &[$(Syscall { name: stringify!($name), number: libc::$number }),*]
+    }
+}
+
+/// Used by `EPoll` itself: creating, controlling, waiting on and closing an epoll.
+///
+/// Architectures without a native `epoll_wait` have libc implement it with `epoll_pwait`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm"))]
+pub const CORE: &[Syscall] = syscalls!(epoll_create1 => SYS_epoll_create1,
+                                       epoll_ctl => SYS_epoll_ctl,
+                                       epoll_wait => SYS_epoll_wait,
+                                       epoll_pwait => SYS_epoll_pwait,
+                                       epoll_pwait2 => SYS_epoll_pwait2,
+                                       close => SYS_close);
+
+/// Used by `EPoll` itself: creating, controlling, waiting on and closing an epoll.
+///
+/// Architectures without a native `epoll_wait` have libc implement it with `epoll_pwait`.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm")))]
+pub const CORE: &[Syscall] = syscalls!(epoll_create1 => SYS_epoll_create1,
+                                       epoll_ctl => SYS_epoll_ctl,
+                                       epoll_pwait => SYS_epoll_pwait,
+                                       epoll_pwait2 => SYS_epoll_pwait2,
+                                       close => SYS_close);
+
+/// Issued by the standard library on the crate's behalf: memory allocation for
+/// the registration table, seeding its hasher, waiting on its lock when it is
+/// contended, and checking that an `OwnedFd` is still open before closing it.
+#[cfg(target_pointer_width = "64")]
+pub const RUNTIME: &[Syscall] = syscalls!(brk => SYS_brk,
+                                          mmap => SYS_mmap,
+                                          munmap => SYS_munmap,
+                                          mremap => SYS_mremap,
+                                          getrandom => SYS_getrandom,
+                                          futex => SYS_futex,
+                                          fcntl => SYS_fcntl);
+
+/// Issued by the standard library on the crate's behalf: memory allocation for
+/// the registration table, seeding its hasher, waiting on its lock when it is
+/// contended, and checking that an `OwnedFd` is still open before closing it.
+///
+/// 32-bit glibc maps memory with `mmap2` and issues `fcntl64` for `fcntl`.
+#[cfg(target_pointer_width = "32")]
+pub const RUNTIME: &[Syscall] = syscalls!(brk => SYS_brk,
+                                          mmap2 => SYS_mmap2,
+                                          munmap => SYS_munmap,
+                                          mremap => SYS_mremap,
+                                          getrandom => SYS_getrandom,
+                                          futex => SYS_futex,
+                                          fcntl => SYS_fcntl,
+                                          fcntl64 => SYS_fcntl64);
+
+/// Used by the `unix` module to create socket pairs, pass descriptors and credentials,
+/// and the registration table along with an epoll.
+///
+/// The standard library reads from sockets with `recvfrom`; a received epoll
+/// is checked by reading its `/proc/self/fd` link.
+#[cfg(target_arch = "x86_64")]
+pub const UNIX: &[Syscall] = syscalls!(socketpair => SYS_socketpair,
+                                       sendmsg => SYS_sendmsg,
+                                       recvmsg => SYS_recvmsg,
+                                       recvfrom => SYS_recvfrom,
+                                       write => SYS_write,
+                                       setsockopt => SYS_setsockopt,
+                                       getsockopt => SYS_getsockopt,
+                                       readlink => SYS_readlink);
+
+/// Used by the `unix` module to create socket pairs, pass descriptors and credentials,
+/// and the registration table along with an epoll.
+///
+/// The standard library reads from sockets with `recvfrom`; a received epoll
+/// is checked by reading its `/proc/self/fd` link.
+#[cfg(not(target_arch = "x86_64"))]
+pub const UNIX: &[Syscall] = syscalls!(socketpair => SYS_socketpair,
+                                       sendmsg => SYS_sendmsg,
+                                       recvmsg => SYS_recvmsg,
+                                       recvfrom => SYS_recvfrom,
+                                       write => SYS_write,
+                                       setsockopt => SYS_setsockopt,
+                                       getsockopt => SYS_getsockopt,
+                                       readlinkat => SYS_readlinkat);
+
+/// Used by the `pipe` module, on top of plain reads and writes.
+pub const PIPE: &[Syscall] = syscalls!(pipe2 => SYS_pipe2,
+                                       fcntl => SYS_fcntl,
+                                       splice => SYS_splice);
+
+/// Used by the `inherit` module to classify descriptors.
+///
+/// glibc implements `fstat` with `newfstatat` on x86-64.
+#[cfg(target_arch = "x86_64")]
+pub const INHERIT: &[Syscall] = syscalls!(fstat => SYS_fstat,
+                                          newfstatat => SYS_newfstatat,
+                                          fcntl => SYS_fcntl,
+                                          getsockopt => SYS_getsockopt,
+                                          getsockname => SYS_getsockname,
+                                          readlink => SYS_readlink);
+
+/// Used by the `inherit` module to classify descriptors.
+#[cfg(not(target_arch = "x86_64"))]
+pub const INHERIT: &[Syscall] = syscalls!(fstat => SYS_fstat,
+                                          fcntl => SYS_fcntl,
+                                          getsockopt => SYS_getsockopt,
+                                          getsockname => SYS_getsockname,
+                                          readlinkat => SYS_readlinkat);
+
+/// Used by `health::HealthChecker` to check descriptors.
+///
+/// glibc implements `fstat` with `newfstatat` on x86-64.
+#[cfg(target_arch = "x86_64")]
+pub const HEALTH: &[Syscall] = syscalls!(fcntl => SYS_fcntl,
+                                         fstat => SYS_fstat,
+                                         newfstatat => SYS_newfstatat);
+
+/// Used by `health::HealthChecker` to check descriptors.
+#[cfg(not(target_arch = "x86_64"))]
+pub const HEALTH: &[Syscall] = syscalls!(fcntl => SYS_fcntl,
+                                         fstat => SYS_fstat);
+
+/// Used by the `privilege` module to drop privileges and capabilities.
+#[cfg(not(any(target_arch = "x86", target_arch = "arm")))]
+pub const PRIVILEGE: &[Syscall] = syscalls!(setgroups => SYS_setgroups,
+                                            geteuid => SYS_geteuid,
+                                            setgid => SYS_setgid,
+                                            setuid => SYS_setuid,
+                                            prctl => SYS_prctl,
+                                            capset => SYS_capset);
+
+/// Used by the `privilege` module to drop privileges and capabilities.
+///
+/// glibc switches users and groups with the 32-bit-id calls on x86 and ARM.
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+pub const PRIVILEGE: &[Syscall] = syscalls!(setgroups32 => SYS_setgroups32,
+                                            geteuid32 => SYS_geteuid32,
+                                            setgid32 => SYS_setgid32,
+                                            setuid32 => SYS_setuid32,
+                                            prctl => SYS_prctl,
+                                            capset => SYS_capset);
+
+/// Used by the `sigset` module to read and change the thread's signal mask.
+pub const SIGSET: &[Syscall] = syscalls!(rt_sigprocmask => SYS_rt_sigprocmask);
+
+/// Used by `signalfd::SignalFd`; `SignalFd::blocking` and `EventLoop::on_signal` need `SIGSET` too.
+pub const SIGNALFD: &[Syscall] = syscalls!(signalfd4 => SYS_signalfd4,
+                                           read => SYS_read);
+
+/// Used by `pidfd::PidFd`, and through it by `EventLoop::on_child_exit`.
+pub const PIDFD: &[Syscall] = syscalls!(pidfd_open => SYS_pidfd_open,
+                                        waitid => SYS_waitid);
+
+/// Used by `inotify::Inotify`.
+pub const INOTIFY: &[Syscall] = syscalls!(inotify_init1 => SYS_inotify_init1,
+                                          inotify_add_watch => SYS_inotify_add_watch,
+                                          inotify_rm_watch => SYS_inotify_rm_watch,
+                                          read => SYS_read);
+
+/// Used by `fanotify::Fanotify`.
+pub const FANOTIFY: &[Syscall] = syscalls!(fanotify_init => SYS_fanotify_init,
+                                           fanotify_mark => SYS_fanotify_mark,
+                                           read => SYS_read,
+                                           write => SYS_write);
+
+/// Used by `mqueue::MessageQueue`; libc sends and receives with the timed calls.
+pub const MQUEUE: &[Syscall] = syscalls!(mq_open => SYS_mq_open,
+                                         mq_timedsend => SYS_mq_timedsend,
+                                         mq_timedreceive => SYS_mq_timedreceive,
+                                         mq_getsetattr => SYS_mq_getsetattr,
+                                         mq_unlink => SYS_mq_unlink);
+
+/// Used by `eventfd::EventFd`, and through it by `counter::Counter` and the `ring` module.
+pub const COUNTER: &[Syscall] = syscalls!(eventfd2 => SYS_eventfd2,
+                                          read => SYS_read,
+                                          write => SYS_write);
+
+/// Used by the `ring` module on top of `COUNTER`, to map and unmap the rings,
+/// and to back off while blocking senders wait for room.
+#[cfg(target_pointer_width = "64")]
+pub const RING: &[Syscall] = syscalls!(mmap => SYS_mmap,
+                                       munmap => SYS_munmap,
+                                       sched_yield => SYS_sched_yield,
+                                       nanosleep => SYS_nanosleep,
+                                       clock_nanosleep => SYS_clock_nanosleep);
+
+/// Used by the `ring` module on top of `COUNTER`, to map and unmap the rings,
+/// and to back off while blocking senders wait for room.
+///
+/// 32-bit glibc maps memory with `mmap2`.
+#[cfg(target_pointer_width = "32")]
+pub const RING: &[Syscall] = syscalls!(mmap2 => SYS_mmap2,
+                                       munmap => SYS_munmap,
+                                       sched_yield => SYS_sched_yield,
+                                       nanosleep => SYS_nanosleep,
+                                       clock_nanosleep => SYS_clock_nanosleep);
+
+/// Used by `mirror::Mirror` to create and map its file.
+///
+/// The standard library issues `fcntl` when opening the file.
+#[cfg(target_pointer_width = "64")]
+pub const MIRROR: &[Syscall] = syscalls!(openat => SYS_openat,
+                                         fcntl => SYS_fcntl,
+                                         ftruncate => SYS_ftruncate,
+                                         mmap => SYS_mmap,
+                                         munmap => SYS_munmap,
+                                         close => SYS_close);
+
+/// Used by `mirror::Mirror` to create and map its file.
+///
+/// The standard library issues `fcntl` when opening the file; 32-bit glibc
+/// issues it as `fcntl64`, and maps the file with `mmap2`.
+#[cfg(target_pointer_width = "32")]
+pub const MIRROR: &[Syscall] = syscalls!(openat => SYS_openat,
+                                         fcntl => SYS_fcntl,
+                                         fcntl64 => SYS_fcntl64,
+                                         ftruncate => SYS_ftruncate,
+                                         mmap2 => SYS_mmap2,
+                                         munmap => SYS_munmap,
+                                         close => SYS_close);
+
+/// Used by `compat::mio`'s `Waker`, on top of `CORE`.
+#[cfg(feature = "mio-compat")]
+pub const MIO: &[Syscall] = syscalls!(eventfd2 => SYS_eventfd2,
+                                      read => SYS_read,
+                                      write => SYS_write);
+
+/// Returns every system call the crate may issue, across all modules, once
+/// each and ordered by number.
+pub fn syscalls_used() -> Vec<Syscall> {
+    #[cfg(feature = "mio-compat")]
+    let lists = [CORE, RUNTIME, UNIX, PIPE, INHERIT, HEALTH, PRIVILEGE, SIGSET, SIGNALFD, PIDFD, INOTIFY, FANOTIFY, MQUEUE, COUNTER, RING, MIRROR, MIO];
+    #[cfg(not(feature = "mio-compat"))]
+    let lists = [CORE, RUNTIME, UNIX, PIPE, INHERIT, HEALTH, PRIVILEGE, SIGSET, SIGNALFD, PIDFD, INOTIFY, FANOTIFY, MQUEUE, COUNTER, RING, MIRROR];
+
+    let mut used: Vec<_> = lists.iter().flat_map(|list| list.iter().cloned()).collect();
+    used.sort_by_key(|syscall| syscall.number);
+    used.dedup_by_key(|syscall| syscall.number);
+    used
+}
+
+// Each list is checked by running its module under seccomp, in `tests/seccomp.rs`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn used_once_each() {
+        let used = syscalls_used();
+        assert!(used.windows(2).all(|pair| pair[0].number < pair[1].number));
+
+        // `fcntl` is in several lists.
+        assert_eq!(used.iter().filter(|syscall| syscall.number == libc::SYS_fcntl).count(), 1);
+        assert!(CORE.iter().chain(UNIX).all(|syscall| used.contains(syscall)));
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Files in a known readiness state, for testing event handling code.
+//!
+//! Every helper returns real, kernel-backed descriptors whose state is already
+//! settled when the function returns, so tests need neither sleeps nor threads.
+//!
+//! | Helper                 | Reported events                        |
+//! |------------------------|----------------------------------------|
+//! | `readable_pipe`        | `EPOLLIN` on the reader                |
+//! | `full_pipe`            | no `EPOLLOUT` on the writer            |
+//! | `widowed_pipe_reader`  | `EPOLLHUP` on the reader               |
+//! | `widowed_pipe_writer`  | `EPOLLERR` on the writer               |
+//! | `half_closed_socket`   | `EPOLLIN` and `EPOLLRDHUP`, `EPOLLOUT`  |
+//! | `hung_up_socket`       | `EPOLLHUP` and `EPOLLRDHUP`             |
+
+use super::*;
+use pipe::{Pipe, PipeReader, PipeWriter};
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+
+/// A pipe with `data` waiting in it.
+pub fn readable_pipe(data: &[u8]) -> io::Result<(PipeReader, PipeWriter)> {
+    let (reader, mut writer) = Pipe::new()?.split();
+    writer.write_all(data)?;
+
+    Ok((reader, writer))
+}
+
+/// A pipe that cannot take another byte.
+///
+/// The pipe is shrunk to a single page before being filled, to keep the test cheap.
+pub fn full_pipe() -> io::Result<(PipeReader, PipeWriter)> {
+    let pipe = Pipe::new()?;
+    let capacity = pipe.set_capacity(1)?;
+    let (reader, mut writer) = pipe.split();
+
+    let filler = vec![0u8; capacity];
+    let mut written = 0;
+    while written < capacity {
+        match writer.write(&filler[written..]) {
+            Ok(amount) => written += amount,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((reader, writer))
+}
+
+/// The reading end of a pipe whose writing end was closed.
+pub fn widowed_pipe_reader() -> io::Result<PipeReader> {
+    let (reader, _) = Pipe::new()?.split();
+
+    Ok(reader)
+}
+
+/// The writing end of a pipe whose reading end was closed.
+pub fn widowed_pipe_writer() -> io::Result<PipeWriter> {
+    let (_, writer) = Pipe::new()?.split();
+
+    Ok(writer)
+}
+
+/// A socket whose peer has shut down its writing half, after sending `data`.
+///
+/// The peer is returned as well; it can still read what is written to the socket.
+pub fn half_closed_socket(data: &[u8]) -> io::Result<(UnixStream, UnixStream)> {
+    let (socket, mut peer) = unix::duplex()?;
+    peer.write_all(data)?;
+    peer.shutdown(Shutdown::Write)?;
+
+    Ok((socket, peer))
+}
+
+/// A socket whose peer is gone altogether.
+pub fn hung_up_socket() -> io::Result<UnixStream> {
+    let (socket, _) = unix::duplex()?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readiness<T: AsFd>(file: &T, interest: EventType) -> EventType {
+        let epoll = EPoll::new().unwrap();
+        epoll.add(file, interest, 0).unwrap();
+
+        let mut events = [Event::default(); 1];
+        match epoll.wait(&mut events, Timeout::Immediate).unwrap() {
+            0 => EventType::empty(),
+            _ => events[0].events,
+        }
+    }
+
+    #[test]
+    fn pipes() {
+        let (reader, writer) = readable_pipe(b"data").unwrap();
+        assert_eq!(readiness(&reader, EPOLLIN), EPOLLIN);
+        assert_eq!(readiness(&writer, EPOLLOUT), EPOLLOUT);
+
+        let (reader, writer) = full_pipe().unwrap();
+        assert_eq!(readiness(&writer, EPOLLOUT), EventType::empty());
+        assert_eq!(readiness(&reader, EPOLLIN), EPOLLIN);
+
+        assert_eq!(readiness(&widowed_pipe_reader().unwrap(), EPOLLIN), EPOLLHUP);
+        assert_eq!(readiness(&widowed_pipe_writer().unwrap(), EPOLLOUT), EPOLLOUT | EPOLLERR);
+    }
+
+    #[test]
+    fn sockets() {
+        let (socket, _peer) = half_closed_socket(b"bye").unwrap();
+        assert_eq!(readiness(&socket, EPOLLIN | EPOLLOUT | EPOLLRDHUP),
+                   EPOLLIN | EPOLLOUT | EPOLLRDHUP);
+
+        let socket = hung_up_socket().unwrap();
+        assert!(readiness(&socket, EPOLLIN | EPOLLRDHUP).contains(EPOLLHUP | EPOLLRDHUP));
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An epoll that associates a value of any type with each registration.
+//!
+//! The values are kept in a table inside the `TypedEPoll`; the kernel only sees
+//! opaque tokens, so there is no casting from `u64` back to objects.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let mut epoll = TypedEPoll::new()?;
+//! epoll.add(&stream, EPOLLIN, Connection::new(stream.try_clone()?))?;
+//!
+//! epoll.wait_mut(Timeout::Indefinite, |events, connection| {
+//!     connection.on_readable(events);
+//! })?;
+//! ```
+
+use super::*;
+
+/// Identifies a registration of a `TypedEPoll`.
+///
+/// Tokens of removed registrations are never confused with later ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token {
+    index: u32,
+    generation: u32,
+}
+
+impl Token {
+    fn from_data(data: u64) -> Token {
+        Token { index: data as u32, generation: (data >> 32) as u32 }
+    }
+
+    fn data(&self) -> u64 {
+        self.index as u64 | (self.generation as u64) << 32
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// An epoll that stores a `T` per registration.
+pub struct TypedEPoll<T> {
+    epoll: EPoll,
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+    events: Vec<Event>,
+}
+
+impl<T> TypedEPoll<T> {
+    /// Creates an epoll that reports up to 64 events per wait.
+    pub fn new() -> io::Result<TypedEPoll<T>> {
+        TypedEPoll::with_capacity(64)
+    }
+
+    /// Creates an epoll that reports up to `events` events per wait.
+    pub fn with_capacity(events: usize) -> io::Result<TypedEPoll<T>> {
+        Ok(TypedEPoll {
+            epoll: EPoll::new()?,
+            slots: Vec::new(),
+            free: Vec::new(),
+            events: vec![Event::default(); events.max(1)],
+        })
+    }
+
+    /// Registers `file`, associating `value` with it.
+    pub fn add<F, E>(&mut self, file: &F, events: E, value: T) -> io::Result<Token>
+        where F: AsFd + ?Sized, E: Into<EventType>
+    {
+        let token = match self.free.pop() {
+            Some(index) => Token { index, generation: self.slots[index as usize].generation },
+            None => {
+                if self.slots.len() > u32::MAX as usize {
+                    return Err(Error::from_raw_os_error(libc::ENOSPC));
+                }
+                self.slots.push(Slot { generation: 0, value: None });
+                Token { index: (self.slots.len() - 1) as u32, generation: 0 }
+            },
+        };
+
+        if let Err(e) = self.epoll.add(file, events, token.data()) {
+            self.free.push(token.index);
+            return Err(e.into());
+        }
+
+        self.slots[token.index as usize].value = Some(value);
+        Ok(token)
+    }
+
+    /// Changes the events `file` is registered with, keeping its value.
+    pub fn modify<F, E>(&mut self, file: &F, events: E) -> io::Result<()>
+        where F: AsFd + ?Sized, E: Into<EventType>
+    {
+        let data = self.data_for(file)?;
+        self.epoll.modify(file, events, data)?;
+        Ok(())
+    }
+
+    /// Deregisters `file`, and returns its value.
+    pub fn remove<F: AsFd + ?Sized>(&mut self, file: &F) -> io::Result<T> {
+        let token = Token::from_data(self.data_for(file)?);
+        self.epoll.remove(file)?;
+
+        let slot = &mut self.slots[token.index as usize];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(token.index);
+
+        Ok(slot.value.take().expect("registered slots hold a value"))
+    }
+
+    /// Returns the value of a registration, or `None` if it was removed.
+    pub fn get(&self, token: Token) -> Option<&T> {
+        match self.slots.get(token.index as usize) {
+            Some(slot) if slot.generation == token.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a registration mutably, or `None` if it was removed.
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        match self.slots.get_mut(token.index as usize) {
+            Some(slot) if slot.generation == token.generation => slot.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Returns the token `file` was registered with.
+    pub fn token_for<F: AsFd + ?Sized>(&self, file: &F) -> Option<Token> {
+        self.epoll.data_for(file.as_fd().as_raw_fd()).map(Token::from_data)
+    }
+
+    /// Waits for events, and returns the events and value of each ready registration.
+    pub fn wait<U: Into<Timeout>>(&mut self, timeout: U) -> io::Result<Ready<'_, T>> {
+        let amount = self.epoll.wait(&mut self.events, timeout)?;
+
+        Ok(Ready { events: self.events[..amount].iter(), slots: &self.slots })
+    }
+
+    /// Waits for events, and calls `f` with the events and value of each ready registration.
+    ///
+    /// Returns the amount of ready registrations.
+    pub fn wait_mut<U, F>(&mut self, timeout: U, mut f: F) -> io::Result<usize>
+        where U: Into<Timeout>, F: FnMut(EventType, &mut T)
+    {
+        let amount = self.epoll.wait(&mut self.events, timeout)?;
+        let mut ready = 0;
+
+        for event in &self.events[..amount] {
+            let token = Token::from_data(event.data);
+            match self.slots.get_mut(token.index as usize) {
+                Some(&mut Slot { generation, value: Some(ref mut value) }) if generation == token.generation => {
+                    f(event.events, value);
+                    ready += 1;
+                },
+                _ => {},
+            }
+        }
+
+        Ok(ready)
+    }
+
+    /// The underlying epoll.
+    pub fn epoll(&self) -> &EPoll {
+        &self.epoll
+    }
+
+    fn data_for<F: AsFd + ?Sized>(&self, file: &F) -> io::Result<u64> {
+        self.epoll.data_for(file.as_fd().as_raw_fd()).ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))
+    }
+}
+
+impl<T> AsRawFd for TypedEPoll<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl<T> AsFd for TypedEPoll<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.as_fd()
+    }
+}
+
+/// The ready registrations of a `TypedEPoll::wait`.
+pub struct Ready<'a, T: 'a> {
+    events: ::std::slice::Iter<'a, Event>,
+    slots: &'a [Slot<T>],
+}
+
+impl<'a, T> Iterator for Ready<'a, T> {
+    type Item = (EventType, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for event in &mut self.events {
+            // Registrations made through `epoll()` may carry any data, and
+            // stale tokens must not reach the value of a reused slot.
+            let token = Token::from_data(event.data);
+            match self.slots.get(token.index as usize) {
+                Some(&Slot { generation, value: Some(ref value) }) if generation == token.generation => {
+                    return Some((event.events, value));
+                },
+                _ => {},
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn values_follow_registrations() {
+        let (reader, _writer) = testing::readable_pipe(b"x").unwrap();
+        let (idle, _idle_writer) = testing::readable_pipe(b"").unwrap();
+
+        let mut epoll = TypedEPoll::new().unwrap();
+        let token = epoll.add(&reader, EPOLLIN, String::from("reader")).unwrap();
+        epoll.add(&idle, EPOLLIN, String::from("idle")).unwrap();
+
+        let ready: Vec<_> = epoll.wait(Timeout::Immediate).unwrap().map(|(e, v)| (e, v.clone())).collect();
+        assert_eq!(ready, vec![(EPOLLIN, String::from("reader"))]);
+
+        epoll.wait_mut(Timeout::Immediate, |_, value| value.push_str(" (seen)")).unwrap();
+        assert_eq!(epoll.get(token).map(|s| &s[..]), Some("reader (seen)"));
+
+        epoll.modify(&reader, EPOLLOUT).unwrap();
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().count(), 0);
+
+        assert_eq!(epoll.remove(&reader).unwrap(), "reader (seen)");
+        assert!(epoll.get(token).is_none());
+        assert!(epoll.remove(&reader).is_err());
+    }
+
+    #[test]
+    fn stale_tokens() {
+        let (first, _w1) = testing::readable_pipe(b"").unwrap();
+        let (second, _w2) = testing::readable_pipe(b"").unwrap();
+
+        let mut epoll = TypedEPoll::new().unwrap();
+        let old = epoll.add(&first, EPOLLIN, 1).unwrap();
+        epoll.remove(&first).unwrap();
+
+        let new = epoll.add(&second, EPOLLIN, 2).unwrap();
+        assert_ne!(old, new);
+        assert_eq!(epoll.get(old), None);
+        assert_eq!(epoll.get_mut(new), Some(&mut 2));
+        assert_eq!(epoll.token_for(&second), Some(new));
+    }
+
+    #[test]
+    fn skips_foreign_and_stale_events() {
+        let (first, _w1) = testing::readable_pipe(b"x").unwrap();
+        let (second, _w2) = testing::readable_pipe(b"x").unwrap();
+        let (foreign, _w3) = testing::readable_pipe(b"x").unwrap();
+        let (stale, _w4) = testing::readable_pipe(b"x").unwrap();
+
+        let mut epoll = TypedEPoll::new().unwrap();
+        let old = epoll.add(&first, EPOLLIN, 1).unwrap();
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().map(|(_, &v)| v).collect::<Vec<_>>(), vec![1]);
+
+        epoll.remove(&first).unwrap();
+        epoll.add(&second, EPOLLIN, 2).unwrap();
+        epoll.epoll().add(&foreign, EPOLLIN, u64::MAX).unwrap();
+        epoll.epoll().add(&stale, EPOLLIN, old.data()).unwrap();
+
+        assert_eq!(epoll.wait(Timeout::Immediate).unwrap().map(|(_, &v)| v).collect::<Vec<_>>(), vec![2]);
+
+        let mut seen = Vec::new();
+        assert_eq!(epoll.wait_mut(Timeout::Immediate, |_, &mut v| seen.push(v)).unwrap(), 1);
+        assert_eq!(seen, vec![2]);
+    }
+}
@@ -0,0 +1,407 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for unix domain sockets.
+//!
+//! `duplex()` creates a connected pair of non-blocking sockets, so components
+//! within a process can talk to each other through the same readiness machinery
+//! used for external I/O.
+//!
+//! An epoll's interest list lives in the kernel, so the epoll descriptor can be
+//! handed to another process (SCM_RIGHTS) together with all of its registrations;
+//! the registration table follows it, so the receiver can look them up as well.
+//! This is handy for privilege-separated designs, where a broker sets up
+//! the watches and a worker only waits on them.
+//!
+//! For authenticated IPC, `recv_message` decodes the credentials (`SCM_CREDENTIALS`)
+//! and descriptors (`SCM_RIGHTS`) that accompany a message, and `peer_credentials`
+//! tells who is on the other end of a connection.
+//!
+//! # Example
+//!
+//! ```no-run
+//! // In the broker:
+//! let mut epoll = EPoll::new()?;
+//! epoll.add(&privileged_socket, EPOLLIN, 0)?;
+//! unix::send_epoll(&channel, &epoll)?;
+//!
+//! // In the worker:
+//! let epoll = unix::recv_epoll(&channel)?;
+//! let event_count = epoll.wait(&mut events, Timeout::Indefinite)?;
+//! ```
+
+use super::*;
+use std::mem;
+use std::ptr;
+use std::os::unix::io::FromRawFd;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+/// Creates two connected, non-blocking stream sockets.
+///
+/// Both ends are close-on-exec and ready to be registered on an `EPoll`.
+pub fn duplex() -> io::Result<(UnixStream, UnixStream)> {
+    let mut fds = [0; 2];
+
+    let rc = unsafe {
+        libc::socketpair(libc::AF_UNIX,
+                         libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                         0,
+                         fds.as_mut_ptr())
+    };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(unsafe { (UnixStream::from_raw_fd(fds[0]), UnixStream::from_raw_fd(fds[1])) })
+    }
+}
+
+/// Sends the descriptor of `epoll` over `socket`, followed by its registration table.
+///
+/// The sending side keeps its own handle; both processes refer to the same
+/// kernel object afterwards. The socket should be blocking, or the table may
+/// only be sent in part.
+pub fn send_epoll(socket: &UnixStream, epoll: &EPoll) -> io::Result<()> {
+    let registrations: Vec<_> = epoll.registrations().collect();
+
+    let mut table = Vec::with_capacity(8 + registrations.len() * TABLE_RECORD);
+    table.extend_from_slice(&(registrations.len() as u64).to_ne_bytes());
+    for (fd, registration) in registrations {
+        table.extend_from_slice(&fd.to_ne_bytes());
+        table.extend_from_slice(&registration.events.bits().to_ne_bytes());
+        table.extend_from_slice(&registration.data.to_ne_bytes());
+    }
+
+    let sent = send_fd(socket, epoll.as_raw_fd(), &table)?;
+    (&*socket).write_all(&table[sent..])
+}
+
+/// Receives an epoll descriptor sent by `send_epoll`, and wraps it in an `EPoll`
+/// with the sender's registration table.
+///
+/// The table names descriptors by their numbers in the sender, which match
+/// the receiver's only for descriptors it inherited from the sender, e.g.
+/// across `fork`. The journal is not carried over.
+///
+/// Fails with `InvalidData` if the descriptor received is not an epoll.
+pub fn recv_epoll(socket: &UnixStream) -> io::Result<EPoll> {
+    let mut count = [0u8; 8];
+    let fd = recv_fd(socket, &mut count)?;
+
+    if inherit::classify_anonymous(fd.as_raw_fd())? != inherit::FdKind::EPoll {
+        return Err(Error::new(io::ErrorKind::InvalidData, "message did not carry an epoll descriptor"));
+    }
+
+    let epoll = EPoll::from_fd(fd);
+
+    let mut record = [0u8; TABLE_RECORD];
+    for _ in 0..u64::from_ne_bytes(count) {
+        (&*socket).read_exact(&mut record)?;
+
+        let field = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&record[offset..offset + 4]);
+            bytes
+        };
+        let mut data = [0; 8];
+        data.copy_from_slice(&record[8..]);
+
+        epoll.table().registrations.insert(i32::from_ne_bytes(field(0)), Registration {
+            events: EventType::from_bits_retain(u32::from_ne_bytes(field(4))),
+            data: u64::from_ne_bytes(data),
+        });
+    }
+
+    Ok(epoll)
+}
+
+/// The size of a registration sent by `send_epoll`: its descriptor, events and data.
+const TABLE_RECORD: usize = 16;
+
+/// The credentials of a process, as passed with `SCM_CREDENTIALS` or `SO_PEERCRED`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// A message received by `recv_message`, along with its ancillary data.
+#[derive(Debug)]
+pub struct Message {
+    /// The amount of bytes written into the buffer.
+    pub len: usize,
+
+    /// The sender's credentials; present if `set_pass_credentials` was enabled.
+    pub credentials: Option<Credentials>,
+
+    /// Descriptors passed with `SCM_RIGHTS`, close-on-exec.
+    pub fds: Vec<OwnedFd>,
+
+    /// Set if some of the ancillary data didn't fit and was discarded by the kernel.
+    pub truncated: bool,
+}
+
+/// The most descriptors `recv_message` accepts in a single message.
+pub const MAX_FDS: usize = 8;
+
+/// Room for credentials and `MAX_FDS` descriptors; u64 keeps it aligned.
+type ControlBuffer = [u64; 16];
+
+/// Makes the kernel attach the sender's credentials to every message received on `socket`.
+pub fn set_pass_credentials(socket: &UnixStream, enabled: bool) -> io::Result<()> {
+    let value: libc::c_int = if enabled { 1 } else { 0 };
+
+    let rc = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_PASSCRED,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(())
+    }
+}
+
+/// Returns the credentials of the peer, as they were when the connection was made.
+pub fn peer_credentials(socket: &UnixStream) -> io::Result<Credentials> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let rc = unsafe {
+        libc::getsockopt(socket.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void,
+                         &mut len)
+    };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(Credentials { pid: cred.pid, uid: cred.uid, gid: cred.gid })
+    }
+}
+
+/// Receives a message into `buf`, decoding its credentials and descriptors.
+///
+/// Like a plain read, this fails with `WouldBlock` on a non-blocking socket
+/// with nothing to receive.
+pub fn recv_message(socket: &UnixStream, buf: &mut [u8]) -> io::Result<Message> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control: ControlBuffer = [0; 16];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+
+    let rc = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+
+    if rc < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut message = Message {
+        len: rc as usize,
+        credentials: None,
+        fds: Vec::new(),
+        truncated: msg.msg_flags & libc::MSG_CTRUNC != 0,
+    };
+
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            let data_len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::SOL_SOCKET, libc::SCM_RIGHTS) => {
+                    for i in 0..data_len / mem::size_of::<RawFd>() {
+                        let fd = ptr::read_unaligned((data as *const RawFd).add(i));
+                        message.fds.push(OwnedFd::from_raw_fd(fd));
+                    }
+                },
+                (libc::SOL_SOCKET, libc::SCM_CREDENTIALS) => {
+                    let cred = ptr::read_unaligned(data as *const libc::ucred);
+                    message.credentials = Some(Credentials { pid: cred.pid, uid: cred.uid, gid: cred.gid });
+                },
+                _ => {}
+            }
+
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok(message)
+}
+
+/// Sends `fd` along with `payload`, and returns how much of the payload was sent.
+///
+/// At least one byte of real data must accompany the control message, so the
+/// payload must not be empty.
+fn send_fd(socket: &UnixStream, fd: RawFd, payload: &[u8]) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut control: ControlBuffer = [0; 16];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+
+    let rc = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+
+    if rc < 0 {
+        Err(Error::last_os_error())
+    }
+    else {
+        Ok(rc as usize)
+    }
+}
+
+/// Receives a descriptor sent by `send_fd`, and fills `payload` with the data after it.
+fn recv_fd(socket: &UnixStream, payload: &mut [u8]) -> io::Result<OwnedFd> {
+    let message = recv_message(socket, payload)?;
+
+    // Anything beyond the first descriptor was not asked for, and is closed.
+    let fd = message.fds.into_iter().next().ok_or_else(|| Error::new(io::ErrorKind::InvalidData,
+                                                                     "message did not carry a file descriptor"))?;
+
+    if message.len < payload.len() {
+        (&*socket).read_exact(&mut payload[message.len..])?;
+    }
+
+    Ok(fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplex_is_connected_and_non_blocking() {
+        use std::io::{Read, Write};
+
+        let (mut left, mut right) = duplex().unwrap();
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&right, EPOLLIN, 0).unwrap();
+
+        let mut buffer = [0u8; 4];
+        assert_eq!(right.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        left.write_all(b"ping").unwrap();
+
+        let mut events = [Event::default(); 1];
+        assert_eq!(epoll.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!(right.read(&mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"ping");
+    }
+
+    #[test]
+    fn registrations_survive_passing() {
+        let (broker, worker) = UnixStream::pair().unwrap();
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 1312).unwrap();
+        send_epoll(&broker, &epoll).unwrap();
+        drop(epoll);
+
+        let received = recv_epoll(&worker).unwrap();
+        assert_eq!(unsafe { libc::write(writer.as_raw_fd(), b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+
+        let mut events = [Event::default(); 1];
+        assert_eq!(received.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!({ events[0].data }, 1312);
+
+        // The table came along, so the registration can be looked up and changed.
+        let registrations: Vec<_> = received.registrations().collect();
+        assert_eq!(registrations, vec![(reader.as_raw_fd(), Registration { events: EPOLLIN, data: 1312 })]);
+        assert_eq!(received.disable(&reader).unwrap().data, 1312);
+        assert_eq!(received.wait(&mut events, Timeout::Immediate).unwrap(), 0);
+    }
+
+    #[test]
+    fn credentials() {
+        use std::io::Write;
+
+        let (client, server) = duplex().unwrap();
+        set_pass_credentials(&server, true).unwrap();
+
+        let me = unsafe {
+            Credentials { pid: libc::getpid(), uid: libc::getuid(), gid: libc::getgid() }
+        };
+        assert_eq!(peer_credentials(&server).unwrap(), me);
+
+        (&client).write_all(b"hello").unwrap();
+
+        let mut buffer = [0u8; 16];
+        let message = recv_message(&server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..message.len], b"hello");
+        assert_eq!(message.credentials, Some(me));
+        assert!(message.fds.is_empty());
+        assert!(!message.truncated);
+    }
+
+    #[test]
+    fn plain_message_is_rejected() {
+        let (broker, worker) = UnixStream::pair().unwrap();
+
+        use std::io::Write;
+        (&broker).write_all(b"x").unwrap();
+
+        assert!(recv_epoll(&worker).is_err());
+    }
+
+    #[test]
+    fn other_descriptors_are_rejected() {
+        let (broker, worker) = UnixStream::pair().unwrap();
+        let (reader, _writer) = ::pipe::Pipe::new().unwrap().split();
+
+        send_fd(&broker, reader.as_raw_fd(), &0u64.to_ne_bytes()).unwrap();
+        match recv_epoll(&worker) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("a pipe was accepted as an epoll"),
+        }
+    }
+}
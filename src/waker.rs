@@ -0,0 +1,96 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Waking an epoll from other threads.
+//!
+//! # Example
+//!
+//! ```no-run
+//! let waker = epoll.waker()?;
+//! thread::spawn(move || {
+//!     jobs.push(job);
+//!     waker.wake().unwrap();
+//! });
+//!
+//! for e in epoll.wait_iter(Timeout::Indefinite)? {
+//!     if e.token() == EPoll::WAKE_TOKEN {
+//!         run(jobs.pop());
+//!     }
+//! }
+//! ```
+
+use super::*;
+use counter::Counter;
+use std::sync::Arc;
+
+/// Interrupts waits on an `EPoll`; see `EPoll::waker`.
+#[derive(Clone, Debug)]
+pub struct Waker {
+    counter: Arc<Counter>,
+}
+
+impl Waker {
+    /// Makes a wait on the epoll return an event carrying `EPoll::WAKE_TOKEN`.
+    ///
+    /// Wakes issued before the next wait are reported by it, once or more.
+    pub fn wake(&self) -> io::Result<()> {
+        self.counter.increment(1)
+    }
+}
+
+impl EPoll {
+    /// The data of events caused by a `Waker`; don't register files with it.
+    pub const WAKE_TOKEN: u64 = u64::MAX;
+
+    /// Returns a waker for this epoll, creating its eventfd on first use.
+    ///
+    /// All wakers of an epoll (and of its clones made afterwards) share the eventfd,
+    /// which is not part of the registration table.
+    pub fn waker(&self) -> io::Result<Waker> {
+        let mut table = self.table();
+
+        if let Some(ref counter) = table.waker {
+            return Ok(Waker { counter: counter.clone() });
+        }
+
+        // Edge-triggered, every wake is reported anew without anyone draining the counter.
+        let counter = Arc::new(Counter::new()?);
+        self.ctl(libc::EPOLL_CTL_ADD, counter.as_raw_fd(), Event::new(EPOLLIN | EPOLLET, EPoll::WAKE_TOKEN))?;
+        table.waker = Some(counter.clone());
+
+        Ok(Waker { counter })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn wakes_a_blocked_wait() {
+        let epoll = EPoll::new().unwrap();
+        let waker = epoll.waker().unwrap();
+        assert!(epoll.is_empty());
+
+        let waking = thread::spawn(move || waker.wake().unwrap());
+        assert_eq!(epoll.wait_one(Timeout::Milliseconds(5000)).unwrap().map(|e| e.token()), Some(EPoll::WAKE_TOKEN));
+        waking.join().unwrap();
+
+        assert!(epoll.wait_one(Timeout::Immediate).unwrap().is_none());
+
+        epoll.waker().unwrap().wake().unwrap();
+        assert_eq!(epoll.wait_one(Timeout::Immediate).unwrap().map(|e| e.token()), Some(EPoll::WAKE_TOKEN));
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright 2017 Gilad Naaman
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks that the lists of `epoll::syscalls` suffice, by running each module
+//! in a child process that seccomp confines to its list.
+//!
+//! This runs without the test harness: the children allocate and unwind, which
+//! is only safe after forking a single-threaded process, and the harness runs
+//! tests on threads of its own. The checks run one after another on the main thread.
+
+extern crate epoll;
+extern crate libc;
+
+use epoll::*;
+use epoll::syscalls::*;
+use std::os::unix::io::{AsFd, FromRawFd, OwnedFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::time::Duration;
+
+/// `CORE` and `RUNTIME`, which everything needs, followed by `lists`.
+fn allowing(lists: &[&[Syscall]]) -> Vec<Syscall> {
+    [CORE, RUNTIME].iter().chain(lists).flat_map(|list| list.iter().cloned()).collect()
+}
+
+/// Runs `f` in a forked child confined to `allowed` (plus exiting), and returns
+/// its wait status. The child exits with 0 if `f` returns, and with 1 if it panics.
+fn run_confined<F: FnOnce()>(allowed: &[Syscall], f: F) -> libc::c_int {
+    let mut filter = vec![
+        libc::sock_filter { code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+                            jt: 0, jf: 0, k: 0 },
+    ];
+    let exits = [libc::SYS_exit, libc::SYS_exit_group];
+    for number in allowed.iter().map(|s| s.number).chain(exits.iter().cloned()) {
+        filter.push(libc::sock_filter { code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+                                        jt: 0, jf: 1, k: number as u32 });
+        filter.push(libc::sock_filter { code: (libc::BPF_RET | libc::BPF_K) as u16,
+                                        jt: 0, jf: 0, k: libc::SECCOMP_RET_ALLOW });
+    }
+    filter.push(libc::sock_filter { code: (libc::BPF_RET | libc::BPF_K) as u16,
+                                    jt: 0, jf: 0, k: libc::SECCOMP_RET_KILL_PROCESS });
+    let program = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+
+    if pid == 0 {
+        // Reporting a panic would write to stderr, which the filter may not allow.
+        panic::set_hook(Box::new(|_| {}));
+        unsafe {
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program) != 0 {
+                libc::_exit(2);
+            }
+        }
+        let completed = panic::catch_unwind(AssertUnwindSafe(f)).is_ok();
+        unsafe { libc::_exit(if completed { 0 } else { 1 }) };
+    }
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    status
+}
+
+/// Fails unless `f` completes when confined to `allowed`.
+fn suffices<F: FnOnce()>(allowed: &[Syscall], f: F) -> Result<(), String> {
+    let status = run_confined(allowed, f);
+    if libc::WIFSIGNALED(status) {
+        Err(format!("killed by signal {}", libc::WTERMSIG(status)))
+    }
+    else if libc::WEXITSTATUS(status) != 0 {
+        Err(format!("exited with {}", libc::WEXITSTATUS(status)))
+    }
+    else {
+        Ok(())
+    }
+}
+
+fn core() -> Result<(), String> {
+    let (reader, _writer) = pipe::Pipe::new().unwrap().split();
+
+    suffices(&allowing(&[]), || {
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+        epoll.modify(&reader, EPOLLIN | EPOLLET, 0).unwrap();
+
+        let mut events = [Event::default(); 1];
+        epoll.wait(&mut events, Timeout::Immediate).unwrap();
+        epoll.remove(&reader).unwrap();
+    })
+}
+
+fn inherit() -> Result<(), String> {
+    let (reader, _writer) = pipe::Pipe::new().unwrap().split();
+    let timerfd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+    assert!(timerfd >= 0);
+    let timerfd = unsafe { OwnedFd::from_raw_fd(timerfd) };
+
+    let fds = vec![reader.as_fd().try_clone_to_owned().unwrap(), timerfd];
+
+    suffices(&allowing(&[INHERIT]), || {
+        let epoll = EPoll::new().unwrap();
+        inherit::register_inherited(&epoll, fds).unwrap();
+    })
+}
+
+fn mirror() -> Result<(), String> {
+    let path = std::env::temp_dir().join(format!("epoll-mirror-seccomp-{}", process::id()));
+    let (reader, _writer) = pipe::Pipe::new().unwrap().split();
+
+    let result = suffices(&allowing(&[MIRROR]), || {
+        let epoll = EPoll::new().unwrap();
+        mirror::attach(&epoll, &path, 4).unwrap();
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+    });
+
+    std::fs::remove_file(&path).unwrap();
+    result
+}
+
+fn unix() -> Result<(), String> {
+    suffices(&allowing(&[UNIX]), || {
+        let (left, right) = unix::duplex().unwrap();
+        unix::set_pass_credentials(&right, true).unwrap();
+        assert!(unix::peer_credentials(&left).unwrap().pid > 0);
+
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&right, EPOLLIN, 0).unwrap();
+        unix::send_epoll(&left, &epoll).unwrap();
+        assert_eq!(unix::recv_epoll(&right).unwrap().len(), 1);
+    })
+}
+
+fn pipe() -> Result<(), String> {
+    let plain = [Syscall { name: "read", number: libc::SYS_read }, Syscall { name: "write", number: libc::SYS_write }];
+
+    suffices(&allowing(&[PIPE, &plain]), || {
+        use std::io::{Read, Write};
+
+        let pipe = pipe::Pipe::new().unwrap();
+        pipe.set_capacity(1 << 16).unwrap();
+        let (mut reader, mut writer) = pipe.split();
+        let (mut other_reader, other_writer) = pipe::Pipe::new().unwrap().split();
+
+        writer.write_all(b"xy").unwrap();
+        assert_eq!(reader.splice_to(&other_writer, 1).unwrap(), 1);
+
+        let mut buf = [0; 1];
+        reader.read_exact(&mut buf).unwrap();
+        other_reader.read_exact(&mut buf).unwrap();
+    })
+}
+
+fn health() -> Result<(), String> {
+    let (reader, _writer) = pipe::Pipe::new().unwrap().split();
+
+    suffices(&allowing(&[HEALTH]), || {
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&reader, EPOLLIN, 0).unwrap();
+
+        let mut checker = health::HealthChecker::new();
+        assert!(checker.check(&epoll).is_empty());
+        assert!(checker.repair(&epoll).is_empty());
+    })
+}
+
+fn privilege() -> Result<(), String> {
+    // Dropping privileges needs them in the first place.
+    if unsafe { libc::getuid() } != 0 {
+        return Ok(());
+    }
+
+    let (reader, _writer) = pipe::Pipe::new().unwrap().split();
+
+    suffices(&allowing(&[PRIVILEGE]), || {
+        let epoll = EPoll::new().unwrap();
+        privilege::register_all(&epoll, &[&reader], EPOLLIN).unwrap();
+        privilege::drop_privileges(65534, 65534).unwrap();
+    })
+}
+
+fn sigset() -> Result<(), String> {
+    suffices(&allowing(&[SIGSET]), || {
+        let set = sigset::SigSet::of(&[libc::SIGUSR2]).unwrap();
+        let previous = set.block().unwrap();
+        assert!(sigset::SigSet::thread_mask().unwrap().contains(libc::SIGUSR2));
+        set.unblock().unwrap();
+        previous.set_thread_mask().unwrap();
+    })
+}
+
+fn signalfd() -> Result<(), String> {
+    suffices(&allowing(&[SIGSET, SIGNALFD]), || {
+        let signals = signalfd::SignalFd::blocking(&[libc::SIGUSR2]).unwrap();
+        signals.set_signals(&sigset::SigSet::of(&[libc::SIGUSR1, libc::SIGUSR2]).unwrap()).unwrap();
+        assert!(signals.read().unwrap().is_none());
+    })
+}
+
+fn pidfd() -> Result<(), String> {
+    let mut child = process::Command::new("true").spawn().unwrap();
+    let pid = child.id();
+
+    let result = suffices(&allowing(&[PIDFD]), || {
+        let pidfd = pidfd::PidFd::open(pid).unwrap();
+        let epoll = EPoll::new().unwrap();
+        epoll.add(&pidfd, EPOLLIN, 0).unwrap();
+        assert!(epoll.wait_one(Timeout::Milliseconds(5000)).unwrap().is_some());
+
+        // The process is the parent's child, not this one's.
+        assert_eq!(pidfd.try_wait().unwrap_err().raw_os_error(), Some(libc::ECHILD));
+    });
+
+    child.wait().unwrap();
+    result
+}
+
+fn inotify() -> Result<(), String> {
+    suffices(&allowing(&[INOTIFY]), || {
+        let inotify = inotify::Inotify::new().unwrap();
+        let watch = inotify.add_watch(std::env::temp_dir(), inotify::IN_CREATE).unwrap();
+
+        let mut buffer = [0; 4096];
+        inotify.read_events(&mut buffer).unwrap();
+        inotify.remove_watch(watch).unwrap();
+    })
+}
+
+fn fanotify() -> Result<(), String> {
+    use fanotify::{Class, Fanotify, InitFlags, MarkFlags, FAN_OPEN};
+
+    // Creating a group needs privileges.
+    match Fanotify::new(Class::Notification, InitFlags::empty()) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Ok(()),
+        fanotify => drop(fanotify.unwrap()),
+    }
+
+    suffices(&allowing(&[FANOTIFY]), || {
+        let fanotify = Fanotify::new(Class::Notification, InitFlags::empty()).unwrap();
+        fanotify.add_mark(std::env::temp_dir(), MarkFlags::empty(), FAN_OPEN).unwrap();
+
+        let mut buffer = [0; 4096];
+        fanotify.read_events(&mut buffer).unwrap().count();
+        fanotify.remove_mark(std::env::temp_dir(), MarkFlags::empty(), FAN_OPEN).unwrap();
+    })
+}
+
+fn mqueue() -> Result<(), String> {
+    let name = format!("/epoll-mqueue-seccomp-{}", process::id());
+
+    suffices(&allowing(&[MQUEUE]), || {
+        let queue = mqueue::MessageQueue::<u64>::create(&name, 1).unwrap();
+        queue.send(&7, 0).unwrap();
+        assert_eq!(queue.len().unwrap(), 1);
+        assert_eq!(queue.receive().unwrap(), Some((7, 0)));
+        mqueue::unlink(&name).unwrap();
+    })
+}
+
+fn counter() -> Result<(), String> {
+    suffices(&allowing(&[COUNTER]), || {
+        let counter = counter::Counter::semaphore().unwrap();
+        counter.increment(2).unwrap();
+        assert_eq!(counter.take().unwrap(), 1);
+    })
+}
+
+fn ring() -> Result<(), String> {
+    suffices(&allowing(&[COUNTER, RING]), || {
+        let (sender, mut receiver) = ring::channel::<u64>(4).unwrap();
+        sender.send(1).unwrap();
+        let (mut single, mut single_receiver) = ring::single_producer::<u64>(1).unwrap();
+        single.send(2).unwrap();
+        assert!(single.send_timeout(3, Duration::from_millis(5)).is_err());
+
+        let mut received = Vec::new();
+        receiver.recv_batch(&mut received).unwrap();
+        single_receiver.recv_batch(&mut received).unwrap();
+        assert_eq!(received, [1, 2]);
+    })
+}
+
+#[cfg(feature = "mio-compat")]
+fn mio() -> Result<(), String> {
+    use compat::mio::{Events, Poll, Token, Waker};
+
+    suffices(&allowing(&[MIO]), || {
+        let mut poll = Poll::new().unwrap();
+        let waker = Waker::new(poll.registry(), Token(1)).unwrap();
+        waker.wake().unwrap();
+
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+        assert!(!events.is_empty());
+    })
+}
+
+fn unlisted_syscalls_are_caught() -> Result<(), String> {
+    let status = run_confined(&[], || { EPoll::new().unwrap(); });
+    if libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSYS {
+        Ok(())
+    }
+    else {
+        Err(format!("expected a SIGSYS kill, got status {:#x}", status))
+    }
+}
+
+fn panics_are_caught() -> Result<(), String> {
+    let status = run_confined(&allowing(&[]), || panic!("confined"));
+    if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 1 {
+        Ok(())
+    }
+    else {
+        Err(format!("expected an exit with 1, got status {:#x}", status))
+    }
+}
+
+/// A check, which tells why it failed.
+type Check = fn() -> Result<(), String>;
+
+fn main() {
+    let checks: &[(&str, Check)] = &[
+        ("core", core),
+        ("inherit", inherit),
+        ("mirror", mirror),
+        ("unix", unix),
+        ("pipe", pipe),
+        ("health", health),
+        ("privilege", privilege),
+        ("sigset", sigset),
+        ("signalfd", signalfd),
+        ("pidfd", pidfd),
+        ("inotify", inotify),
+        ("fanotify", fanotify),
+        ("mqueue", mqueue),
+        ("counter", counter),
+        ("ring", ring),
+        #[cfg(feature = "mio-compat")]
+        ("mio", mio),
+        ("unlisted_syscalls_are_caught", unlisted_syscalls_are_caught),
+        ("panics_are_caught", panics_are_caught),
+    ];
+
+    let mut failed = 0;
+    for &(name, check) in checks {
+        match check() {
+            Ok(()) => println!("seccomp {} ... ok", name),
+            Err(reason) => {
+                println!("seccomp {} ... FAILED: {}", name, reason);
+                failed += 1;
+            },
+        }
+    }
+
+    println!("{} passed; {} failed", checks.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}